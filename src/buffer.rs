@@ -1,93 +1,1592 @@
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    io::{ErrorKind, Read},
+    time::SystemTime,
+};
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::{
+    edit::{end_of, Edit},
+    filetype::{detect_filetype, FileType},
+    rope::Rope,
+};
+
+/// The text being edited. There is always at least one line; an empty file is
+/// a single empty line. Columns are counted in grapheme clusters, as the
+/// cursor moves over them; the edits it records are in chars.
 pub struct Buffer {
     pub file: Option<String>,
-    pub lines: Vec<String>,
+    /// The lines joined by `\n`, without the newline that ends the file.
+    text: Rope,
+    /// Whether the file ended with a newline, which saving keeps as it was.
+    pub trailing_newline: bool,
+    /// Whether the lines changed since the file was loaded or last saved.
+    pub modified: bool,
+    /// Modification time of the file when it was last read or written.
+    mtime: Option<SystemTime>,
+    /// Whether the content was piped in on stdin rather than read from a file.
+    pub stdin: bool,
+    /// Set for files without write permission, by `-R` and by `:view`. Changes
+    /// are refused and only `:w!` writes the file.
+    pub readonly: bool,
+    /// Detected when the file is read; `:set ft=` changes it.
+    pub filetype: FileType,
+    /// Width of a tab, from the `tabstop` setting.
+    pub tabstop: usize,
+    /// Edits applied since they were last taken, for undo.
+    edits: Vec<Edit>,
+    /// Number of edits ever applied, to tell whether the text changed since it
+    /// was last looked at.
+    changes: usize,
 }
 
 impl Buffer {
-    pub fn from_file(file: Option<String>) -> Self {
-        let lines = match &file {
-            Some(file) => std::fs::read_to_string(file)
-                .unwrap()
-                .lines()
-                .map(|line| line.to_string())
-                .collect(),
-            None => vec![],
+    /// Reads `file` into a buffer. A file that does not exist yet opens as
+    /// an empty buffer; one that cannot be read, or is not UTF-8, is an
+    /// error, so that writing the buffer cannot overwrite it with nothing.
+    pub fn from_file(file: Option<String>) -> std::io::Result<Self> {
+        let content = match file.as_deref().map(std::fs::read_to_string) {
+            Some(Err(err)) if err.kind() != ErrorKind::NotFound => return Err(err),
+            Some(Ok(content)) => content,
+            _ => String::new(),
         };
+        let (text, trailing_newline) = split_lines(&content);
 
-        Self { file, lines }
+        let mtime = file.as_deref().and_then(disk_mtime);
+        let readonly = file
+            .as_deref()
+            .and_then(|file| std::fs::metadata(file).ok())
+            .is_some_and(|metadata| metadata.permissions().readonly());
+        let filetype = detect_filetype(file.as_deref(), Some(&text.line(0)));
+
+        Ok(Self {
+            file,
+            text,
+            trailing_newline,
+            modified: false,
+            mtime,
+            stdin: false,
+            readonly,
+            filetype,
+            tabstop: 8,
+            edits: vec![],
+            changes: 0,
+        })
     }
 
-    pub fn get(&self, line: usize) -> Option<String> {
-        if self.lines.len() > line {
-            return Some(self.lines[line].clone());
+    /// Reads the whole of stdin into an unnamed buffer, for `cat file | vigil`.
+    pub fn from_stdin() -> std::io::Result<Self> {
+        let mut content = String::new();
+        std::io::stdin().read_to_string(&mut content)?;
+
+        let (text, trailing_newline) = split_lines(&content);
+        Ok(Self {
+            file: None,
+            filetype: detect_filetype(None, Some(&text.line(0))),
+            text,
+            trailing_newline,
+            modified: false,
+            mtime: None,
+            stdin: true,
+            readonly: false,
+            tabstop: 8,
+            edits: vec![],
+            changes: 0,
+        })
+    }
+
+    /// An unnamed buffer holding `lines`, for tests.
+    #[cfg(test)]
+    pub fn from_lines(lines: &[&str]) -> Self {
+        let mut buffer = Self::from_file(None).unwrap();
+        buffer.text = Rope::from(lines.join("\n").as_str());
+        buffer
+    }
+
+    /// Whether the file was modified by someone else since we read or wrote it.
+    pub fn changed_on_disk(&self) -> bool {
+        match &self.file {
+            Some(file) => disk_mtime(file) != self.mtime,
+            None => false,
         }
+    }
 
-        None
+    pub fn get(&self, line: usize) -> Option<String> {
+        self.line(line).map(Cow::into_owned)
+    }
+
+    /// Like [`Buffer::get`], but borrows the line unless it is split between
+    /// chunks of the text, which saves copying it on every draw.
+    pub fn line(&self, line: usize) -> Option<Cow<'_, str>> {
+        (line < self.len()).then(|| self.text.line(line))
     }
 
     pub fn len(&self) -> usize {
-        self.lines.len()
+        self.text.len_lines()
+    }
+
+    /// Number of lines from `start` up to, not including, `end`; those past
+    /// the end of the buffer are not counted.
+    pub fn len_lines_in_range(&self, start: usize, end: usize) -> usize {
+        end.min(self.len()).saturating_sub(start)
+    }
+
+    /// Number of grapheme clusters in the buffer, not counting line breaks.
+    // for a word count, which nothing shows yet
+    #[allow(dead_code)]
+    pub fn total_chars(&self) -> usize {
+        self.lines().map(|line| line.graphemes(true).count()).sum()
+    }
+
+    pub fn lines(&self) -> impl Iterator<Item = Cow<'_, str>> {
+        self.text.lines()
+    }
+
+    /// The lines joined by `\n`, as sent to language servers.
+    pub fn text(&self) -> String {
+        self.text.to_string()
     }
 
+    /// Number of grapheme clusters on line `y`, 0 if there is no such line.
+    pub fn chars_on_line(&self, y: usize) -> usize {
+        self.text.line(y).graphemes(true).count()
+    }
+
+    /// The grapheme clusters of line `y`, one per column, for rendering. Empty
+    /// if there is no such line.
+    pub fn graphemes_on_line(&self, y: usize) -> Vec<String> {
+        self.line(y).map_or(vec![], |line| {
+            line.graphemes(true).map(String::from).collect()
+        })
+    }
+
+    /// The text of line `y` from grapheme `x_start` up to, not including,
+    /// `x_end`. Columns past the end of the line are clamped to it.
+    pub fn get_range(&self, y: u16, x_start: u16, x_end: u16) -> String {
+        self.get_multiline_range(y, x_start, y, x_end)
+    }
+
+    /// The text from grapheme `x1` of line `y1` up to, not including, grapheme
+    /// `x2` of line `y2`, with the lines joined by `\n`. Empty when the end comes
+    /// before the start.
+    pub fn get_multiline_range(&self, y1: u16, x1: u16, y2: u16, x2: u16) -> String {
+        let (y1, y2) = (y1 as usize, (y2 as usize).min(self.len() - 1));
+        if y1 > y2 || y1 >= self.len() || (y1 == y2 && x1 >= x2) {
+            return String::new();
+        }
+
+        let mut text = String::new();
+        for y in y1..=y2 {
+            let line = self.text.line(y);
+            let graphemes = line.graphemes(true);
+            let start = if y == y1 { x1 as usize } else { 0 };
+            if y == y2 {
+                text.extend(
+                    graphemes
+                        .skip(start)
+                        .take((x2 as usize).saturating_sub(start)),
+                );
+            } else {
+                text.extend(graphemes.skip(start));
+                text.push('\n');
+            }
+        }
+        text
+    }
+
+    /// Applies `edit` and records it for [`Buffer::take_edits`]. All changes to
+    /// the lines go through here; the other methods build the edits.
+    pub fn apply_edit(&mut self, edit: &Edit) {
+        let len = self.len();
+        match edit {
+            Edit::InsertChar { x, y, c } => {
+                if *y < len {
+                    let offset = self.offset(*y, *x);
+                    self.text.insert(offset, c.encode_utf8(&mut [0; 4]));
+                }
+            }
+            Edit::RemoveChar { x, y, .. } => {
+                if *y < len && *x < self.chars_in(*y) {
+                    self.text.delete(self.offset(*y, *x), 1);
+                }
+            }
+            Edit::InsertLine { y, line } => {
+                if *y < len {
+                    self.text
+                        .insert(self.text.line_to_char(*y), &format!("{line}\n"));
+                } else {
+                    self.text.insert(usize::MAX, &format!("\n{line}"));
+                }
+            }
+            Edit::RemoveLine { y, .. } => {
+                let (start, end) = match *y {
+                    y if y + 1 < len => (self.text.line_to_char(y), self.text.line_to_char(y + 1)),
+                    0 => (0, usize::MAX),
+                    y if y < len => (self.text.line_to_char(y) - 1, usize::MAX),
+                    _ => (0, 0),
+                };
+                self.text.delete(start, end - start);
+            }
+            Edit::Replace { start, old, new } => {
+                let (x1, y1) = *start;
+                let (x2, y2) = end_of(*start, old);
+                if y2 >= len {
+                    return;
+                }
+                let (start, end) = (self.offset(y1, x1), self.offset(y2, x2));
+                self.text.delete(start, end.saturating_sub(start));
+                self.text.insert(start, new);
+            }
+        }
+        self.modified = true;
+        self.changes += 1;
+        self.edits.push(edit.clone());
+    }
+
+    /// Reverses `edit`, which must be the last change to the text it touches.
+    pub fn unapply_edit(&mut self, edit: &Edit) {
+        self.apply_edit(&edit.inverse());
+    }
+
+    pub fn changes(&self) -> usize {
+        self.changes
+    }
+
+    /// The edits applied since the last call, oldest first.
+    pub fn take_edits(&mut self) -> Vec<Edit> {
+        std::mem::take(&mut self.edits)
+    }
+
+    /// Inserts `c` at column `x` of line `y`, padding the line with spaces if
+    /// it is shorter. Past the last line, a new line is started.
     pub fn insert(&mut self, x: u16, y: u16, c: char) {
-        let y = y as usize;
-        if let Some(line) = self.lines.get_mut(y) {
-            let mut new_line = String::new();
-            let mut char_count = 0;
-            let x = x as usize;
-
-            for ch in line.chars() {
-                if char_count == x {
-                    new_line.push(c);
+        let (x, y) = (x as usize, y as usize);
+        if y >= self.len() {
+            let line = format!("{}{c}", " ".repeat(x));
+            self.apply_edit(&Edit::InsertLine {
+                y: self.len(),
+                line,
+            });
+            return;
+        }
+
+        let len = self.chars_on_line(y);
+        if x > len {
+            self.apply_edit(&Edit::Replace {
+                start: (self.chars_in(y), y),
+                old: String::new(),
+                new: format!("{}{c}", " ".repeat(x - len)),
+            });
+        } else {
+            let x = self.char_col(y, x);
+            self.apply_edit(&Edit::InsertChar { x, y, c });
+        }
+    }
+
+    /// Removes the grapheme at column `x` of line `y`, all of its chars.
+    pub fn remove(&mut self, x: u16, y: u16) {
+        let (x, y) = (x as usize, y as usize);
+        let Some(line) = self.line(y) else {
+            return;
+        };
+        let Some(grapheme) = line.graphemes(true).nth(x) else {
+            return;
+        };
+        let start = char_index(&line, x);
+        let mut chars = grapheme.chars();
+        let edit = match (chars.next(), chars.next()) {
+            (Some(c), None) => Edit::RemoveChar { x: start, y, c },
+            _ => Edit::Replace {
+                start: (start, y),
+                old: grapheme.to_string(),
+                new: String::new(),
+            },
+        };
+        self.apply_edit(&edit);
+    }
+
+    /// The text as it is written to disk.
+    pub fn content(&self) -> String {
+        let mut content = self.text.to_string();
+        if self.trailing_newline {
+            content.push('\n');
+        }
+        content
+    }
+
+    /// The lines joined by `\n`, each but the empty ones prefixed with
+    /// `base_indent`, to be put into text indented that much.
+    #[allow(dead_code)]
+    pub fn get_indented_content(&self, base_indent: &str) -> String {
+        let lines: Vec<String> = self
+            .lines()
+            .map(|line| match line.is_empty() {
+                true => String::new(),
+                false => format!("{base_indent}{line}"),
+            })
+            .collect();
+        lines.join("\n")
+    }
+
+    /// The lines joined by `\n` with the leading whitespace they all share
+    /// taken off, and that whitespace. Blank lines do not count towards it.
+    /// Meant for the `==` operator, which is yet to come.
+    #[allow(dead_code)]
+    pub fn dedented_content(&self) -> (String, String) {
+        let mut prefix: Option<String> = None;
+        for line in self.lines().filter(|line| !line.trim().is_empty()) {
+            let indent: String = line.chars().take_while(|c| c.is_whitespace()).collect();
+            prefix = Some(match prefix {
+                None => indent,
+                Some(prefix) => (prefix.chars().zip(indent.chars()))
+                    .take_while(|(a, b)| a == b)
+                    .map(|(a, _)| a)
+                    .collect(),
+            });
+        }
+        let prefix = prefix.unwrap_or_default();
+
+        let lines: Vec<String> = self
+            .lines()
+            .map(|line| {
+                line.strip_prefix(prefix.as_str())
+                    .unwrap_or(line.trim_start())
+                    .to_string()
+            })
+            .collect();
+        (lines.join("\n"), prefix)
+    }
+
+    pub fn save(&mut self) -> std::io::Result<()> {
+        if let Some(file) = &self.file {
+            std::fs::write(file, self.content())?;
+            self.modified = false;
+            self.mtime = disk_mtime(file);
+        }
+        Ok(())
+    }
+
+    /// Points the buffer at `file`, as if it had just been read from there, so
+    /// that a copy can be written to it the way the buffer is saved. Returns
+    /// what it pointed at, for [`Buffer::restore_target`].
+    pub fn retarget(&mut self, file: &str) -> Target {
+        let mtime = disk_mtime(file);
+        Target {
+            file: self.file.replace(file.to_string()),
+            mtime: std::mem::replace(&mut self.mtime, mtime),
+            modified: self.modified,
+            changes: self.changes,
+        }
+    }
+
+    /// Points the buffer back at its own file after [`Buffer::retarget`]. It
+    /// is still modified if it was before, or if it changed in between.
+    pub fn restore_target(&mut self, target: Target) {
+        self.file = target.file;
+        self.mtime = target.mtime;
+        self.modified = target.modified || self.changes != target.changes;
+    }
+
+    /// Sorts lines `start..=end`, optionally in reverse, by their first number
+    /// (lines without one come first) and dropping duplicates. Returns the number
+    /// of lines that were in the range.
+    pub fn sort_lines(
+        &mut self,
+        start: u16,
+        end: u16,
+        reverse: bool,
+        unique: bool,
+        numeric: bool,
+    ) -> usize {
+        let end = (end as usize).min(self.len() - 1);
+        let start = (start as usize).min(end);
+
+        let mut lines: Vec<String> = (start..=end)
+            .map(|y| self.text.line(y).into_owned())
+            .collect();
+        let count = lines.len();
+        if numeric {
+            lines.sort_by_key(|line| first_number(line));
+        } else {
+            lines.sort();
+        }
+        if reverse {
+            lines.reverse();
+        }
+        if unique {
+            lines.dedup();
+        }
+
+        let old = self.text_between((0, start), (self.chars_in(end), end));
+        let new = lines.join("\n");
+        if old != new {
+            self.apply_edit(&Edit::Replace {
+                start: (0, start),
+                old,
+                new,
+            });
+        }
+        count
+    }
+
+    /// Converts the leading whitespace of lines `start..=end` between tabs and
+    /// spaces, keeping the column it reaches with tab stops every `tab_width`:
+    /// it becomes all spaces, or as many tabs as fit and spaces for the rest.
+    /// Returns the number of lines changed.
+    pub fn retab(&mut self, start: u16, end: u16, to_spaces: bool, tab_width: usize) -> usize {
+        let tab_width = tab_width.max(1);
+        let end = (end as usize).min(self.len() - 1);
+        let mut changed = 0;
+        for y in start as usize..=end {
+            let line = self.text.line(y);
+            let indent_len = line.len() - line.trim_start_matches([' ', '\t']).len();
+            let indent = &line[..indent_len];
+
+            let width = indent.chars().fold(0, |col, c| match c {
+                '\t' => col + tab_width - col % tab_width,
+                _ => col + 1,
+            });
+            let retabbed = if to_spaces {
+                " ".repeat(width)
+            } else {
+                "\t".repeat(width / tab_width) + &" ".repeat(width % tab_width)
+            };
+
+            if retabbed != indent {
+                let old = indent.to_string();
+                self.apply_edit(&Edit::Replace {
+                    start: (0, y),
+                    old,
+                    new: retabbed,
+                });
+                changed += 1;
+            }
+        }
+        changed
+    }
+
+    pub fn is_blank(&self, y: usize) -> bool {
+        y < self.len() && self.chars_in(y) == 0
+    }
+
+    /// Whether line `y` holds nothing but whitespace. Unlike [`Buffer::is_blank`],
+    /// a line of spaces counts.
+    pub fn is_blank_line(&self, y: usize) -> bool {
+        y < self.len() && self.text.line(y).trim().is_empty()
+    }
+
+    /// Counts the lines that end in whitespace, and the lines indented with a
+    /// tab where most indented lines start with a space, or the other way round.
+    pub fn whitespace_issues(&self) -> (usize, usize) {
+        let (mut trailing, mut tabs, mut spaces) = (0, 0, 0);
+        for line in self.lines() {
+            if line.ends_with([' ', '\t']) {
+                trailing += 1;
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            match line.chars().next() {
+                Some('\t') => tabs += 1,
+                Some(' ') => spaces += 1,
+                _ => {}
+            }
+        }
+        (trailing, tabs.min(spaces))
+    }
+
+    /// How deep line `y` is indented, in tab stops. Tabs advance to the next
+    /// stop and spaces count one column each; a partial stop is dropped. Meant
+    /// for re-indenting, which is yet to come.
+    #[allow(dead_code)]
+    pub fn indent_level(&self, y: usize) -> usize {
+        self.indent_width(y) / self.tabstop.max(1)
+    }
+
+    /// How many columns the indentation of line `y` takes.
+    fn indent_width(&self, y: usize) -> usize {
+        let tabstop = self.tabstop.max(1);
+        let mut col = 0;
+        for c in self.text.line(y).chars() {
+            match c {
+                '\t' => col += tabstop - col % tabstop,
+                ' ' => col += 1,
+                _ => break,
+            }
+        }
+        col
+    }
+
+    /// The folds by indentation, as `(start, end)` lines sorted by start: a
+    /// line followed by more indented ones folds up to the last of those
+    /// before the indent drops back. Blank lines go with the lines around
+    /// them, but do not end a fold. Folds nest.
+    pub fn detect_indent_folds(&self) -> Vec<(u16, u16)> {
+        let mut folds = vec![];
+        // lines that may start a fold, with their indent, innermost last
+        let mut starts: Vec<(usize, usize)> = vec![];
+        let mut last = 0;
+        for y in (0..self.len()).filter(|&y| !self.is_blank_line(y)) {
+            let indent = self.indent_width(y);
+            while let Some(&(start, start_indent)) = starts.last() {
+                if indent > start_indent {
+                    break;
+                }
+                starts.pop();
+                if last > start {
+                    folds.push((start as u16, last as u16));
                 }
-                new_line.push(ch);
-                char_count += 1;
             }
+            starts.push((y, indent));
+            last = y;
+        }
+        for (start, _) in starts {
+            if last > start {
+                folds.push((start as u16, last as u16));
+            }
+        }
+        folds.sort();
+        folds
+    }
+
+    /// The first empty line after `y`, or the last line if there is none.
+    pub fn next_blank_line(&self, y: usize) -> usize {
+        (y + 1..self.len())
+            .find(|&i| self.is_blank(i))
+            .unwrap_or(self.len() - 1)
+    }
+
+    /// The last empty line before `y`, or the first line if there is none.
+    pub fn prev_blank_line(&self, y: usize) -> usize {
+        (0..y.min(self.len()))
+            .rev()
+            .find(|&i| self.is_blank(i))
+            .unwrap_or(0)
+    }
+
+    /// Column of the first non-blank char of line `y`, or 0 if there is none.
+    pub fn first_non_blank(&self, y: usize) -> usize {
+        let line = self.text.line(y);
+        line.graphemes(true)
+            .position(|g| !g.chars().all(char::is_whitespace))
+            .unwrap_or(0)
+    }
+
+    /// Replaces the text between `start` and `end` (both `(x, y)`, `end`
+    /// exclusive) with `text`, which may span several lines.
+    pub fn replace(&mut self, start: (usize, usize), end: (usize, usize), text: &str) {
+        let Some((start, end)) = self.clamp_range(start, end) else {
+            return;
+        };
+        let old = self.text_between(start, end);
+        if old != text {
+            self.apply_edit(&Edit::Replace {
+                start,
+                old,
+                new: text.to_string(),
+            });
+        }
+    }
+
+    /// Removes the text between `start` and `end` (both `(x, y)`, `end`
+    /// exclusive) and returns it. What is left of the first and last lines is
+    /// joined; an end past the last line is the end of the buffer.
+    pub fn delete_range(&mut self, start: (usize, usize), end: (usize, usize)) -> String {
+        let Some((start, end)) = self.clamp_range(start, end) else {
+            return String::new();
+        };
+        let removed = self.text_between(start, end);
+        self.replace(start, end, "");
+        removed
+    }
+
+    /// Clamps `start` and `end` to the buffer and their lines, and turns their
+    /// columns into chars. `None` when the range starts past the last line or
+    /// ends on a line before it starts.
+    fn clamp_range(
+        &self,
+        start: (usize, usize),
+        end: (usize, usize),
+    ) -> Option<((usize, usize), (usize, usize))> {
+        let ((x1, y1), (x2, y2)) = (start, end);
+        if y1 > y2 || y1 >= self.len() {
+            return None;
+        }
+        let y2 = y2.min(self.len() - 1);
+        let x1 = self.char_col(y1, x1);
+        let x2 = self.char_col(y2, x2);
+        let x2 = if y1 == y2 { x2.max(x1) } else { x2 };
+        Some(((x1, y1), (x2, y2)))
+    }
+
+    /// The char that column `x` of line `y` starts at, the end of the line for
+    /// columns past it.
+    fn char_col(&self, y: usize, x: usize) -> usize {
+        char_index(&self.text.line(y), x)
+    }
+
+    fn chars_in(&self, y: usize) -> usize {
+        let end = match self.text.line_to_char(y + 1) {
+            end if y + 1 < self.len() => end - 1,
+            end => end,
+        };
+        end - self.text.line_to_char(y)
+    }
+
+    /// The text between two positions in chars, `end` exclusive. Both must be
+    /// inside the buffer.
+    fn text_between(&self, start: (usize, usize), end: (usize, usize)) -> String {
+        let ((x1, y1), (x2, y2)) = (start, end);
+        self.text.slice(self.offset(y1, x1), self.offset(y2, x2))
+    }
+
+    /// Finds the first occurrence of `pattern` after column `x` of line `y`,
+    /// wrapping around the end of the buffer. Returns the match as `(x, y)`.
+    pub fn find(
+        &self,
+        pattern: &str,
+        x: usize,
+        y: usize,
+        ignore_case: bool,
+    ) -> Option<(usize, usize)> {
+        if pattern.is_empty() {
+            return None;
+        }
+
+        let len = self.len();
+        let y = y.min(len - 1);
+        for i in 0..=len {
+            let line_y = (y + i) % len;
+            let line = self.text.line(line_y);
+            let found = find_all(&line, pattern, ignore_case)
+                .into_iter()
+                .map(|(byte, _)| column_at(&line, byte))
+                .find(|&col| match i {
+                    0 => col > x,
+                    _ if i == len => col <= x,
+                    _ => true,
+                });
+
+            if let Some(col) = found {
+                return Some((col, line_y));
+            }
+        }
 
-            if char_count < x {
-                new_line.push_str(&" ".repeat(x - char_count));
-                new_line.push(c);
-            } else if char_count == x {
-                new_line.push(c);
+        None
+    }
+
+    /// Like [`Buffer::find`] for a regular expression: the first match after
+    /// column `from_x` of line `from_y`, wrapping around, as `(line, start_col,
+    /// end_col)`. `None` also when `pattern` is not a valid regex.
+    #[cfg(feature = "regex-search")]
+    pub fn search_regex(
+        &self,
+        pattern: &str,
+        from_y: usize,
+        from_x: usize,
+        ignore_case: bool,
+    ) -> Option<(usize, usize, usize)> {
+        let regex = regex::RegexBuilder::new(pattern)
+            .case_insensitive(ignore_case)
+            .build()
+            .ok()?;
+        let len = self.len();
+        let from_y = from_y.min(len - 1);
+        for i in 0..=len {
+            let y = (from_y + i) % len;
+            let line = self.text.line(y);
+            let found = regex
+                .find_iter(&line)
+                .map(|m| (column_at(&line, m.start()), column_at(&line, m.end())))
+                .find(|&(col, _)| match i {
+                    0 => col > from_x,
+                    _ if i == len => col <= from_x,
+                    _ => true,
+                });
+
+            if let Some((start, end)) = found {
+                return Some((y, start, end));
             }
+        }
+
+        None
+    }
 
-            *line = new_line;
+    /// Where the word next to column `x` of line `y` ends, going right when
+    /// `forward` and left otherwise: past the blanks in between, then past the
+    /// run of chars of one class. Stays within the line.
+    pub fn word_boundary(&self, x: usize, y: usize, forward: bool) -> usize {
+        let line = self.column_chars(y);
+        let x = x.min(line.len());
+        let class_at = |i: usize| char_class(line[i]);
+        if forward {
+            let mut end = x;
+            while end < line.len() && class_at(end) == CharClass::Blank {
+                end += 1;
+            }
+            if let Some(class) = (end < line.len()).then(|| class_at(end)) {
+                while end < line.len() && class_at(end) == class {
+                    end += 1;
+                }
+            }
+            end
         } else {
-            let mut new_line = String::new();
-            if x > 0 {
-                new_line.push_str(&" ".repeat(x as usize));
+            let mut start = x;
+            while start > 0 && class_at(start - 1) == CharClass::Blank {
+                start -= 1;
             }
-            new_line.push(c);
-            self.lines.push(new_line);
+            if let Some(class) = (start > 0).then(|| class_at(start - 1)) {
+                while start > 0 && class_at(start - 1) == class {
+                    start -= 1;
+                }
+            }
+            start
         }
     }
 
-    pub fn remove(&mut self, x: u16, y: u16) {
+    /// The chars of line `y` that `iw`, or `aw` when `around`, selects at column
+    /// `x`, as `start..end`. `iw` is the run of chars of one class there. `aw`
+    /// adds the blanks after a word, or those before it when there are none
+    /// after (but not the indentation); on blanks it adds the word that follows.
+    /// Empty on an empty line.
+    pub fn word_object(&self, x: usize, y: usize, around: bool) -> (usize, usize) {
+        let classes: Vec<CharClass> = self.column_chars(y).into_iter().map(char_class).collect();
+        let Some(last) = classes.len().checked_sub(1) else {
+            return (0, 0);
+        };
+        let x = x.min(last);
+        let run_start = |mut i: usize, class| {
+            while i > 0 && classes[i - 1] == class {
+                i -= 1;
+            }
+            i
+        };
+        let run_end = |mut i: usize, class| {
+            while i < classes.len() && classes[i] == class {
+                i += 1;
+            }
+            i
+        };
+
+        let (start, end) = (run_start(x, classes[x]), run_end(x, classes[x]));
+        if !around {
+            return (start, end);
+        }
+        if classes[x] == CharClass::Blank {
+            return match classes.get(end) {
+                Some(&class) => (start, run_end(end, class)),
+                None => (start, end),
+            };
+        }
+
+        let with_trailing = run_end(end, CharClass::Blank);
+        if with_trailing > end {
+            return (start, with_trailing);
+        }
+        match run_start(start, CharClass::Blank) {
+            0 => (start, end),
+            leading => (leading, end),
+        }
+    }
+
+    /// Replaces `pattern` with `replacement` on lines `start..=end`: the first
+    /// match on each line, or every match when `global`. The pattern is matched
+    /// literally. Returns the number of replacements and the last line changed.
+    pub fn substitute(
+        &mut self,
+        start: usize,
+        end: usize,
+        pattern: &str,
+        replacement: &str,
+        global: bool,
+        ignore_case: bool,
+    ) -> (usize, Option<usize>) {
+        let (mut count, mut last) = (0, None);
+        if pattern.is_empty() {
+            return (count, last);
+        }
+        for y in start..=end.min(self.len() - 1) {
+            let line = self.text.line(y);
+            let mut matches = find_all(&line, pattern, ignore_case);
+            if matches.is_empty() {
+                continue;
+            }
+            if !global {
+                matches.truncate(1);
+            }
+            let mut new = String::new();
+            let mut rest = 0;
+            for &(start, end) in &matches {
+                new.push_str(&line[rest..start]);
+                new.push_str(replacement);
+                rest = end;
+            }
+            new.push_str(&line[rest..]);
+            self.replace((0, y), (usize::MAX, y), &new);
+            count += matches.len();
+            last = Some(y);
+        }
+        (count, last)
+    }
+
+    /// Replaces columns `x_start..x_end` of line `y` with `transform` applied to
+    /// them. The replacement may have a different length, e.g. `ß` uppercases to `SS`.
+    pub fn transform_range(
+        &mut self,
+        y: u16,
+        x_start: u16,
+        x_end: u16,
+        transform: fn(&str) -> String,
+    ) {
         let y = y as usize;
-        let x = x as usize;
-
-        if let Some(line) = self.lines.get_mut(y) {
-            if !line.is_empty() && x < line.chars().count() {
-                let mut chars: Vec<char> = line.chars().collect();
-                if x < chars.len() {
-                    chars.remove(x);
-                    *line = chars.into_iter().collect();
-                }
+        if y >= self.len() {
+            return;
+        }
+        let (start, end) = (
+            self.char_col(y, x_start as usize),
+            self.char_col(y, x_end as usize),
+        );
+        if start >= end {
+            return;
+        }
+
+        let old = self.text_between((start, y), (end, y));
+        let new = transform(&old);
+        if new != old {
+            self.apply_edit(&Edit::Replace {
+                start: (start, y),
+                old,
+                new,
+            });
+        }
+    }
+
+    /// Adds one level of indentation to line `y`: `shiftwidth` spaces with
+    /// `expandtab`, a tab without. Empty lines are left as they are. Returns
+    /// the number of chars added.
+    pub fn indent_line(&mut self, y: u16, shiftwidth: usize, expandtab: bool) -> usize {
+        let y = y as usize;
+        if self.get(y).is_none_or(|line| line.is_empty()) {
+            return 0;
+        }
+        let indent = match expandtab {
+            true => " ".repeat(shiftwidth.max(1)),
+            false => "\t".to_string(),
+        };
+        self.replace((0, y), (0, y), &indent);
+        indent.len()
+    }
+
+    /// Removes one level of indentation from line `y`: a leading tab, or up to
+    /// `shiftwidth` leading spaces. Returns the number of chars removed.
+    pub fn outdent_line(&mut self, y: u16, shiftwidth: usize) -> usize {
+        let y = y as usize;
+        let Some(line) = self.get(y) else {
+            return 0;
+        };
+
+        let removed = if line.starts_with('\t') {
+            1
+        } else {
+            line.chars()
+                .take(shiftwidth.max(1))
+                .take_while(|c| *c == ' ')
+                .count()
+        };
+        if removed > 0 {
+            let old = line[..removed].to_string();
+            self.apply_edit(&Edit::Replace {
+                start: (0, y),
+                old,
+                new: String::new(),
+            });
+        }
+        removed
+    }
+
+    /// Removes columns `left..right` from each of lines `top..=bottom`, the
+    /// lines shorter than `left` being left as they are.
+    pub fn delete_block(&mut self, top: usize, bottom: usize, left: usize, right: usize) {
+        for y in top..=bottom.min(self.len() - 1) {
+            let len = self.chars_on_line(y);
+            if len > left {
+                self.replace((left, y), (right.min(len), y), "");
             }
         }
     }
 
-    pub fn save(&self) {
-        if let Some(file) = &self.file {
-            let content = self.lines.join("\n");
-            std::fs::write(file, content).unwrap();
+    /// Inserts `text` at column `col` of each of lines `top..=bottom`. Lines that
+    /// do not reach `col` are padded with spaces when `pad` is set, else skipped.
+    pub fn insert_block(&mut self, top: usize, bottom: usize, col: usize, text: &str, pad: bool) {
+        for y in top..=bottom.min(self.len() - 1) {
+            let len = self.chars_on_line(y);
+            if len <= col && !pad {
+                continue;
+            }
+            let padding = " ".repeat(col.saturating_sub(len));
+            let x = col.min(len);
+            self.replace((x, y), (x, y), &format!("{padding}{text}"));
+        }
+    }
+
+    /// Comments out lines `top..=bottom` with `prefix`, or uncomments them when
+    /// every non-blank one is commented already. Blank lines are left alone.
+    /// The prefix goes after the smallest indentation of the lines, so nested
+    /// code stays aligned.
+    pub fn toggle_comment(&mut self, top: usize, bottom: usize, prefix: &str) {
+        let indent = |line: &str| line.chars().take_while(|c| c.is_whitespace()).count();
+        let lines: Vec<(usize, String)> = (top..=bottom.min(self.len() - 1))
+            .map(|y| (y, self.text.line(y).into_owned()))
+            .filter(|&(y, _)| !self.is_blank_line(y))
+            .collect();
+        if lines.is_empty() {
+            return;
+        }
+
+        let commented = lines
+            .iter()
+            .all(|(_, line)| line.trim_start().starts_with(prefix));
+        if commented {
+            for (y, line) in lines {
+                let x = indent(&line);
+                let rest = &line.trim_start()[prefix.len()..];
+                let len = prefix.graphemes(true).count() + usize::from(rest.starts_with(' '));
+                self.replace((x, y), (x + len, y), "");
+            }
+        } else {
+            let x = lines
+                .iter()
+                .map(|(_, line)| indent(line))
+                .min()
+                .unwrap_or(0);
+            for (y, _) in lines {
+                self.replace((x, y), (x, y), &format!("{prefix} "));
+            }
+        }
+    }
+
+    /// Finds the bracket matching the first of `()[]{}` at or after column `x` of
+    /// line `y`, searching across lines. Returns its position as `(x, y)`.
+    pub fn matching_bracket(&self, x: usize, y: usize) -> Option<(usize, usize)> {
+        let line = (y < self.len()).then(|| self.column_chars(y))?;
+        let x = (x..line.len()).find(|&i| "()[]{}".contains(line[i]))?;
+        let open = line[x];
+        let (close, forward) = match open {
+            '(' => (')', true),
+            '[' => (']', true),
+            '{' => ('}', true),
+            ')' => ('(', false),
+            ']' => ('[', false),
+            _ => ('{', false),
+        };
+
+        // read a line at a time from the bracket on, so that only the lines
+        // up to the match are read, not the whole buffer
+        let mut depth = 0;
+        let mut matches = |c: char| {
+            if c == open {
+                depth += 1;
+            } else if c == close {
+                depth -= 1;
+            }
+            depth == 0
+        };
+        let (mut y, mut line) = (y, line);
+        let mut cols = if forward { x..line.len() } else { 0..x + 1 };
+        loop {
+            let found = if forward {
+                cols.clone().find(|&i| matches(line[i]))
+            } else {
+                cols.clone().rev().find(|&i| matches(line[i]))
+            };
+            if let Some(x) = found {
+                return Some((x, y));
+            }
+            y = if forward { y + 1 } else { y.checked_sub(1)? };
+            line = (y < self.len()).then(|| self.column_chars(y))?;
+            cols = 0..line.len();
         }
     }
 
-    pub fn remove_line(&mut self, y: u16) {
+    /// The line that a char offset into the text of all lines joined by `\n`
+    /// falls on, `None` past the end of the buffer.
+    pub fn line_at_char_offset(&self, offset: usize) -> Option<usize> {
+        (offset <= self.text.len_chars()).then(|| self.text.char_to_line(offset))
+    }
+
+    /// Converts a char offset into the text of all lines joined by `\n` to a
+    /// `(line, col)` position. Offsets past the end give the end of the last line.
+    pub fn offset_to_pos(&self, offset: usize) -> (usize, usize) {
+        let Some(y) = self.line_at_char_offset(offset) else {
+            let y = self.len() - 1;
+            return (y, self.chars_on_line(y));
+        };
+        let line = self.text.line(y);
+        let chars = offset - self.text.line_to_char(y);
+        let byte = line
+            .char_indices()
+            .nth(chars)
+            .map_or(line.len(), |(i, _)| i);
+        (y, column_at(&line, byte))
+    }
+
+    /// The char offset of `(line, col)` in the text of all lines joined by `\n`.
+    /// Columns past the end of the line are clamped to it.
+    pub fn pos_to_offset(&self, line: usize, col: usize) -> usize {
+        self.offset(line, self.char_col(line, col))
+    }
+
+    /// Like [`Buffer::pos_to_offset`], for a column in chars, as edits have.
+    fn offset(&self, y: usize, x: usize) -> usize {
+        self.text.line_to_char(y) + x.min(self.chars_in(y))
+    }
+
+    /// The first char of each grapheme of line `y`, one per column: enough to
+    /// tell words, blanks and brackets apart.
+    pub fn column_chars(&self, y: usize) -> Vec<char> {
+        let line = self.text.line(y);
+        line.graphemes(true)
+            .filter_map(|g| g.chars().next())
+            .collect()
+    }
+
+    /// The distinct words starting with `prefix`, in the order Ctrl-n finds
+    /// them: from column `x` of line `y` on to the end of the buffer, then from
+    /// its start round to there. Matching is case-sensitive.
+    pub fn words_with_prefix(&self, prefix: &str, x: usize, y: usize) -> Vec<String> {
+        // line `y` comes twice: first its words from `x` on, last those before
+        let lines = (y..self.len()).chain(0..=y);
+        let x = self.char_col(y, x);
+        let last = self.len();
+
+        let mut seen = HashSet::new();
+        let mut words = vec![];
+        for (i, line) in lines.enumerate() {
+            let mut col = 0;
+            for word in self.text.line(line).split(|c: char| !is_word_char(c)) {
+                let start = col;
+                col += word.chars().count() + 1;
+                let wanted = match i {
+                    0 => start >= x,
+                    _ if i == last => start < x,
+                    _ => true,
+                };
+                if wanted
+                    && !word.is_empty()
+                    && word.starts_with(prefix)
+                    && seen.insert(word.to_string())
+                {
+                    words.push(word.to_string());
+                }
+            }
+        }
+        words
+    }
+
+    /// Removes line `y` and returns its content (empty if there is no such line).
+    /// The only line of the buffer is emptied instead.
+    pub fn remove_line(&mut self, y: u16) -> String {
         let y = y as usize;
-        if y < self.lines.len() {
-            self.lines.remove(y);
+        let Some(line) = self.get(y) else {
+            return String::new();
+        };
+        if self.len() == 1 {
+            self.replace((0, 0), (usize::MAX, 0), "");
+            return line;
+        }
+
+        self.apply_edit(&Edit::RemoveLine {
+            y,
+            line: line.clone(),
+        });
+        line
+    }
+
+    pub fn insert_line(&mut self, y: usize, line: String) {
+        let y = y.min(self.len());
+        self.apply_edit(&Edit::InsertLine { y, line });
+    }
+}
+
+/// Splits file content into lines, an empty file giving one empty line, and
+/// tells whether it ended with a newline. `\r\n` line endings become `\n`.
+fn split_lines(content: &str) -> (Rope, bool) {
+    let text = content.replace("\r\n", "\n");
+    let text = text.strip_suffix('\n').unwrap_or(&text);
+    (Rope::from(text), content.ends_with('\n'))
+}
+
+/// The char index where column `x` of `line` starts, a column being one
+/// grapheme; the end of the line for columns past it.
+fn char_index(line: &str, x: usize) -> usize {
+    match line.grapheme_indices(true).nth(x) {
+        Some((byte, _)) => line[..byte].chars().count(),
+        None => line.chars().count(),
+    }
+}
+
+/// The column of `line` that byte `byte` falls in.
+fn column_at(line: &str, byte: usize) -> usize {
+    line.grapheme_indices(true)
+        .take_while(|(i, g)| i + g.len() <= byte)
+        .count()
+}
+
+pub fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Where a buffer was pointed before [`Buffer::retarget`].
+pub struct Target {
+    file: Option<String>,
+    mtime: Option<SystemTime>,
+    modified: bool,
+    changes: usize,
+}
+
+/// What a char counts as when moving or selecting by words: a run of chars of
+/// the same class makes one word.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CharClass {
+    Blank,
+    Word,
+    Punctuation,
+}
+
+pub fn char_class(c: char) -> CharClass {
+    if c.is_whitespace() {
+        CharClass::Blank
+    } else if is_word_char(c) {
+        CharClass::Word
+    } else {
+        CharClass::Punctuation
+    }
+}
+
+/// The byte ranges of the matches of `pattern` in `line`, left to right and
+/// not overlapping. With `ignore_case`, chars match regardless of their case.
+fn find_all(line: &str, pattern: &str, ignore_case: bool) -> Vec<(usize, usize)> {
+    if !ignore_case {
+        return (line.match_indices(pattern))
+            .map(|(start, found)| (start, start + found.len()))
+            .collect();
+    }
+    let same = |a: char, b: char| a == b || a.to_lowercase().eq(b.to_lowercase());
+    let mut found = vec![];
+    let mut start = 0;
+    while start < line.len() && !pattern.is_empty() {
+        let mut chars = line[start..].char_indices();
+        let matched = pattern
+            .chars()
+            .all(|p| chars.next().is_some_and(|(_, c)| same(c, p)));
+        if matched {
+            let end = chars.next().map_or(line.len(), |(i, _)| start + i);
+            found.push((start, end));
+            start = end;
+        } else {
+            start += line[start..].chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    found
+}
+
+fn disk_mtime(file: &str) -> Option<SystemTime> {
+    std::fs::metadata(file).and_then(|m| m.modified()).ok()
+}
+
+fn first_number(line: &str) -> Option<i64> {
+    let start = line.find(|c: char| c.is_ascii_digit())?;
+    let digits = &line[start..];
+    let len = digits
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(digits.len());
+    let n: i64 = digits[..len].parse().ok()?;
+    let negative = line[..start].ends_with('-');
+    Some(if negative { -n } else { n })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    fn buffer(lines: &[&str]) -> Buffer {
+        Buffer::from_lines(lines)
+    }
+
+    fn lines(buffer: &Buffer) -> Vec<String> {
+        buffer.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn saving_preserves_the_bytes_of_the_file() {
+        let dir = TempDir::new("roundtrip");
+        for content in ["", "a", "a\n", "\n", "one\ntwo", "one\n\n"] {
+            dir.write("file", content);
+            let mut buffer = Buffer::from_file(Some(dir.file("file"))).unwrap();
+            buffer.save().unwrap();
+            assert_eq!(std::fs::read_to_string(dir.join("file")).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn only_a_missing_file_opens_empty() {
+        let dir = TempDir::new("unreadable");
+        let buffer = Buffer::from_file(Some(dir.file("file"))).unwrap();
+        assert_eq!(lines(&buffer), [""]);
+
+        dir.write("file", b"caf\xe9\n");
+        let Err(err) = Buffer::from_file(Some(dir.file("file"))) else {
+            panic!("a file that is not UTF-8 was read");
+        };
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn content_can_be_indented_and_dedented() {
+        let code = buffer(&["fn main() {", "", "    body();", "}"]);
+        assert_eq!(
+            code.get_indented_content("  "),
+            "  fn main() {\n\n      body();\n  }"
+        );
+
+        let nested = buffer(&["\t    if x {", "  ", "\t        y", "\t    }"]);
+        let (content, prefix) = nested.dedented_content();
+        assert_eq!(prefix, "\t    ");
+        assert_eq!(content, "if x {\n\n    y\n}");
+    }
+
+    #[test]
+    fn lines_in_a_range_and_chars_are_counted() {
+        let buffer = buffer(&["héllo", "", "wörld!"]);
+        assert_eq!(buffer.len_lines_in_range(0, 2), 2);
+        assert_eq!(buffer.len_lines_in_range(1, 10), 2);
+        assert_eq!(buffer.len_lines_in_range(3, 2), 0);
+        assert_eq!(buffer.total_chars(), 11);
+    }
+
+    #[test]
+    fn an_empty_file_is_one_empty_line() {
+        let mut buffer = Buffer::from_file(None).unwrap();
+        assert_eq!(lines(&buffer), [""]);
+        assert_eq!(buffer.content(), "");
+
+        buffer.insert(0, 0, 'x');
+        assert_eq!(buffer.remove_line(0), "x");
+        assert_eq!(lines(&buffer), [""]);
+    }
+
+    #[test]
+    fn toggle_comment_keeps_indentation_and_comments_mixed_lines() {
+        let mut buffer = buffer(&["fn f() {", "    // a();", "", "    b();", "}"]);
+        buffer.toggle_comment(1, 3, "//");
+        assert_eq!(lines(&buffer)[1..4], ["    // // a();", "", "    // b();"]);
+
+        buffer.toggle_comment(1, 3, "//");
+        assert_eq!(lines(&buffer)[1..4], ["    // a();", "", "    b();"]);
+
+        buffer.toggle_comment(0, 4, "//");
+        assert_eq!(lines(&buffer)[0], "// fn f() {");
+        assert_eq!(lines(&buffer)[3], "//     b();");
+    }
+
+    #[test]
+    fn toggle_comment_removes_the_prefix_without_a_space() {
+        let mut buffer = buffer(&["  #x", "  # y"]);
+        buffer.toggle_comment(0, 1, "#");
+        assert_eq!(lines(&buffer), ["  x", "  y"]);
+    }
+
+    #[test]
+    fn delete_range_joins_lines_and_returns_the_text() {
+        let mut buffer = Buffer::from_lines(&["one", "two", "three", "four"]);
+        assert_eq!(buffer.delete_range((1, 0), (2, 0)), "n");
+        assert_eq!(buffer.delete_range((1, 0), (2, 2)), "e\ntwo\nth");
+        assert_eq!(lines(&buffer), ["oree", "four"]);
+
+        assert_eq!(buffer.delete_range((0, 0), (0, 1)), "oree\n");
+        assert_eq!(lines(&buffer), ["four"]);
+        assert_eq!(buffer.delete_range((2, 0), (9, 9)), "ur");
+        assert_eq!(buffer.delete_range((0, 3), (0, 4)), "");
+        assert_eq!(lines(&buffer), ["fo"]);
+    }
+
+    #[test]
+    fn substitute_replaces_the_first_or_every_match() {
+        let mut buffer = buffer(&["a.b.c", "none", "x.y"]);
+        assert_eq!(
+            buffer.substitute(0, 2, ".", "::", false, false),
+            (2, Some(2))
+        );
+        assert_eq!(lines(&buffer), ["a::b.c", "none", "x::y"]);
+        assert_eq!(buffer.substitute(0, 0, ".", "-", true, false), (1, Some(0)));
+        assert_eq!(buffer.substitute(0, 2, "::", "", true, false), (2, Some(2)));
+        assert_eq!(lines(&buffer), ["ab-c", "none", "xy"]);
+        assert_eq!(buffer.substitute(1, 1, "q", "r", true, false), (0, None));
+
+        let mut accented = Buffer::from_lines(&["Straße STRASSE", "ÉtÉ été"]);
+        assert_eq!(
+            accented.substitute(0, 1, "é", "e", true, true),
+            (4, Some(1))
+        );
+        assert_eq!(
+            accented.substitute(0, 1, "straße", "-", false, true),
+            (1, Some(0))
+        );
+        assert_eq!(lines(&accented), ["- STRASSE", "ete ete"]);
+    }
+
+    #[test]
+    fn word_objects_split_words_punctuation_and_blanks() {
+        let buffer = buffer(&["    foo.bar(x)  end", ""]);
+        assert_eq!(buffer.word_object(5, 0, false), (4, 7));
+        assert_eq!(buffer.word_object(7, 0, false), (7, 8));
+        assert_eq!(buffer.word_object(8, 0, true), (8, 11));
+        assert_eq!(buffer.word_object(13, 0, true), (13, 16));
+        assert_eq!(buffer.word_object(14, 0, true), (14, 19));
+        // no blanks after the last word, so the ones before it go instead
+        assert_eq!(buffer.word_object(17, 0, true), (14, 19));
+        // but not the indentation of the first one
+        assert_eq!(buffer.word_object(4, 0, true), (4, 7));
+        assert_eq!(buffer.word_object(0, 1, true), (0, 0));
+    }
+
+    #[test]
+    fn indent_level_counts_tab_stops() {
+        let mut buffer = buffer(&["\tx", "        x", "  \tx", "     x", "   ", "x"]);
+        buffer.tabstop = 4;
+        let levels: Vec<usize> = (0..6).map(|y| buffer.indent_level(y)).collect();
+        assert_eq!(levels, [1, 2, 1, 1, 0, 0]);
+        let blank: Vec<bool> = (0..7).map(|y| buffer.is_blank_line(y)).collect();
+        assert_eq!(blank, [false, false, false, false, true, false, false]);
+    }
+
+    #[test]
+    fn word_boundaries_skip_blanks_then_one_word() {
+        let buffer = buffer(&["let foo = bar(1);  "]);
+        assert_eq!(buffer.word_boundary(7, 0, false), 4);
+        assert_eq!(buffer.word_boundary(8, 0, false), 4);
+        assert_eq!(buffer.word_boundary(4, 0, false), 0);
+        assert_eq!(buffer.word_boundary(0, 0, false), 0);
+        assert_eq!(buffer.word_boundary(7, 0, true), 9);
+        assert_eq!(buffer.word_boundary(13, 0, true), 14);
+        assert_eq!(buffer.word_boundary(17, 0, true), 19);
+    }
+
+    #[test]
+    fn whitespace_issues_count_trailing_blanks_and_odd_indents() {
+        let untidy = buffer(&["fn f() {", "    a; ", "\tb;", "    c;\t", "   ", "}"]);
+        assert_eq!(untidy.whitespace_issues(), (3, 1));
+        assert_eq!(buffer(&["\ta", "\tb"]).whitespace_issues(), (0, 0));
+    }
+
+    #[cfg(feature = "regex-search")]
+    #[test]
+    fn regex_search_wraps_around() {
+        let buffer = buffer(&["let x = 10;", "let y = 200;"]);
+        assert_eq!(buffer.search_regex(r"\d+", 0, 0, false), Some((0, 8, 10)));
+        assert_eq!(buffer.search_regex(r"\d+", 0, 8, false), Some((1, 8, 11)));
+        assert_eq!(buffer.search_regex(r"\d+", 1, 8, false), Some((0, 8, 10)));
+        assert_eq!(buffer.search_regex("^let", 1, 0, false), Some((0, 0, 3)));
+        assert_eq!(buffer.search_regex("(", 0, 0, false), None);
+    }
+
+    #[test]
+    fn folds_follow_the_indentation() {
+        let buffer = buffer(&[
+            "fn main() {",
+            "    if x {",
+            "        y();",
+            "",
+            "        z();",
+            "    }",
+            "}",
+            "",
+            "fn f() {}",
+            "mod a {",
+            "    b",
+        ]);
+        assert_eq!(buffer.detect_indent_folds(), [(0, 5), (1, 4), (9, 10)]);
+    }
+
+    #[test]
+    fn offsets_and_positions_convert_both_ways() {
+        let buffer = buffer(&["ab", "", "çd"]);
+        assert_eq!(buffer.pos_to_offset(0, 1), 1);
+        assert_eq!(buffer.pos_to_offset(1, 0), 3);
+        assert_eq!(buffer.pos_to_offset(2, 1), 5);
+        assert_eq!(buffer.pos_to_offset(2, 9), 6);
+        for offset in 0..=6 {
+            let (line, col) = buffer.offset_to_pos(offset);
+            assert_eq!(buffer.pos_to_offset(line, col), offset);
+        }
+        assert_eq!(buffer.offset_to_pos(2), (0, 2));
+        assert_eq!(buffer.offset_to_pos(100), (2, 2));
+        assert_eq!(buffer.line_at_char_offset(3), Some(1));
+        assert_eq!(buffer.line_at_char_offset(6), Some(2));
+        assert_eq!(buffer.line_at_char_offset(7), None);
+
+        // a column is a whole grapheme, accent and all
+        let accented = Buffer::from_lines(&["e\u{301}a"]);
+        assert_eq!(accented.pos_to_offset(0, 1), 2);
+        assert_eq!(accented.offset_to_pos(1), (0, 0));
+        assert_eq!(accented.offset_to_pos(2), (0, 1));
+    }
+
+    #[test]
+    fn matching_bracket_searches_across_lines() {
+        let buffer = buffer(&["fn f() {", "    (a[0])", "}"]);
+        assert_eq!(buffer.matching_bracket(0, 0), Some((5, 0)));
+        assert_eq!(buffer.matching_bracket(7, 0), Some((0, 2)));
+        assert_eq!(buffer.matching_bracket(0, 2), Some((7, 0)));
+        assert_eq!(buffer.matching_bracket(9, 1), Some((4, 1)));
+        assert_eq!(buffer.matching_bracket(0, 1), Some((9, 1)));
+        assert_eq!(buffer.matching_bracket(4, 1), Some((9, 1)));
+
+        let unmatched = Buffer::from_lines(&["(", "", "a[", "]"]);
+        assert_eq!(unmatched.matching_bracket(0, 0), None);
+        assert_eq!(unmatched.matching_bracket(0, 3), Some((1, 2)));
+    }
+
+    #[test]
+    fn words_are_found_from_the_cursor_round_the_buffer() {
+        let buffer = buffer(&["fa fb", "fc x fd fe", "ff"]);
+        assert_eq!(
+            buffer.words_with_prefix("f", 5, 1),
+            ["fd", "fe", "ff", "fa", "fb", "fc"]
+        );
+        assert_eq!(buffer.words_with_prefix("fe", 0, 0), ["fe"]);
+    }
+
+    #[test]
+    fn unapplying_edits_in_reverse_restores_the_lines() {
+        let mut buffer = buffer(&["hello", "wide world", "end"]);
+        buffer.insert(5, 0, '!');
+        buffer.insert(8, 2, 'x');
+        buffer.remove(0, 1);
+        buffer.replace((2, 0), (3, 1), "y\nnew\nz");
+        buffer.remove_line(3);
+        buffer.insert_line(0, "top".to_string());
+        buffer.sort_lines(0, 3, true, false, false);
+
+        let edits = buffer.take_edits();
+        for edit in edits.iter().rev() {
+            buffer.unapply_edit(edit);
+        }
+        assert_eq!(lines(&buffer), ["hello", "wide world", "end"]);
+
+        buffer.take_edits();
+        for edit in &edits {
+            buffer.apply_edit(edit);
+        }
+        assert_eq!(lines(&buffer), ["z world", "top", "new", "hey"]);
+    }
+
+    #[test]
+    fn retab_keeps_the_column_the_indent_reaches() {
+        let mut code = buffer(&["  \ty", "\t  \tz", "          w", "x"]);
+        assert_eq!(code.retab(0, 3, true, 8), 2);
+        assert_eq!(
+            lines(&code),
+            [
+                format!("{}y", " ".repeat(8)),
+                format!("{}z", " ".repeat(16)),
+                format!("{}w", " ".repeat(10)),
+                "x".to_string(),
+            ]
+        );
+
+        let mut code = buffer(&["  \ty", "\t  \tz", "          w", "x"]);
+        assert_eq!(code.retab(0, 3, false, 8), 3);
+        assert_eq!(lines(&code), ["\ty", "\t\tz", "\t  w", "x"]);
+    }
+
+    #[test]
+    fn get_range_counts_graphemes() {
+        let buffer = buffer(&["e\u{301}te\u{301} café"]);
+        assert_eq!(buffer.get_range(0, 1, 3), "te\u{301}");
+        assert_eq!(buffer.get_range(0, 4, 100), "café");
+        assert_eq!(buffer.get_range(0, 3, 3), "");
+        assert_eq!(buffer.get_range(5, 0, 3), "");
+        assert_eq!(
+            buffer.graphemes_on_line(0)[..4],
+            ["e\u{301}", "t", "e\u{301}", " "]
+        );
+        assert!(buffer.graphemes_on_line(5).is_empty());
+    }
+
+    #[test]
+    fn get_multiline_range_joins_lines() {
+        let buffer = buffer(&["first", "second", "third"]);
+        assert_eq!(buffer.get_multiline_range(0, 3, 2, 2), "st\nsecond\nth");
+        assert_eq!(buffer.get_multiline_range(1, 6, 2, 0), "\n");
+        assert_eq!(buffer.get_multiline_range(2, 0, 1, 3), "");
+    }
+
+    #[test]
+    fn indent_adds_a_shiftwidth_or_a_tab_to_lines_with_text() {
+        let mut buffer = buffer(&["foo", "", "\tbar"]);
+        assert_eq!(buffer.indent_line(0, 2, true), 2);
+        assert_eq!(buffer.indent_line(1, 2, true), 0);
+        assert_eq!(buffer.indent_line(2, 2, false), 1);
+        assert_eq!(lines(&buffer), ["  foo", "", "\t\tbar"]);
+    }
+
+    #[test]
+    fn outdent_removes_one_shiftwidth_of_spaces() {
+        let mut buffer = buffer(&["      foo", "  bar"]);
+        assert_eq!(buffer.outdent_line(0, 4), 4);
+        assert_eq!(lines(&buffer)[0], "  foo");
+        assert_eq!(buffer.outdent_line(1, 4), 2);
+        assert_eq!(lines(&buffer)[1], "bar");
+        assert!(buffer.modified);
+    }
+
+    #[test]
+    fn outdent_removes_a_leading_tab() {
+        let mut buffer = buffer(&["\t\tfoo"]);
+        assert_eq!(buffer.outdent_line(0, 4), 1);
+        assert_eq!(lines(&buffer)[0], "\tfoo");
+    }
+
+    #[test]
+    fn outdent_leaves_unindented_line_alone() {
+        let mut buffer = buffer(&["foo"]);
+        assert_eq!(buffer.outdent_line(0, 4), 0);
+        assert_eq!(lines(&buffer)[0], "foo");
+        assert!(!buffer.modified);
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn benchmark_borrowing_a_screenful_of_long_lines() {
+        use std::time::Instant;
+
+        let text: Vec<String> = (0..10_000)
+            .map(|i| format!("{i} {}", "x".repeat(300)))
+            .collect();
+        let buffer = buffer(&text.iter().map(String::as_str).collect::<Vec<_>>());
+        let frames = 10_000;
+
+        let start = Instant::now();
+        for frame in 0..frames {
+            for y in frame..frame + 60 {
+                std::hint::black_box(buffer.get(y));
+            }
         }
+        let get_time = start.elapsed();
+
+        let start = Instant::now();
+        for frame in 0..frames {
+            for y in frame..frame + 60 {
+                std::hint::black_box(buffer.line(y));
+            }
+        }
+        let line_time = start.elapsed();
+
+        println!("{frames} screens of 60 lines: get {get_time:?}, line {line_time:?}");
     }
 }
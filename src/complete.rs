@@ -0,0 +1,51 @@
+use std::path::Path;
+
+/// Lists the paths that complete `partial`, reading the directory it points into.
+/// Directories are returned with a trailing `/`; hidden entries are only offered
+/// when the partial name itself starts with a dot.
+pub fn complete_path(partial: &str) -> Vec<String> {
+    let (dir, prefix) = match partial.rfind('/') {
+        Some(i) => (&partial[..=i], &partial[i + 1..]),
+        None => ("", partial),
+    };
+
+    let read_from = if dir.is_empty() { "." } else { dir };
+    let Ok(entries) = std::fs::read_dir(Path::new(read_from)) else {
+        return vec![];
+    };
+
+    let mut matches: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            let is_dir = entry.file_type().ok()?.is_dir();
+            Some(format!("{dir}{name}{}", if is_dir { "/" } else { "" }))
+        })
+        .collect();
+
+    matches.sort();
+    matches
+}
+
+/// The longest string every candidate starts with.
+pub fn common_prefix(candidates: &[String]) -> String {
+    let Some(first) = candidates.first() else {
+        return String::new();
+    };
+
+    let mut len = first.len();
+    for candidate in &candidates[1..] {
+        len = first
+            .char_indices()
+            .zip(candidate.chars())
+            .take_while(|((_, a), b)| a == b)
+            .last()
+            .map_or(0, |((i, a), _)| i + a.len_utf8())
+            .min(len);
+    }
+
+    first[..len].to_string()
+}
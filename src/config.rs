@@ -0,0 +1,166 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use serde::Deserialize;
+
+use crate::log;
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Minimum number of lines kept above and below the cursor.
+    pub scrolloff: u16,
+    /// Width of a tab character.
+    pub tabstop: usize,
+    /// Width of one level of indentation, also used for soft tabs.
+    pub shiftwidth: usize,
+    /// Insert spaces instead of a tab character when Tab is pressed.
+    pub expandtab: bool,
+    /// Backspace over indentation spaces removes a whole `shiftwidth` at a time.
+    pub smarttab: bool,
+    /// Maximum number of changes that can be undone; 0 disables undo.
+    pub undolevels: usize,
+    /// Line comment prefixes by file type, e.g. `text = "#"`, used by `gc` in
+    /// place of the built-in ones.
+    pub comments: HashMap<String, String>,
+    /// Formatters by file type, e.g. `rust = "rustfmt --emit stdout"`: shell
+    /// commands that read the text on stdin and print it formatted.
+    pub formatprg: HashMap<String, String>,
+    /// Pipe the buffer through the `formatprg` of its file type before each
+    /// write. A formatter that fails leaves the buffer as it was and stops
+    /// the write.
+    pub format_on_save: bool,
+    /// Leave out of the file finder what the `.gitignore` of the current
+    /// directory ignores.
+    pub gitignore: bool,
+    /// On save, report lines with trailing whitespace or indented unlike the
+    /// rest of the file. The file is written as it is.
+    pub whitespace_warnings: bool,
+    /// Searches and `:s` match letters regardless of their case.
+    pub ignorecase: bool,
+    /// With `ignorecase`, a pattern with an uppercase letter is still matched
+    /// case-sensitively.
+    pub smartcase: bool,
+    /// Highlight the line the cursor is on.
+    pub cursorline: bool,
+    /// Highlight this screen column, counted from 1, to show where lines get
+    /// too long.
+    pub colorcolumn: Option<usize>,
+    /// Seconds without changes after which a modified buffer is written to its
+    /// file. Off when unset.
+    pub autosave_interval: Option<u64>,
+    /// Chars besides letters and digits that `gf` takes as part of a file name.
+    pub isfname: String,
+    pub keys: KeysConfig,
+    pub statusline: StatusLineConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            scrolloff: 0,
+            tabstop: 8,
+            shiftwidth: 4,
+            expandtab: false,
+            smarttab: true,
+            undolevels: 1000,
+            comments: HashMap::new(),
+            formatprg: HashMap::new(),
+            format_on_save: false,
+            gitignore: true,
+            whitespace_warnings: false,
+            ignorecase: false,
+            smartcase: false,
+            cursorline: true,
+            colorcolumn: None,
+            autosave_interval: None,
+            isfname: "/.-_+#$%~=".to_string(),
+            keys: KeysConfig::default(),
+            statusline: StatusLineConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct KeysConfig {
+    /// The key `<leader>` stands for in mappings, e.g. `","`.
+    pub leader: String,
+    /// Normal-mode overrides, e.g. `";" = "command_mode"`, `"q" = "none"` or
+    /// `"<leader>w" = "save"`.
+    pub normal: HashMap<String, String>,
+}
+
+impl Default for KeysConfig {
+    fn default() -> Self {
+        KeysConfig {
+            leader: "<Space>".to_string(),
+            normal: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct StatusLineConfig {
+    /// Segments from left to right, out of `mode`, `file`, `position`, `percent`
+    /// and `filetype`. The `file` segment stretches to fill the line.
+    pub segments: Vec<String>,
+    /// A format string like `" %f%m%=%y %l:%c "`, used instead of the segments
+    /// when set. It is drawn in the file colors; see [`crate::statusline::expand`].
+    pub format: Option<String>,
+    /// Colors as `#rrggbb` or a name such as `dark_grey`.
+    pub mode_fg: String,
+    pub mode_bg: String,
+    pub file_fg: String,
+    pub file_bg: String,
+    pub position_fg: String,
+    pub position_bg: String,
+}
+
+impl Default for StatusLineConfig {
+    fn default() -> Self {
+        StatusLineConfig {
+            segments: ["mode", "file", "filetype", "position"]
+                .map(String::from)
+                .to_vec(),
+            format: None,
+            mode_fg: "#000000".to_string(),
+            mode_bg: "#b890f3".to_string(),
+            file_fg: "#ffffff".to_string(),
+            file_bg: "#434659".to_string(),
+            position_fg: "#000000".to_string(),
+            position_bg: "#b890f3".to_string(),
+        }
+    }
+}
+
+impl Config {
+    /// Loads `$XDG_CONFIG_HOME/vigil/config.toml` (or `~/.config/vigil/config.toml`).
+    /// A missing file gives the defaults; a malformed one is logged and ignored.
+    pub fn load() -> Config {
+        let Some(path) = config_path() else {
+            return Config::default();
+        };
+
+        let Ok(content) = std::fs::read_to_string(&path) else {
+            return Config::default();
+        };
+
+        match toml::from_str(&content) {
+            Ok(config) => config,
+            Err(err) => {
+                log!("config: ignoring {}: {err}", path.display());
+                Config::default()
+            }
+        }
+    }
+}
+
+fn config_path() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+
+    Some(dir.join("vigil").join("config.toml"))
+}
@@ -0,0 +1,274 @@
+use std::io::{stdout, Stdout, Write};
+
+use crossterm::{
+    cursor,
+    event::{self, read},
+    style::{self, Stylize},
+    terminal, ExecutableCommand, QueueableCommand,
+};
+use similar::{Algorithm, DiffTag};
+
+use crate::{buffer::Buffer, editor::expand_tabs};
+
+const NUMBER_WIDTH: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RowKind {
+    Equal,
+    Added,
+    Removed,
+    Changed,
+}
+
+/// One screen row of the diff: the line shown in each pane, `None` being a
+/// blank placeholder that keeps both sides aligned.
+struct DiffRow {
+    left: Option<usize>,
+    right: Option<usize>,
+    kind: RowKind,
+}
+
+/// Shows two files side by side with their differences highlighted, like `vimdiff`.
+pub struct DiffView {
+    left: Buffer,
+    right: Buffer,
+    rows: Vec<DiffRow>,
+    stdout: Stdout,
+    size: (u16, u16),
+    top: usize,
+    cursor: usize,
+    tabstop: usize,
+    waiting_command: Option<char>,
+}
+
+impl DiffView {
+    pub fn new(left: Buffer, right: Buffer, tabstop: usize) -> anyhow::Result<Self> {
+        let rows = diff_rows(&left, &right);
+
+        let mut stdout = stdout();
+        terminal::enable_raw_mode()?;
+        stdout
+            .execute(terminal::EnterAlternateScreen)?
+            .execute(terminal::Clear(terminal::ClearType::All))?;
+        let size = terminal::size()?;
+
+        Ok(DiffView {
+            left,
+            right,
+            rows,
+            stdout,
+            size,
+            top: 0,
+            cursor: 0,
+            tabstop,
+            waiting_command: None,
+        })
+    }
+
+    fn vheight(&self) -> usize {
+        self.size.1.saturating_sub(1) as usize
+    }
+
+    pub fn run(&mut self) -> anyhow::Result<()> {
+        loop {
+            self.scroll_into_view();
+            self.draw()?;
+
+            match read()? {
+                event::Event::Key(event) if self.handle_key(event) => break,
+                event::Event::Resize(width, height) => self.size = (width, height),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns `true` when the view should close.
+    fn handle_key(&mut self, event: event::KeyEvent) -> bool {
+        let ctrl = event.modifiers.contains(event::KeyModifiers::CONTROL);
+        let last = self.rows.len().saturating_sub(1);
+
+        if let Some(cmd) = self.waiting_command.take() {
+            if event.code == event::KeyCode::Char('c') {
+                self.jump_to_hunk(cmd == ']');
+            }
+            return false;
+        }
+
+        match event.code {
+            event::KeyCode::Char('q') | event::KeyCode::Esc => return true,
+            event::KeyCode::Char('d') if ctrl => {
+                self.cursor = (self.cursor + self.vheight() / 2).min(last);
+            }
+            event::KeyCode::Char('u') if ctrl => {
+                self.cursor = self.cursor.saturating_sub(self.vheight() / 2);
+            }
+            event::KeyCode::Char('j') | event::KeyCode::Down => {
+                self.cursor = (self.cursor + 1).min(last);
+            }
+            event::KeyCode::Char('k') | event::KeyCode::Up => {
+                self.cursor = self.cursor.saturating_sub(1);
+            }
+            event::KeyCode::Char('g') => self.cursor = 0,
+            event::KeyCode::Char('G') => self.cursor = last,
+            event::KeyCode::Char(c @ (']' | '[')) => self.waiting_command = Some(c),
+            _ => {}
+        }
+
+        false
+    }
+
+    /// Moves the cursor to the start of the next (or previous) run of changed rows.
+    fn jump_to_hunk(&mut self, forward: bool) {
+        let starts = self.hunk_starts();
+        let target = if forward {
+            starts.into_iter().find(|&i| i > self.cursor)
+        } else {
+            starts.into_iter().rev().find(|&i| i < self.cursor)
+        };
+        if let Some(target) = target {
+            self.cursor = target;
+        }
+    }
+
+    /// Indices of the rows where a run of changed rows begins.
+    fn hunk_starts(&self) -> Vec<usize> {
+        (0..self.rows.len())
+            .filter(|&i| {
+                self.rows[i].kind != RowKind::Equal
+                    && (i == 0 || self.rows[i - 1].kind == RowKind::Equal)
+            })
+            .collect()
+    }
+
+    fn scroll_into_view(&mut self) {
+        let height = self.vheight().max(1);
+        if self.cursor < self.top {
+            self.top = self.cursor;
+        } else if self.cursor >= self.top + height {
+            self.top = self.cursor + 1 - height;
+        }
+    }
+
+    fn draw(&mut self) -> anyhow::Result<()> {
+        let pane_width = (self.size.0 as usize).saturating_sub(1) / 2;
+
+        for i in 0..self.vheight() {
+            self.stdout.queue(cursor::MoveTo(0, i as u16))?;
+            match self.rows.get(self.top + i) {
+                Some(row) => {
+                    let (left, right, kind) = (row.left, row.right, row.kind);
+                    self.draw_pane(&self.left, left, kind, pane_width)?;
+                    self.stdout.queue(style::Print("│"))?;
+                    self.draw_pane(&self.right, right, kind, pane_width)?;
+                }
+                None => {
+                    self.stdout
+                        .queue(terminal::Clear(terminal::ClearType::CurrentLine))?;
+                }
+            }
+        }
+
+        self.draw_statusline()?;
+
+        let cursor_y = (self.cursor - self.top) as u16;
+        self.stdout
+            .queue(cursor::MoveTo(NUMBER_WIDTH as u16 + 1, cursor_y))?;
+        self.stdout.flush()?;
+
+        Ok(())
+    }
+
+    fn draw_pane(
+        &self,
+        buffer: &Buffer,
+        line: Option<usize>,
+        kind: RowKind,
+        width: usize,
+    ) -> anyhow::Result<()> {
+        let mut stdout = &self.stdout;
+        let text = match line.and_then(|y| buffer.get(y).map(|text| (y, text))) {
+            Some((y, text)) => {
+                let text = expand_tabs(&text, self.tabstop);
+                format!("{:>NUMBER_WIDTH$} {text}", y + 1)
+            }
+            None => String::new(),
+        };
+        let text: String = text.chars().take(width).collect();
+        let text = format!("{text:<width$}");
+
+        let color = match kind {
+            RowKind::Equal => None,
+            RowKind::Added => Some(style::Color::Green),
+            RowKind::Removed => Some(style::Color::Red),
+            RowKind::Changed => Some(style::Color::Yellow),
+        };
+        match color {
+            Some(color) if line.is_some() => {
+                stdout.queue(style::PrintStyledContent(text.with(color)))?
+            }
+            _ => stdout.queue(style::Print(text))?,
+        };
+
+        Ok(())
+    }
+
+    fn draw_statusline(&mut self) -> anyhow::Result<()> {
+        let hunks = self.hunk_starts().len();
+        let name = |buffer: &Buffer| buffer.file.clone().unwrap_or_default();
+        let status = format!(
+            " DIFF  {} │ {}  ({hunks} hunks)",
+            name(&self.left),
+            name(&self.right)
+        );
+        let width = self.size.0 as usize;
+        let status: String = status.chars().take(width).collect();
+
+        self.stdout
+            .queue(cursor::MoveTo(0, self.size.1.saturating_sub(1)))?
+            .queue(style::PrintStyledContent(
+                format!("{status:<width$}").reverse(),
+            ))?;
+
+        Ok(())
+    }
+
+    pub fn cleanup(&mut self) -> anyhow::Result<()> {
+        self.stdout.execute(terminal::LeaveAlternateScreen)?;
+        terminal::disable_raw_mode()?;
+
+        Ok(())
+    }
+}
+
+/// Aligns the lines of both buffers using a Myers diff. Within a replaced block,
+/// lines are paired up as changed and the leftover lines count as removed or added.
+fn diff_rows(left: &Buffer, right: &Buffer) -> Vec<DiffRow> {
+    let (left, right): (Vec<_>, Vec<_>) = (left.lines().collect(), right.lines().collect());
+    let ops = similar::capture_diff_slices(Algorithm::Myers, &left, &right);
+
+    let mut rows = vec![];
+    for op in ops {
+        let (tag, old, new) = op.as_tag_tuple();
+        let kind = match tag {
+            DiffTag::Equal => RowKind::Equal,
+            DiffTag::Delete => RowKind::Removed,
+            DiffTag::Insert => RowKind::Added,
+            DiffTag::Replace => RowKind::Changed,
+        };
+
+        for i in 0..old.len().max(new.len()) {
+            let left = (i < old.len()).then(|| old.start + i);
+            let right = (i < new.len()).then(|| new.start + i);
+            let kind = match (kind, left, right) {
+                (RowKind::Changed, None, _) => RowKind::Added,
+                (RowKind::Changed, _, None) => RowKind::Removed,
+                _ => kind,
+            };
+            rows.push(DiffRow { left, right, kind });
+        }
+    }
+
+    rows
+}
@@ -0,0 +1,55 @@
+/// One change to the buffer. Each edit keeps the text it removes, so it can be
+/// reversed with [`Edit::inverse`]. Positions are `(x, y)` in chars.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Edit {
+    InsertChar {
+        x: usize,
+        y: usize,
+        c: char,
+    },
+    RemoveChar {
+        x: usize,
+        y: usize,
+        c: char,
+    },
+    InsertLine {
+        y: usize,
+        line: String,
+    },
+    RemoveLine {
+        y: usize,
+        line: String,
+    },
+    /// `old` at `start` replaced by `new`. Both may span several lines.
+    Replace {
+        start: (usize, usize),
+        old: String,
+        new: String,
+    },
+}
+
+impl Edit {
+    /// The edit that undoes this one.
+    pub fn inverse(&self) -> Edit {
+        match self.clone() {
+            Edit::InsertChar { x, y, c } => Edit::RemoveChar { x, y, c },
+            Edit::RemoveChar { x, y, c } => Edit::InsertChar { x, y, c },
+            Edit::InsertLine { y, line } => Edit::RemoveLine { y, line },
+            Edit::RemoveLine { y, line } => Edit::InsertLine { y, line },
+            Edit::Replace { start, old, new } => Edit::Replace {
+                start,
+                old: new,
+                new: old,
+            },
+        }
+    }
+}
+
+/// The position just after `text` when it is placed at `start`.
+pub fn end_of(start: (usize, usize), text: &str) -> (usize, usize) {
+    let (x, y) = start;
+    match text.rsplit_once('\n') {
+        Some((before, last)) => (last.chars().count(), y + before.matches('\n').count() + 1),
+        None => (x + text.chars().count(), y),
+    }
+}
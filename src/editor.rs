@@ -5,106 +5,832 @@ use crossterm::{
     style::{self, Stylize},
     terminal, ExecutableCommand, QueueableCommand,
 };
-use std::io::{stdout, Write};
+use std::{
+    borrow::Cow,
+    io::{stdout, BufWriter, Stdout, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{Receiver, TryRecvError},
+    time::{Duration, Instant},
+};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::{
+    buffer::{is_word_char, Buffer},
+    complete,
+    config::Config,
+    filetype::FileType,
+    finder, format,
+    gutter::{GitGutter, Sign, Signs},
+    history::History,
+    keymap::{KeyBinding, KeyResult, Keymap},
+    log,
+    lsp::{self, LspClient},
+    palette::Palette,
+    quickfix::{self, Quickfix},
+    statusline::{self, SectionColors, Segment, StatusInfo, StatusLine},
+    swap,
+    trie::Trie,
+    undo::UndoHistory,
+};
 
-use crate::buffer::Buffer;
+const POPUP_WIDTH: usize = 60;
+const MENU_HEIGHT: usize = 10;
+/// Background of the cursor line, with `cursorline`.
+const CURSOR_LINE_BG: style::Color = style::Color::Rgb {
+    r: 50,
+    g: 50,
+    b: 70,
+};
+/// Background of the `colorcolumn`.
+const COLOR_COLUMN_BG: style::Color = style::Color::Rgb {
+    r: 70,
+    g: 40,
+    b: 40,
+};
+/// How long a message stays in the message line, unless a key clears it first.
+const MESSAGE_DURATION: Duration = Duration::from_secs(4);
+/// How often unsaved changes are written to the swap file, at most.
+const SWAP_INTERVAL: Duration = Duration::from_secs(4);
+/// How long a formatter may run on save before the write is given up.
+const FORMAT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a partly typed key sequence waits for its next key.
+const KEY_TIMEOUT: Duration = Duration::from_millis(1000);
+/// Room for a whole frame, so that drawing one takes a single write.
+const OUT_CAPACITY: usize = 1 << 16;
+const KEY_ROOT: usize = Trie::<KeyBinding, Action>::ROOT;
+/// Chars that make a search pattern a regular expression under `magic`.
+#[cfg(feature = "regex-search")]
+const REGEX_CHARS: [char; 14] = [
+    '.', '*', '+', '?', '(', ')', '[', ']', '{', '}', '|', '^', '$', '\\',
+];
+/// How long the main loop waits for input before it goes round anyway. Long
+/// enough that an idle editor costs next to nothing.
+const IDLE_POLL: Duration = Duration::from_millis(500);
+/// How often the file finder list is updated while the walk is still going.
+const FINDER_POLL: Duration = Duration::from_millis(50);
 
-enum Action {
+#[derive(Clone)]
+pub(crate) enum Action {
     Quit,
     Save,
+    ReloadFile,
+    /// Replace the buffer with the text of its swap file.
+    RecoverSwap,
+    /// Ctrl-^: switch to the alternate file.
+    AlternateFile,
+    /// `gf`: open the file whose name is under the cursor, a relative one
+    /// being looked for next to the current file.
+    GoToFile,
+    /// Ctrl-g: show the file name and where the cursor is in the file.
+    ShowFileInfo,
+    Undo,
+    Redo,
 
     MoveUp,
     MoveDown,
     MoveLeft,
     MoveRight,
+    /// Backspace: left, from the start of a line on to the end of the one above.
+    MoveBackward,
+    /// Space: right, from the end of a line on to the start of the one below.
+    MoveForward,
     MoveToLineEnd,
     MoveToLineStart,
+    /// Home: to the first non-blank char, or to column 0 if already there.
+    SmartHome,
+    Move(Motion),
+    /// `%`: jump to the matching bracket, or with a count to that percentage of the file.
+    Percent,
+    /// `gg`: go to the first line, or with a count to that line.
+    GoToTop,
+    /// `G`: go to the last line, or with a count to that line.
+    GoToBottom,
     PageUp,
     PageDown,
+    HalfPageUp,
+    HalfPageDown,
+    /// Ctrl-e: scroll the view down a line, the cursor staying on its line
+    /// unless that goes out of view.
+    ScrollDown,
+    /// Ctrl-y: scroll the view up a line.
+    ScrollUp,
+    /// `zo`: open the closed fold under the cursor.
+    OpenFold,
+    /// `zc`: close the innermost open fold around the cursor.
+    CloseFold,
+    /// `zR`
+    OpenAllFolds,
+    /// `zM`
+    CloseAllFolds,
 
     InsertCharAtCursorPos(char),
+    /// A char typed after Ctrl-v in insert mode, inserted as it is: a tab
+    /// stays a tab even with `expandtab`.
+    InsertLiteral(char),
+    InsertTab,
+    Outdent,
     DeleteCharAtCursorPos,
+    /// Ctrl-w in insert mode: delete the word before the cursor, and the
+    /// blanks between them, into the register.
+    DeleteWordBackward,
+    /// Ctrl-Delete or Alt-d in insert mode: delete the word after the cursor.
+    DeleteWordForward,
+    /// Ctrl-u in insert mode: delete from the cursor back to the start of the
+    /// line, into the register.
+    DeleteToLineStart,
+    /// Ctrl-k in insert mode: delete from the cursor to the end of the line,
+    /// into the register, or join the next line when nothing is left to delete.
+    DeleteRestOfLine,
     DeleteCurrentLine,
-    SetWaitingCad(char),
+    DeleteMotion(Motion),
+    /// `s`: delete the char under the cursor (or `count` chars) and enter insert mode.
+    SubstituteChar,
+    /// `S`: clear the line after its indentation and enter insert mode.
+    SubstituteLine,
+    /// `a`: enter insert mode after the char under the cursor.
+    Append,
+    /// `o` or `O`: open a line below or above the cursor and insert there.
+    OpenLine {
+        above: bool,
+    },
+    /// `c` over a text object: delete it and enter insert mode.
+    Change(Motion),
+    /// `D`: delete from the cursor to the end of the line.
+    DeleteToLineEnd,
+    /// `C`: like `D`, then enter insert mode.
+    ChangeToLineEnd,
+    /// `gU`, `gu` or `g~` over a motion, or over the current line when `None`.
+    ChangeCase(CaseOp, Option<Motion>),
+    /// `gc` over the lines of a motion, or `gcc` on the current line.
+    ToggleComment(Option<Motion>),
+    /// `y` over a motion, or `yy` on the current line when `None`.
+    Yank(Option<Motion>),
+    PasteAfterCursor,
+    DeleteBlock,
+    /// `y` on a visual block: yank it as a block.
+    YankBlock,
+    /// `>` on a visual block: indent its lines, by as many levels as the count.
+    /// Whole lines are shifted whatever the columns of the block, which stands
+    /// in for the linewise visual mode there is not.
+    IndentBlock,
+    /// `<` on a visual block: outdent its lines.
+    OutdentBlock,
+    /// `gv`: select the last visual block again.
+    ReselectBlock,
+    /// `o` on a visual block: move the cursor to the anchor and the anchor to
+    /// where the cursor was, so motions move the other corner.
+    SwapBlockCorners,
+    /// `I` or `A` on a visual block: insert before or after it on every line.
+    BlockInsert {
+        append: bool,
+    },
     NewLine,
 
     EnterMode(Mode),
+    ExecuteCommand(String),
+    /// Ctrl-p: list the named actions and ex commands to pick one to run.
+    OpenPalette,
+    /// `<leader>f`: fuzzy find a file under the current directory to open.
+    FindFile,
+    Hover,
+    PromptRename,
+    RenameSymbol(String),
+
+    TriggerCompletion,
+    /// Ctrl-n / Ctrl-p: complete the word before the cursor from the buffer,
+    /// starting at the nearest candidate or at the farthest one.
+    CompleteWord {
+        backward: bool,
+    },
+    /// Ctrl-x Ctrl-f: complete the file name before the cursor.
+    CompleteFileName,
+    CompletionNext,
+    CompletionPrev,
+    AcceptCompletion,
+    CancelCompletion,
+}
+
+/// A cursor movement that can also serve as the target of an operator (`d}`).
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Motion {
+    Up,
+    Down,
+    Left,
+    Right,
+    LineStart,
+    FirstNonBlank,
+    LineEnd,
+    NextParagraph,
+    PrevParagraph,
+    /// `iw`: the word, run of punctuation or run of blanks under the cursor.
+    InnerWord,
+    /// `aw`: like `iw`, with the blanks around it.
+    AWord,
+}
+
+impl Action {
+    /// Whether the action edits the buffer (or starts insert mode to do so),
+    /// and so begins a new undo step.
+    fn changes_buffer(&self) -> bool {
+        matches!(
+            self,
+            Action::InsertCharAtCursorPos(_)
+                | Action::InsertLiteral(_)
+                | Action::InsertTab
+                | Action::Outdent
+                | Action::DeleteCharAtCursorPos
+                | Action::DeleteWordBackward
+                | Action::DeleteWordForward
+                | Action::DeleteToLineStart
+                | Action::DeleteRestOfLine
+                | Action::DeleteCurrentLine
+                | Action::DeleteMotion(_)
+                | Action::Change(_)
+                | Action::DeleteToLineEnd
+                | Action::ChangeToLineEnd
+                | Action::SubstituteChar
+                | Action::SubstituteLine
+                | Action::OpenLine { .. }
+                | Action::ChangeCase(..)
+                | Action::ToggleComment(_)
+                | Action::PasteAfterCursor
+                | Action::DeleteBlock
+                | Action::IndentBlock
+                | Action::OutdentBlock
+                | Action::BlockInsert { .. }
+                | Action::NewLine
+                | Action::EnterMode(Mode::Insert)
+                | Action::Append
+                | Action::RenameSymbol(_)
+                | Action::RecoverSwap
+        )
+    }
+}
+
+impl Motion {
+    /// Linewise motions make an operator act on whole lines.
+    fn is_linewise(self) -> bool {
+        matches!(
+            self,
+            Motion::Up | Motion::Down | Motion::NextParagraph | Motion::PrevParagraph
+        )
+    }
+}
+
+/// The case conversion done by the `gU`, `gu` and `g~` operators.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum CaseOp {
+    Upper,
+    Lower,
+    Toggle,
 }
 
-#[derive(Debug)]
-enum Mode {
+impl CaseOp {
+    fn transform(self) -> fn(&str) -> String {
+        match self {
+            CaseOp::Upper => str::to_uppercase,
+            CaseOp::Lower => str::to_lowercase,
+            CaseOp::Toggle => toggle_case,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Mode {
     Normal,
     Insert,
+    Command,
+    Search,
+    VisualBlock,
+}
+
+/// The editor after one [`Editor::step`].
+#[derive(Debug, PartialEq)]
+pub(crate) struct StepState {
+    /// Cursor position in the buffer as `(x, y)`.
+    pub cursor: (usize, usize),
+    pub mode: Mode,
+    /// Whether the event quit the editor.
+    pub quit: bool,
+}
+
+/// A viewport row split into what gets styled differently.
+#[derive(PartialEq)]
+struct RenderedRow {
+    /// The gutter column, when there is one, and the sign it shows on this line.
+    gutter: Option<Option<Sign>>,
+    /// One grapheme per screen column, exactly as wide as the text area.
+    cells: Vec<String>,
+    /// Columns `start..end` covered by the visual block.
+    selection: (usize, usize),
+    /// Whether the row is highlighted as the cursor line.
+    cursor_line: bool,
+    /// The column highlighted by `colorcolumn`, from 0.
+    color_column: Option<usize>,
+}
+
+impl RenderedRow {
+    /// The background of the cells outside the selection and the color column.
+    fn background(&self) -> style::Color {
+        match self.cursor_line {
+            true => CURSOR_LINE_BG,
+            false => style::Color::Reset,
+        }
+    }
+
+    /// The cells in runs styled alike, each with its background, or `None`
+    /// for the selection, which shows reversed. The selection shows over the
+    /// color column, and both over the cursor line.
+    fn runs(&self) -> Vec<(String, Option<style::Color>)> {
+        let (start, end) = self.selection;
+        let style_of = |x: usize| match x {
+            _ if (start..end).contains(&x) => None,
+            _ if self.color_column == Some(x) => Some(COLOR_COLUMN_BG),
+            _ => Some(self.background()),
+        };
+        let mut runs = vec![];
+        let mut x = 0;
+        while x < self.cells.len() {
+            let style = style_of(x);
+            let run = (x..self.cells.len())
+                .take_while(|&x| style_of(x) == style)
+                .count();
+            runs.push((self.cells[x..x + run].concat(), style));
+            x += run;
+        }
+        runs
+    }
+
+    #[cfg(test)]
+    fn text(&self) -> String {
+        let gutter = self.gutter.map(|sign| sign.map_or(' ', Sign::symbol));
+        let mut text: String = gutter.into_iter().collect();
+        text.push_str(&self.cells.concat());
+        text
+    }
+}
+
+struct Completion {
+    items: Vec<lsp::CompletionItem>,
+    selected: usize,
+    /// Column where the word being completed starts.
+    start: u16,
+    source: CompletionSource,
 }
 
-pub struct Editor {
+#[derive(PartialEq)]
+enum CompletionSource {
+    Lsp,
+    /// Words from the buffer. The selected word replaces the one typed as the
+    /// selection moves, so the list is not filtered by the text before the cursor.
+    Words,
+    /// File names, completing the path before the cursor.
+    Paths,
+}
+
+/// Runs on the buffer before it is written, and may change it; an error
+/// stops the write and is shown.
+type PreSaveHook = Box<dyn FnMut(&mut Buffer) -> anyhow::Result<()>>;
+/// Runs on the buffer once it is written.
+type PostSaveHook = Box<dyn FnMut(&Buffer)>;
+
+/// The editor, drawing to the terminal through `out`. Tests use a `Vec<u8>`
+/// instead, and feed keys with [`Editor::step`].
+pub struct Editor<W: Write = BufWriter<Stdout>> {
     buffer: Buffer,
-    stdout: std::io::Stdout,
+    out: W,
     size: (u16, u16),
     mode: Mode,
     vtop: u16,
     vleft: u16,
     cx: u16,
     cy: u16,
-    waiting_command: Option<char>,
+    /// Node of the keymap trie reached by the keys typed so far in a sequence
+    /// like `gU`, the root when none is pending.
+    key_node: usize,
+    /// When the last key of a pending sequence was typed.
+    key_time: Instant,
+    lsp: Option<LspClient>,
+    popup: Option<String>,
+    /// The command palette, when it is open. It takes all keys until closed.
+    palette: Option<Palette>,
+    /// The files the finder walk has yet to send to the palette.
+    finder: Option<Receiver<Vec<String>>>,
+    /// Where the finder and `:vimgrep` look for files: the working directory,
+    /// unless set to another.
+    root: PathBuf,
+    completion: Option<Completion>,
+    /// Set with [`Editor::set_message`], and shown until a key is pressed or
+    /// `MESSAGE_DURATION` has passed.
+    message: Option<String>,
+    message_time: Instant,
+    keymap: Keymap,
+    statusline: StatusLine,
+    command: String,
+    command_history: History,
+    search_history: History,
+    /// `(vtop, cx, cy)` when the search prompt was opened, restored on cancel.
+    search_origin: (u16, u16, u16),
+    /// Whether the search prompt was opened from visual block mode, which the
+    /// search then extends the block in and goes back to.
+    visual_search: bool,
+    /// `:set magic`: search patterns with regex special chars are regular
+    /// expressions. Without the `regex-search` feature there is no such option.
+    #[cfg(feature = "regex-search")]
+    magic: bool,
+    /// The viewport rows as they were last drawn, so that those that did not
+    /// change are not drawn again. Emptied when something covers them.
+    drawn: Vec<RenderedRow>,
+    path_completion: Option<PathCompletion>,
+    /// The unnamed register.
+    register: Register,
+    config: Config,
+    /// Action to run if the user answers `y` to the question in the message line.
+    confirm: Option<Action>,
+    /// The corner of the visual block opposite the cursor, as `(x, y)` in the buffer.
+    visual_anchor: (usize, usize),
+    /// The anchor and the cursor of the last visual block, for `gv`.
+    last_block: Option<((usize, usize), (usize, usize))>,
+    /// Pending block insert, repeated on the other lines when insert mode ends.
+    block_insert: Option<BlockInsert>,
+    /// Pending repeat of an insert started with a count.
+    insert_repeat: Option<InsertRepeat>,
+    /// The matches of the last `:vimgrep`.
+    quickfix: Quickfix,
+    /// The file as committed, when it is tracked by git, to mark the changes from.
+    gutter: Option<GitGutter>,
+    /// Signs shown in a column left of the text, which is there when the file
+    /// is tracked or any signs are placed.
+    signs: Signs,
+    /// The buffer's count of changes when the git signs were last worked out.
+    git_signs_at: Option<usize>,
+    /// The buffer's count of changes and when it was first seen, for
+    /// `autosave_interval`.
+    last_change: (usize, Instant),
+    /// The buffer's count of changes when the swap file was last written, and
+    /// when that was.
+    swap_written: (usize, Instant),
+    /// Registered with [`Editor::on_pre_save`], run in that order.
+    pre_save: Vec<PreSaveHook>,
+    /// Registered with [`Editor::on_post_save`], run in that order.
+    post_save: Vec<PostSaveHook>,
+    /// Whether Ctrl-r was pressed on the command line and a register name is expected.
+    register_pending: bool,
+    /// Whether Ctrl-x was pressed in insert mode and a completion key is expected.
+    ctrl_x_pending: bool,
+    /// What Ctrl-v in insert mode has read so far of the char to insert.
+    literal: Option<Literal>,
+    /// Count typed before a normal-mode command, like the `3` in `3s`.
+    count: Option<usize>,
+    /// Column to return to when moving vertically, kept while passing through
+    /// lines too short for it. Reset by any other action.
+    preferred_cx: Option<u16>,
+    /// The previously edited file and the cursor position in it, for Ctrl-^.
+    alternate: Option<(String, usize, usize)>,
+    /// The last `:s`, repeated by `&`, `g&` and a bare `:s`.
+    last_substitute: Option<Substitute>,
+    /// The folds by indentation, sorted by start. They are worked out again
+    /// by each fold command, and after changes while some are closed.
+    folds: Vec<Fold>,
+    undo: UndoHistory,
+}
+
+/// Lines `start_line..=end_line`, shown as just the first one while closed.
+struct Fold {
+    start_line: u16,
+    end_line: u16,
+    open: bool,
+}
+
+impl Fold {
+    fn contains(&self, y: usize) -> bool {
+        (self.start_line as usize..=self.end_line as usize).contains(&y)
+    }
+}
+
+/// Text typed on the first line of a block insert at `col` is copied to the
+/// lines below it, through `bottom`, when leaving insert mode.
+struct BlockInsert {
+    top: usize,
+    bottom: usize,
+    col: usize,
+    /// `A` pads lines shorter than the block; `I` skips them.
+    pad: bool,
+}
+
+/// A char being typed after Ctrl-v in insert mode.
+enum Literal {
+    /// Waiting for the key to insert, or for the start of a code.
+    Key,
+    /// Up to three decimal digits of a code up to 255.
+    Decimal(String),
+    /// The hex digits of a code point, at most `max` of them: 4 after `u`,
+    /// 8 after `U`.
+    Hex { digits: String, max: usize },
+}
+
+/// An insert started with a count, whose text is typed `count - 1` more times
+/// when leaving insert mode. Like a block insert, only text typed on the line
+/// where it started, from `col` on, is repeated.
+struct InsertRepeat {
+    count: usize,
+    col: usize,
+    y: usize,
+    /// Each copy goes on a line of its own, as after `o`.
+    lines: bool,
+}
+
+/// Text deleted or yanked, to be put back with `p`.
+#[derive(Default)]
+struct Register {
+    /// Linewise text ends with a newline; the rows of a block are joined by
+    /// newlines.
+    text: String,
+    kind: RegisterKind,
+}
+
+/// How the text of a register was taken, which decides how `p` puts it back.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+enum RegisterKind {
+    /// Part of a line, or a run of text across lines: spliced in after the cursor.
+    #[default]
+    Charwise,
+    /// Whole lines, from `dd` or a linewise motion: put on lines of their own.
+    Linewise,
+    /// A visual block: put as a rectangle, one row on each line from the cursor down.
+    Blockwise,
+}
+
+/// A `:s/pattern/replacement/flags` command. Only the `g` flag does anything.
+#[derive(Clone)]
+struct Substitute {
+    pattern: String,
+    replacement: String,
+    flags: String,
+}
+
+/// Tab-completion state for a path argument on the command line.
+struct PathCompletion {
+    /// The command line up to the path being completed.
+    base: String,
+    candidates: Vec<String>,
+    /// Candidate shown on the command line, `None` before cycling starts.
+    index: Option<usize>,
+}
+
+/// Where the cursor starts, from a `+N`, `+` or `+/pattern` argument.
+#[derive(Debug, PartialEq)]
+pub enum StartPosition {
+    /// 1-based, clamped to the buffer.
+    Line(usize),
+    LastLine,
+    Search(String),
 }
 
 impl Editor {
-    pub fn new(buffer: Buffer) -> anyhow::Result<Self> {
-        let mut stdout = stdout();
+    pub fn new(buffer: Buffer, config: Config) -> anyhow::Result<Self> {
+        let mut stdout = BufWriter::with_capacity(OUT_CAPACITY, stdout());
 
         terminal::enable_raw_mode().unwrap();
         stdout
             .execute(terminal::EnterAlternateScreen)?
+            .execute(event::EnableFocusChange)?
             .execute(terminal::Clear(terminal::ClearType::All))?;
 
         let size = terminal::size()?;
 
-        Ok(Editor {
+        Ok(Editor::with_output(buffer, config, size, stdout))
+    }
+
+    pub fn run(&mut self) -> anyhow::Result<()> {
+        loop {
+            self.receive_files();
+            self.autosave(false);
+            self.write_swap(false);
+            self.check_bounds();
+            self.draw()?;
+
+            // wake up now and then even without input, for what goes on by itself
+            let finder_poll = self.finder.as_ref().map(|_| FINDER_POLL);
+            let timeout = (self.key_timeout().into_iter())
+                .chain(finder_poll)
+                .fold(IDLE_POLL, Duration::min);
+            let quit = if event::poll(timeout)? {
+                self.step(read()?)?.quit
+            } else if self.key_timeout() == Some(Duration::ZERO) {
+                // a pending sequence stands on its own once the user pauses
+                let action = self.keymap.normal_value(self.key_node);
+                self.key_node = KEY_ROOT;
+                self.apply(action)?
+            } else {
+                self.update_git_signs();
+                false
+            };
+            if quit {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn cleanup(&mut self) -> anyhow::Result<()> {
+        self.out.execute(event::DisableFocusChange)?;
+        self.out.execute(terminal::LeaveAlternateScreen)?;
+        terminal::disable_raw_mode()?;
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Editor<W> {
+    /// Builds the editor for a screen of `size`, drawing to `out`, without
+    /// touching the terminal.
+    fn with_output(mut buffer: Buffer, config: Config, size: (u16, u16), out: W) -> Self {
+        buffer.tabstop = config.tabstop;
+        let lsp = buffer
+            .file
+            .as_deref()
+            .and_then(|file| start_lsp(file, buffer.filetype));
+        let gutter = buffer.file.as_deref().and_then(GitGutter::load);
+
+        let mut editor = Editor {
             buffer,
-            stdout,
+            out,
             size,
             mode: Mode::Normal,
             vtop: 0,
             vleft: 0,
             cx: 0,
             cy: 0,
-            waiting_command: None,
+            key_node: KEY_ROOT,
+            key_time: Instant::now(),
+            lsp,
+            popup: None,
+            palette: None,
+            finder: None,
+            root: PathBuf::from("."),
+            completion: None,
+            message: None,
+            message_time: Instant::now(),
+            keymap: Keymap::new(&config.keys),
+            undo: UndoHistory::new(config.undolevels),
+            statusline: StatusLine::new(&config.statusline),
+            command: String::new(),
+            command_history: History::default(),
+            search_history: History::default(),
+            search_origin: (0, 0, 0),
+            visual_search: false,
+            #[cfg(feature = "regex-search")]
+            magic: true,
+            drawn: vec![],
+            path_completion: None,
+            register: Register::default(),
+            config,
+            confirm: None,
+            visual_anchor: (0, 0),
+            last_block: None,
+            block_insert: None,
+            insert_repeat: None,
+            quickfix: Quickfix::default(),
+            gutter,
+            signs: Signs::default(),
+            git_signs_at: None,
+            last_change: (0, Instant::now()),
+            swap_written: (0, Instant::now()),
+            pre_save: vec![],
+            post_save: vec![],
+            register_pending: false,
+            ctrl_x_pending: false,
+            literal: None,
+            count: None,
+            preferred_cx: None,
+            alternate: None,
+            last_substitute: None,
+            folds: vec![],
+        };
+        editor.update_git_signs();
+        editor.check_swap();
+        if editor.config.format_on_save {
+            let formatprg = editor.config.formatprg.clone();
+            editor.on_pre_save(move |buffer| {
+                let Some(command) = formatprg.get(buffer.filetype.name()) else {
+                    return Ok(());
+                };
+                let formatted = format::run(command, &buffer.content(), FORMAT_TIMEOUT)?;
+                let text = formatted.strip_suffix('\n').unwrap_or(&formatted);
+                // the cursor keeps its line number as far as the buffer still
+                // reaches
+                if text != buffer.text() {
+                    buffer.replace((0, 0), (usize::MAX, usize::MAX), text);
+                }
+                Ok(())
+            });
+        }
+        editor
+    }
+
+    /// Adds `hook` to run before each write of the buffer, for what has to
+    /// change it first, like a formatter. An error it returns stops the write.
+    pub fn on_pre_save(&mut self, hook: impl FnMut(&mut Buffer) -> anyhow::Result<()> + 'static) {
+        self.pre_save.push(Box::new(hook));
+    }
+
+    /// Adds `hook` to run after each write of the buffer.
+    #[allow(dead_code)]
+    pub fn on_post_save(&mut self, hook: impl FnMut(&Buffer) + 'static) {
+        self.post_save.push(Box::new(hook));
+    }
+
+    /// Handles one input event and returns the resulting state. This is the
+    /// main loop minus drawing and the key timeout, so tests can drive the
+    /// editor without a terminal.
+    pub(crate) fn step(&mut self, ev: event::Event) -> anyhow::Result<StepState> {
+        let mut quit = false;
+        if let Some(action) = self.end_pending_keys(&ev) {
+            quit = self.apply(Some(action))?;
+        }
+        if !quit {
+            let action = self.handle_event(ev)?;
+            quit = self.apply(action)?;
+        }
+        self.check_bounds();
+
+        Ok(StepState {
+            cursor: (self.cx as usize, self.buffer_line() as usize),
+            mode: self.mode.clone(),
+            quit,
         })
     }
 
+    /// Executes the action bound to the keys typed. Returns `true` when the
+    /// editor should quit.
+    fn apply(&mut self, action: Option<Action>) -> anyhow::Result<bool> {
+        let Some(action) = action else {
+            return Ok(false);
+        };
+        // closed folds have to follow the lines they hide as those change
+        let refold = self.folds.iter().any(|fold| !fold.open)
+            && (action.changes_buffer()
+                || matches!(
+                    action,
+                    Action::Undo | Action::Redo | Action::ExecuteCommand(_)
+                ));
+        let (y, len) = (self.buffer_line() as usize, self.buffer.len());
+        // `gv` brings back the block as it was when visual block mode ended
+        let block = matches!(self.mode, Mode::VisualBlock)
+            .then(|| (self.visual_anchor, (self.cx as usize, y)));
+        if self.execute(action)? {
+            return Ok(true);
+        }
+        if block.is_some() && !matches!(self.mode, Mode::VisualBlock) {
+            self.last_block = block;
+        }
+        if refold {
+            self.update_folds(y, len);
+        }
+        self.signs
+            .shift(y, self.buffer.len() as isize - len as isize);
+        // a count applies to the whole command, including its pending keys
+        if self.key_node == KEY_ROOT {
+            self.count = None;
+        }
+        Ok(false)
+    }
+
     fn vwidth(&self) -> u16 {
-        self.size.0
+        self.size.0.saturating_sub(self.gutter_width())
+    }
+
+    fn gutter_width(&self) -> u16 {
+        u16::from(self.gutter.is_some() || !self.signs.is_empty())
     }
 
     fn vheight(&self) -> u16 {
-        self.size.1 - 2
+        self.size.1.saturating_sub(2)
     }
 
     fn line_length(&self) -> u16 {
-        if let Some(line) = self.viewport_line(self.cy) {
-            let length = line.len() as u16;
-            return length;
-        }
-        0
+        self.buffer.chars_on_line(self.buffer_line() as usize) as u16
     }
 
     fn buffer_line(&self) -> u16 {
         self.vtop + self.cy
     }
 
-    fn viewport_line(&self, n: u16) -> Option<String> {
-        let buffer_line = self.vtop + n;
-        self.buffer.get(buffer_line as usize)
+    /// Screen column of the cursor, which differs from `cx` when tabs precede it.
+    fn screen_cx(&self) -> u16 {
+        let line = self.buffer.graphemes_on_line(self.buffer_line() as usize);
+        display_col(&line, self.cx as usize, self.config.tabstop) as u16 + self.gutter_width()
     }
 
     fn set_cursor_style(&mut self) -> anyhow::Result<()> {
-        self.stdout.queue(match self.waiting_command {
-            Some(_) => cursor::SetCursorStyle::SteadyUnderScore,
-            _ => match self.mode {
-                Mode::Normal => cursor::SetCursorStyle::DefaultUserShape,
-                Mode::Insert => cursor::SetCursorStyle::SteadyBar,
+        self.out.queue(match self.key_node {
+            KEY_ROOT => match self.mode {
+                Mode::Normal | Mode::VisualBlock => cursor::SetCursorStyle::DefaultUserShape,
+                Mode::Insert | Mode::Command | Mode::Search => cursor::SetCursorStyle::SteadyBar,
             },
+            _ => cursor::SetCursorStyle::SteadyUnderScore,
         })?;
 
         Ok(())
@@ -114,329 +840,4690 @@ impl Editor {
         self.set_cursor_style()?;
         self.draw_viewport()?;
         self.draw_statusline()?;
-        self.stdout.queue(cursor::MoveTo(self.cx, self.cy))?;
-        self.stdout.flush()?;
+        self.draw_message()?;
+        let (screen_cx, screen_cy) = (self.screen_cx(), self.screen_cy());
+        if let Some(content) = self.popup.clone() {
+            self.draw_popup(&content, screen_cx, screen_cy)?;
+        }
+        if let Some(completion) = &self.completion {
+            let labels: Vec<String> = self
+                .completion_matches()
+                .iter()
+                .map(|item| item.label.clone())
+                .collect();
+            let (selected, start) = (completion.selected, completion.start);
+            self.draw_menu(&labels, selected, start + self.gutter_width(), screen_cy)?;
+        }
+        if let Some(palette) = &self.palette {
+            let selected = palette.selected;
+            let labels = palette.labels(selected + MENU_HEIGHT);
+            self.draw_menu(&labels, selected, 0, self.vheight())?;
+        }
+        // the rows under a popup or menu have to be drawn again once it is gone
+        if self.popup.is_some() || self.completion.is_some() || self.palette.is_some() {
+            self.drawn.clear();
+        }
+        match self.mode {
+            _ if self.palette.is_some() => {
+                let query = self.palette.as_ref().map_or(0, |p| p.query.chars().count());
+                self.out
+                    .queue(cursor::MoveTo(query as u16 + 2, self.size.1 - 1))?;
+            }
+            Mode::Command | Mode::Search => {
+                let col = self.command.chars().count() as u16 + 1;
+                self.out.queue(cursor::MoveTo(col, self.size.1 - 1))?;
+            }
+            _ => {
+                self.out.queue(cursor::MoveTo(screen_cx, screen_cy))?;
+            }
+        }
+        self.out.flush()?;
         Ok(())
     }
 
+    /// Draws the viewport rows that changed since the last draw.
     pub fn draw_viewport(&mut self) -> anyhow::Result<()> {
-        let vwidth = self.vwidth() as usize;
-        for i in 0..self.vheight() {
-            let line = match self.viewport_line(i) {
-                None => String::new(), // clear the line
-                Some(s) => s,
-            };
+        let lines = self.viewport_lines();
+        let rows: Vec<RenderedRow> = lines.into_iter().map(|y| self.render_row(y)).collect();
+        for (i, row) in rows.iter().enumerate() {
+            if self.drawn.get(i) == Some(row) {
+                continue;
+            }
+            let width = self.draw_gutter(row, i as u16)?;
+            self.draw_cells(row, i as u16, width)?;
+        }
+        self.drawn = rows;
+        Ok(())
+    }
+
+    /// Draws the gutter of `row` on screen row `y`, and returns how many
+    /// columns it takes.
+    fn draw_gutter(&mut self, row: &RenderedRow, y: u16) -> anyhow::Result<u16> {
+        let Some(sign) = row.gutter else {
+            return Ok(0);
+        };
+        let bg = row.background();
+        self.out.queue(cursor::MoveTo(0, y))?;
+        self.out.queue(match sign {
+            Some(sign) => style::PrintStyledContent(sign.symbol().with(sign.color()).on(bg)),
+            None => style::PrintStyledContent(' '.on(bg)),
+        })?;
+        Ok(1)
+    }
 
-            self.stdout
-                .queue(cursor::MoveTo(0, i))?
-                .queue(style::Print(format!("{line:<width$}", width = vwidth)))?;
+    /// Draws the text of `row` on screen row `y`, from column `x_offset`.
+    fn draw_cells(&mut self, row: &RenderedRow, y: u16, x_offset: u16) -> anyhow::Result<()> {
+        self.out.queue(cursor::MoveTo(x_offset, y))?;
+        for (text, style) in row.runs() {
+            self.out.queue(style::PrintStyledContent(match style {
+                Some(bg) => text.on(bg),
+                None => text.reverse(),
+            }))?;
         }
         Ok(())
     }
 
-    fn draw_statusline(&mut self) -> anyhow::Result<()> {
-        let mode = format!(" {:?} ", self.mode).to_uppercase();
-        let file = format!(" {}", self.buffer.file.as_deref().unwrap_or("No Name"));
-        let pos = format!(" {}:{} ", self.cx + 1, self.cy + 1);
+    /// The row showing buffer line `y` as it is drawn, before styling. Lines
+    /// past the end of the buffer are blank, and a closed fold shows its first
+    /// line with the number of lines it hides.
+    fn render_row(&self, y: usize) -> RenderedRow {
+        let mut line = self.buffer.graphemes_on_line(y);
+        let selection = self.block_columns(y, &line);
+        if let Some(fold) = self.closed_fold(y) {
+            let count = fold.end_line - fold.start_line + 1;
+            line.extend(format!(" ··· {count} lines").chars().map(String::from));
+        }
+        RenderedRow {
+            gutter: (self.gutter_width() > 0).then(|| self.signs.shown(y)),
+            selection,
+            cursor_line: self.config.cursorline && y == self.buffer_line() as usize,
+            color_column: (self.config.colorcolumn)
+                .filter(|_| y < self.buffer.len())
+                .and_then(|column| column.checked_sub(1)),
+            cells: screen_cells(line, self.vwidth() as usize, self.config.tabstop),
+        }
+    }
 
-        let file_width = self.size.0 - mode.len() as u16 - pos.len() as u16 - 2;
+    /// The buffer lines shown on the viewport rows, top to bottom, stepping
+    /// over closed folds. Past the end of the buffer they keep counting up.
+    fn viewport_lines(&self) -> Vec<usize> {
+        std::iter::successors(Some(self.vtop as usize), |&y| Some(self.next_visible(y)))
+            .take(self.vheight() as usize)
+            .collect()
+    }
 
-        self.stdout.queue(cursor::MoveTo(0, self.size.1 - 2))?;
-        self.stdout.queue(style::PrintStyledContent(
-            mode.with(style::Color::Rgb { r: 0, g: 0, b: 0 })
-                .on(style::Color::Rgb {
-                    r: 184,
-                    g: 144,
-                    b: 243,
-                }),
-        ))?;
-        self.stdout.queue(style::PrintStyledContent(
-            ""
-                .with(style::Color::Rgb {
-                    r: 184,
-                    g: 144,
-                    b: 243,
-                })
-                .on(style::Color::Rgb {
-                    r: 67,
-                    g: 70,
-                    b: 89,
-                }),
-        ))?;
-        self.stdout.queue(style::PrintStyledContent(
-            format!("{:<width$}", file, width = file_width as usize)
-                .with(style::Color::Rgb {
-                    r: 255,
-                    g: 255,
-                    b: 255,
-                })
-                .on(style::Color::Rgb {
-                    r: 67,
-                    g: 70,
-                    b: 89,
-                }),
-        ))?;
-        self.stdout.queue(style::PrintStyledContent(
-            ""
-                .with(style::Color::Rgb {
-                    r: 184,
-                    g: 144,
-                    b: 243,
-                })
-                .on(style::Color::Rgb {
-                    r: 67,
-                    g: 70,
-                    b: 89,
-                }),
-        ))?;
-        self.stdout.queue(style::PrintStyledContent(
-            pos.with(style::Color::Rgb { r: 0, g: 0, b: 0 })
-                .bold()
-                .on(style::Color::Rgb {
-                    r: 184,
-                    g: 144,
-                    b: 243,
-                }),
-        ))?;
+    /// The viewport row of the cursor, which is above `cy` when closed folds
+    /// hide lines in between.
+    fn screen_cy(&self) -> u16 {
+        let y = self.buffer_line() as usize;
+        let lines = self.viewport_lines();
+        lines.iter().take_while(|&&line| line < y).count() as u16
+    }
 
-        Ok(())
+    /// The text of every viewport row without styling, for tests to compare the
+    /// screen against.
+    #[cfg(test)]
+    fn screen_text(&self) -> Vec<String> {
+        self.viewport_lines()
+            .into_iter()
+            .map(|y| self.render_row(y).text())
+            .collect()
     }
 
-    fn check_bounds(&mut self) {
-        let line_length = self.line_length();
-        if self.cx >= line_length {
-            if line_length > 0 {
-                self.cx = line_length;
-            } else {
-                self.cx = 0;
-            }
+    /// The screen columns of buffer line `y` covered by the visual block, as
+    /// `start..end`; empty when the line is outside of it or there is no block.
+    fn block_columns(&self, y: usize, line: &[String]) -> (usize, usize) {
+        let searching = matches!(self.mode, Mode::Search) && self.visual_search;
+        if !matches!(self.mode, Mode::VisualBlock) && !searching {
+            return (0, 0);
         }
 
-        if self.cx >= self.vwidth() {
-            self.cx = self.vwidth();
+        let (top, bottom, left, right) = self.block();
+        let len = line.len();
+        if y < top || y > bottom || left >= len {
+            return (0, 0);
         }
 
-        let line_on_buffer = self.cy + self.vtop;
-        if line_on_buffer as usize >= self.buffer.len() {
-            self.cy = self.buffer.len() as u16 - self.vtop;
-        }
+        let tabstop = self.config.tabstop;
+        let start = display_col(line, left, tabstop);
+        let end = display_col(line, (right + 1).min(len), tabstop);
+        let width = self.vwidth() as usize;
+        (start.min(width), end.min(width))
     }
 
-    pub fn run(&mut self) -> anyhow::Result<()> {
-        loop {
-            self.check_bounds();
-            self.draw()?;
+    /// The visual block as `(top, bottom, left, right)`, all inclusive.
+    fn block(&self) -> (usize, usize, usize, usize) {
+        let (ax, ay) = self.visual_anchor;
+        let (x, y) = (self.cx as usize, self.buffer_line() as usize);
+        (ay.min(y), ay.max(y), ax.min(x), ax.max(x))
+    }
 
-            if let Some(action) = self.handle_event(read()?)? {
-                match action {
-                    Action::Quit => break,
-                    Action::Save => {
-                        self.buffer.save();
-                    }
-                    Action::MoveUp => {
-                        if self.cy == 0 {
-                            if self.vtop > 0 {
-                                self.vtop -= 1;
-                            }
-                        } else {
-                            self.cy = self.cy.saturating_sub(1);
-                        }
-                    }
-                    Action::MoveDown => {
-                        self.cy += 1;
-                        if self.cy > self.vheight() {
-                            self.vtop += 1;
-                            self.cy -= 1;
-                        }
-                    }
-                    Action::MoveLeft => {
-                        self.cx = self.cx.saturating_sub(1);
-                        if self.cx < self.vleft {
-                            self.cx = self.vleft;
-                        }
-                    }
-                    Action::MoveRight => {
-                        self.cx += 1;
-                    }
-                    Action::MoveToLineEnd => {
-                        self.cx = self.line_length();
-                    }
-                    Action::MoveToLineStart => {
-                        self.cx = 0;
-                    }
-                    Action::PageUp => {
-                        if self.vtop > 0 {
-                            self.vtop = self.vtop.saturating_sub(self.vheight());
-                        }
-                    }
-                    Action::PageDown => {
-                        if self.buffer.len() > (self.vtop + self.vheight()) as usize {
-                            self.vtop += self.vheight();
-                        }
-                    }
-                    Action::EnterMode(new_mode) => {
-                        self.mode = new_mode;
-                    }
-                    Action::InsertCharAtCursorPos(c) => {
-                        self.buffer.insert(self.cx, self.buffer_line(), c);
-                        self.stdout.queue(cursor::MoveTo(self.cx, self.cy))?;
-                        self.stdout.queue(style::Print(c))?;
-                        self.cx += 1;
-                    }
-                    Action::DeleteCharAtCursorPos => {
-                        if self.cx > 0 {
-                            self.cx -= 1;
-                            self.buffer.remove(self.cx, self.buffer_line());
-                        } else if self.buffer_line() > 0 {
-                            self.cy = self.cy.saturating_sub(1);
-
-                            self.cx = self.line_length();
-
-                            if self.cx > 0 {
-                                self.cx -= 1;
-                                self.buffer.remove(self.cx, self.buffer_line());
-                            }
-                        }
-                    }
-                    Action::NewLine => {
-                        self.cy += 1;
-                        self.cx = 0;
-                    }
-                    Action::SetWaitingCad(cmd) => {
-                        self.waiting_command = Some(cmd);
-                    }
-                    Action::DeleteCurrentLine => {
-                        let line = self.buffer_line();
-                        self.buffer.remove_line(line);
-                        if self.cy > 0 {
-                            self.cy -= 1;
-                        }
-                        if self.vtop > 0 {
-                            self.vtop -= 1;
-                        }
-                    }
-                }
-            };
-        }
+    /// Draws the status line from a format string in the file colors, with the
+    /// part after `%=` aligned to the right.
+    fn draw_statusline_format(&mut self, format: &str) -> anyhow::Result<()> {
+        let line = self.buffer_line() as usize + 1;
+        let file = match self.buffer.file.as_deref() {
+            Some(file) => file,
+            None if self.buffer.stdin => "[stdin]",
+            None => "No Name",
+        };
+        let info = StatusInfo {
+            file,
+            modified: self.buffer.modified,
+            readonly: self.buffer.readonly,
+            line,
+            column: self.cx as usize + 1,
+            percent: self.percent(),
+            filetype: self.buffer.filetype.name(),
+        };
+        let (left, right) = statusline::expand(format, &info);
+
+        let width = self.size.0 as usize;
+        let right: String = right.chars().take(width).collect();
+        let left_width = width - right.chars().count();
+        let left: String = left.chars().take(left_width).collect();
+        let text = format!("{left:<left_width$}{right}");
 
+        let SectionColors { fg, bg } = self.statusline.file;
+        self.out.queue(cursor::MoveTo(0, self.size.1 - 2))?;
+        self.out
+            .queue(style::PrintStyledContent(text.with(fg).on(bg)))?;
         Ok(())
     }
 
-    fn handle_event(&mut self, ev: event::Event) -> anyhow::Result<Option<Action>> {
-        if let event::Event::Resize(width, height) = ev {
-            self.size = (width, height);
-            return Ok(None);
-        }
-        match self.mode {
-            Mode::Normal => self.handle_normal_event(ev),
-            Mode::Insert => self.handle_insert_event(ev),
+    fn draw_statusline(&mut self) -> anyhow::Result<()> {
+        if let Some(format) = self.statusline.format.clone() {
+            return self.draw_statusline_format(&format);
         }
-    }
 
-    fn handle_normal_event(&mut self, ev: event::Event) -> anyhow::Result<Option<Action>> {
-        if let Some(cmd) = self.waiting_command.clone() {
-            self.waiting_command = None;
-            return self.handle_waiting_command(ev, cmd);
+        let mut pieces = vec![];
+        for &segment in &self.statusline.segments {
+            let text = match segment {
+                Segment::Mode => format!(" {:?} ", self.mode).to_uppercase(),
+                // sized below, once the width of the other segments is known
+                Segment::File => String::new(),
+                Segment::Position => format!(" {}:{} ", self.cx + 1, self.screen_cy() + 1),
+                Segment::Percent => format!(" {}% ", self.percent()),
+                Segment::Filetype => format!(" {} ", self.buffer.filetype.name()),
+            };
+            pieces.push((segment, text));
         }
-
-        let action = match ev {
-            event::Event::Key(event) => {
-                let code = event.code;
-                let modifiers = event.modifiers;
-
-                match code {
-                    event::KeyCode::Char('q') => Some(Action::Quit),
-                    event::KeyCode::Up | event::KeyCode::Char('k') => Some(Action::MoveUp),
-                    event::KeyCode::Down | event::KeyCode::Char('j') => Some(Action::MoveDown),
-                    event::KeyCode::Left | event::KeyCode::Char('h') => Some(Action::MoveLeft),
-                    event::KeyCode::Right | event::KeyCode::Char('l') => Some(Action::MoveRight),
-                    event::KeyCode::Char('i') => Some(Action::EnterMode(Mode::Insert)),
-                    event::KeyCode::Home | event::KeyCode::Char('0') => {
-                        Some(Action::MoveToLineStart)
-                    }
-                    event::KeyCode::End | event::KeyCode::Char('$') => Some(Action::MoveToLineEnd),
-                    event::KeyCode::Char('b') => {
-                        if matches!(modifiers, event::KeyModifiers::CONTROL) {
-                            Some(Action::PageUp)
-                        } else {
-                            None
-                        }
-                    }
-                    event::KeyCode::Char('f') => {
-                        if matches!(modifiers, event::KeyModifiers::CONTROL) {
-                            Some(Action::PageDown)
-                        } else {
-                            None
-                        }
-                    }
-                    event::KeyCode::Char('s') => {
-                        if matches!(modifiers, event::KeyModifiers::CONTROL) {
-                            Some(Action::Save)
-                        } else {
-                            None
-                        }
-                    }
-                    event::KeyCode::Char('d') => Some(Action::SetWaitingCad('d')),
-                    _ => None,
-                }
+        // without a file segment, a blank one still fills the rest of the line
+        let fill = match pieces.iter().position(|(s, _)| *s == Segment::File) {
+            Some(fill) => fill,
+            None => {
+                pieces.push((Segment::File, String::new()));
+                pieces.len() - 1
             }
-            _ => None,
         };
+        let show_file = self.statusline.segments.contains(&Segment::File);
 
-        Ok(action)
-    }
-
-    fn handle_waiting_command(
-        &mut self,
-        ev: event::Event,
-        cmd: char,
-    ) -> anyhow::Result<Option<Action>> {
-        let action = match cmd {
-            'd' => match ev {
-                event::Event::Key(event) => match event.code {
-                    event::KeyCode::Char('d') => Some(Action::DeleteCurrentLine),
-                    event::KeyCode::Esc => Some(Action::EnterMode(Mode::Normal)),
-                    _ => None,
-                },
-                _ => None,
-            },
-            _ => None,
+        let colors: Vec<_> = pieces
+            .iter()
+            .map(|(segment, _)| self.statusline.colors(*segment))
+            .collect();
+        let separators = colors.windows(2).filter(|c| c[0].bg != c[1].bg).count();
+        let used: usize = pieces.iter().map(|(_, text)| text.chars().count()).sum();
+        let file_width = (self.size.0 as usize).saturating_sub(used + separators);
+        let readonly = if self.buffer.readonly {
+            " [readonly]"
+        } else {
+            ""
+        };
+        let name_width = file_width.saturating_sub(1 + readonly.len());
+        let name = match self.buffer.file.as_deref() {
+            _ if !show_file => String::new(),
+            Some(file) => format!(" {}{readonly}", abbreviate_path(file, name_width)),
+            None if self.buffer.stdin => format!(" [stdin]{readonly}"),
+            None => format!(" No Name{readonly}"),
         };
+        pieces[fill].1 = format!("{name:<file_width$}");
 
-        Ok(action)
-    }
+        self.out.queue(cursor::MoveTo(0, self.size.1 - 2))?;
+        for (i, (segment, text)) in pieces.iter().enumerate() {
+            let SectionColors { fg, bg } = colors[i];
+            let text = text.as_str().with(fg).on(bg);
+            let text = match segment {
+                Segment::Position | Segment::Percent => text.bold(),
+                _ => text,
+            };
+            self.out.queue(style::PrintStyledContent(text))?;
 
-    fn handle_insert_event(&self, ev: event::Event) -> anyhow::Result<Option<Action>> {
-        let action = match ev {
-            event::Event::Key(event) => match event.code {
-                event::KeyCode::Esc => Some(Action::EnterMode(Mode::Normal)),
-                event::KeyCode::Char(c) => Some(Action::InsertCharAtCursorPos(c)),
-                event::KeyCode::Backspace => Some(Action::DeleteCharAtCursorPos),
-                event::KeyCode::Enter => Some(Action::NewLine),
-                event::KeyCode::Up => Some(Action::MoveUp),
-                event::KeyCode::Down => Some(Action::MoveDown),
-                event::KeyCode::Left => Some(Action::MoveLeft),
-                event::KeyCode::Right => Some(Action::MoveRight),
-                _ => None,
-            },
-            _ => None,
-        };
+            // powerline arrows point into the file segment from both sides
+            if let Some(next) = colors.get(i + 1).filter(|next| next.bg != bg) {
+                let arrow = if i < fill {
+                    "".with(bg).on(next.bg)
+                } else {
+                    "".with(next.bg).on(bg)
+                };
+                self.out.queue(style::PrintStyledContent(arrow))?;
+            }
+        }
 
-        Ok(action)
+        Ok(())
     }
 
-    pub fn cleanup(&mut self) -> anyhow::Result<()> {
-        self.stdout.execute(terminal::LeaveAlternateScreen)?;
-        terminal::disable_raw_mode()?;
+    fn draw_message(&mut self) -> anyhow::Result<()> {
+        let message = self.message_line();
+        let width = self.size.0 as usize;
+        // a message wider than the screen would wrap and scroll the terminal
+        let message: String = message.chars().take(width).collect();
+        self.out
+            .queue(cursor::MoveTo(0, self.size.1 - 1))?
+            .queue(style::Print(format!("{message:<width$}")))?;
 
         Ok(())
     }
-}
 
-impl Drop for Editor {
+    /// What the last row shows: the prompt being typed, or the message.
+    fn message_line(&self) -> String {
+        match self.mode {
+            _ if self.palette.is_some() => {
+                format!("> {}", self.palette.as_ref().map_or("", |p| &p.query))
+            }
+            Mode::Command => format!(":{}", self.command),
+            Mode::Search => format!("/{}", self.command),
+            // a question stays until it is answered
+            _ if self.message_time.elapsed() >= MESSAGE_DURATION && self.confirm.is_none() => {
+                String::new()
+            }
+            _ => self.message.clone().unwrap_or_default(),
+        }
+    }
+
+    /// Draws `content` in a floating box anchored at the cursor position `(x, y)`.
+    /// The box opens on the row below the anchor, or above it when there is not
+    /// enough room, and is shifted left to stay inside the viewport.
+    fn draw_popup(&mut self, content: &str, x: u16, y: u16) -> anyhow::Result<()> {
+        let mut lines = wrap_text(content, POPUP_WIDTH);
+        let room_below = self.vheight().saturating_sub(y + 1) as usize;
+        let room_above = y as usize;
+        let max_height = room_below.max(room_above).max(1);
+        lines.truncate(max_height);
+
+        let width = lines.iter().map(|l| l.chars().count()).max().unwrap_or(0) + 2;
+        let width = width.min(self.size.0 as usize);
+        let height = lines.len() as u16;
+
+        let top = if room_below >= lines.len() || room_below >= room_above {
+            y + 1
+        } else {
+            y - height
+        };
+        let left = x.min(self.size.0.saturating_sub(width as u16));
+
+        for (i, line) in lines.iter().enumerate() {
+            let text: String = format!(" {line:<w$}", w = width - 1)
+                .chars()
+                .take(width)
+                .collect();
+            self.out
+                .queue(cursor::MoveTo(left, top + i as u16))?
+                .queue(style::PrintStyledContent(
+                    text.with(style::Color::Rgb {
+                        r: 255,
+                        g: 255,
+                        b: 255,
+                    })
+                    .on(style::Color::Rgb {
+                        r: 67,
+                        g: 70,
+                        b: 89,
+                    }),
+                ))?;
+        }
+
+        Ok(())
+    }
+
+    /// Draws a list of `items` below (or above) the cursor row `y`, starting at
+    /// column `x`, with the `selected` entry highlighted.
+    fn draw_menu(
+        &mut self,
+        items: &[String],
+        selected: usize,
+        x: u16,
+        y: u16,
+    ) -> anyhow::Result<()> {
+        let room_below = self.vheight().saturating_sub(y + 1) as usize;
+        let room_above = y as usize;
+        let height = items.len().min(MENU_HEIGHT).min(room_below.max(room_above));
+        if height == 0 {
+            return Ok(());
+        }
+
+        // scroll the window so the selected item stays visible
+        let first = selected.saturating_sub(height - 1);
+        let visible = &items[first..first + height];
+
+        let width = visible.iter().map(|l| l.chars().count()).max().unwrap_or(0) + 2;
+        let width = width.min(self.size.0 as usize);
+        let top = if room_below >= height {
+            y + 1
+        } else {
+            y - height as u16
+        };
+        let left = x.min(self.size.0.saturating_sub(width as u16));
+
+        for (i, item) in visible.iter().enumerate() {
+            let text: String = format!(" {item:<w$}", w = width - 1)
+                .chars()
+                .take(width)
+                .collect();
+            let text = if first + i == selected {
+                text.with(style::Color::Rgb { r: 0, g: 0, b: 0 })
+                    .on(style::Color::Rgb {
+                        r: 184,
+                        g: 144,
+                        b: 243,
+                    })
+            } else {
+                text.with(style::Color::Rgb {
+                    r: 255,
+                    g: 255,
+                    b: 255,
+                })
+                .on(style::Color::Rgb {
+                    r: 67,
+                    g: 70,
+                    b: 89,
+                })
+            };
+            self.out
+                .queue(cursor::MoveTo(left, top + i as u16))?
+                .queue(style::PrintStyledContent(text))?;
+        }
+
+        Ok(())
+    }
+
+    fn hover(&mut self) {
+        let line = self.buffer_line() as usize;
+        let character =
+            lsp::utf16_col(&self.buffer.get(line).unwrap_or_default(), self.cx as usize);
+        let text = self.buffer.text();
+
+        let Some(lsp) = self.lsp.as_mut() else {
+            self.set_message("No documentation available");
+            return;
+        };
+
+        match lsp.hover(&text, line, character) {
+            Result::Ok(Some(doc)) => self.popup = Some(doc),
+            Result::Ok(None) => self.set_message("No documentation available"),
+            Err(err) => {
+                log!("lsp: hover failed: {err}");
+                self.set_message("No documentation available");
+            }
+        }
+    }
+
+    /// Replaces the buffer with `file`, resetting the view and the language server.
+    /// The file being left becomes the alternate file. A file that cannot be
+    /// read is not opened; returns whether it was.
+    fn open(&mut self, file: &str) -> bool {
+        let buffer = match Buffer::from_file(Some(file.to_string())) {
+            Result::Ok(buffer) => buffer,
+            Err(err) => {
+                self.set_message(format!("Can't open {file}: {err}"));
+                return false;
+            }
+        };
+        self.remove_swap();
+        if let Some(current) = self.buffer.file.take().filter(|current| current != file) {
+            self.alternate = Some((current, self.cx as usize, self.buffer_line() as usize));
+        }
+        self.buffer = buffer;
+        self.buffer.tabstop = self.config.tabstop;
+        self.lsp = start_lsp(file, self.buffer.filetype);
+        self.signs = Signs::default();
+        self.load_git_gutter();
+        self.undo.clear();
+        self.folds.clear();
+        (self.vtop, self.vleft, self.cx, self.cy) = (0, 0, 0, 0);
+        self.check_swap();
+        true
+    }
+
+    /// Completes the path argument of `:e` and `:w`. The first Tab fills in the
+    /// longest common prefix of the matches; further Tabs cycle through them.
+    fn complete_command_path(&mut self) {
+        if let Some(completion) = self.path_completion.as_mut() {
+            let next = completion
+                .index
+                .map_or(0, |i| (i + 1) % completion.candidates.len());
+            completion.index = Some(next);
+            self.command = format!("{}{}", completion.base, completion.candidates[next]);
+            return;
+        }
+
+        let Some((name, partial)) = self.command.split_once(' ') else {
+            return;
+        };
+        if !matches!(
+            name,
+            "e" | "e!" | "edit" | "edit!" | "view" | "view!" | "w" | "w!" | "write" | "write!"
+        ) {
+            return;
+        }
+
+        let base = format!("{name} ");
+        let candidates = complete::complete_path(partial.trim_start());
+        if candidates.is_empty() {
+            return;
+        }
+
+        let prefix = complete::common_prefix(&candidates);
+        let index = if candidates.len() == 1 || prefix.len() > partial.trim_start().len() {
+            self.command = format!("{base}{prefix}");
+            None
+        } else {
+            self.command = format!("{base}{}", candidates[0]);
+            Some(0)
+        };
+
+        if candidates.len() > 1 {
+            self.path_completion = Some(PathCompletion {
+                base,
+                candidates,
+                index,
+            });
+        }
+    }
+
+    fn word_under_cursor(&self) -> String {
+        self.word_at(self.cx as usize, self.buffer_line() as usize)
+    }
+
+    fn word_at(&self, x: usize, y: usize) -> String {
+        let line = self.buffer.get(y).unwrap_or_default();
+        let line: Vec<&str> = line.graphemes(true).collect();
+        let in_word = |g: &&str| g.chars().next().is_some_and(is_word_char);
+        let cx = x.min(line.len());
+        let start = line[..cx]
+            .iter()
+            .rposition(|g| !in_word(g))
+            .map_or(0, |i| i + 1);
+        let end = line[cx..]
+            .iter()
+            .position(|g| !in_word(g))
+            .map_or(line.len(), |i| cx + i);
+
+        line[start..end].concat()
+    }
+
+    /// The file name around column `x` of line `y`: letters, digits and the
+    /// chars of `isfname`.
+    fn path_at(&self, x: usize, y: usize) -> String {
+        let is_fname_char = |g: &&str| {
+            g.chars()
+                .next()
+                .is_some_and(|c| c.is_alphanumeric() || self.config.isfname.contains(c))
+        };
+        let line = self.buffer.get(y).unwrap_or_default();
+        let line: Vec<&str> = line.graphemes(true).collect();
+        let cx = x.min(line.len());
+        let start = line[..cx]
+            .iter()
+            .rposition(|c| !is_fname_char(c))
+            .map_or(0, |i| i + 1);
+        let end = line[cx..]
+            .iter()
+            .position(|c| !is_fname_char(c))
+            .map_or(line.len(), |i| cx + i);
+
+        line[start..end].concat()
+    }
+
+    fn rename_symbol(&mut self, new_name: &str) {
+        let line = self.buffer_line() as usize;
+        let character =
+            lsp::utf16_col(&self.buffer.get(line).unwrap_or_default(), self.cx as usize);
+        let text = self.buffer.text();
+
+        let Some(lsp) = self.lsp.as_mut() else {
+            self.set_message("No language server running");
+            return;
+        };
+
+        let files = match lsp.rename(&text, line, character, new_name) {
+            Result::Ok(files) => files,
+            Err(err) => {
+                log!("lsp: rename failed: {err}");
+                self.set_message(format!("Rename failed: {err}"));
+                return;
+            }
+        };
+
+        if files.is_empty() {
+            self.set_message("Nothing to rename");
+            return;
+        }
+        self.apply_rename(files);
+    }
+
+    /// Makes the edits of a rename, file by file. There is only one buffer, so
+    /// other files are edited on disk: all of them are read and edited before
+    /// any is written, and one that cannot be read, or that has a swap file
+    /// because it is being edited, leaves everything as it was. Each is then
+    /// written as the buffer is, pre-save hooks first; the edits to the buffer
+    /// itself are one undo step.
+    fn apply_rename(&mut self, files: Vec<(String, Vec<lsp::TextEdit>)>) {
+        let current = (self.buffer.file.as_deref()).and_then(|f| std::fs::canonicalize(f).ok());
+        let file_count = files.len();
+        let occurrences: usize = files.iter().map(|(_, edits)| edits.len()).sum();
+
+        let mut own = None;
+        let mut others = vec![];
+        let mut failures = vec![];
+        for (path, edits) in files {
+            if std::fs::canonicalize(&path).ok() == current {
+                own = Some(edits);
+            } else if swap::swap_path(&path).exists() {
+                failures.push(format!("{path} is being edited"));
+            } else {
+                match Buffer::from_file(Some(path.clone())) {
+                    Result::Ok(mut buffer) => {
+                        lsp::apply_text_edits(&mut buffer, edits);
+                        others.push((path, buffer));
+                    }
+                    Err(err) => failures.push(format!("{path}: {err}")),
+                }
+            }
+        }
+        if !failures.is_empty() {
+            self.set_message(format!("Not renamed: {}", failures.join(", ")));
+            return;
+        }
+
+        for (path, mut buffer) in others {
+            if let Err(err) = self.write_other(&mut buffer) {
+                failures.push(format!("{path}: {err}"));
+            }
+        }
+        if let Some(edits) = own {
+            lsp::apply_text_edits(&mut self.buffer, edits);
+            self.save_undo_state();
+        }
+
+        let renamed = format!(
+            "Renamed {} in {}",
+            counted(occurrences, "occurrence"),
+            counted(file_count, "file")
+        );
+        match failures.is_empty() {
+            true => self.set_message(renamed),
+            false => self.set_message(format!("{renamed}; not written: {}", failures.join(", "))),
+        }
+    }
+
+    /// Writes `buffer`, which holds a file other than the one being edited, as
+    /// the buffer is written: after the pre-save hooks and before the post-save
+    /// ones.
+    fn write_other(&mut self, buffer: &mut Buffer) -> anyhow::Result<()> {
+        for hook in &mut self.pre_save {
+            hook(buffer)?;
+        }
+        buffer.save()?;
+        for hook in &mut self.post_save {
+            hook(buffer);
+        }
+        Ok(())
+    }
+
+    fn trigger_completion(&mut self) {
+        let line = self.buffer_line() as usize;
+        let current = self.buffer.get(line).unwrap_or_default();
+        let chars = self.buffer.column_chars(line);
+        let cx = (self.cx as usize).min(chars.len());
+        let start = chars[..cx]
+            .iter()
+            .rposition(|c| !is_word_char(*c))
+            .map_or(0, |i| i + 1);
+
+        let character = lsp::utf16_col(&current, cx);
+        let text = self.buffer.text();
+
+        let Some(lsp) = self.lsp.as_mut() else {
+            self.set_message("No completions available");
+            return;
+        };
+
+        match lsp.completion(&text, line, character) {
+            Result::Ok(items) if !items.is_empty() => {
+                self.completion = Some(Completion {
+                    items,
+                    selected: 0,
+                    start: start as u16,
+                    source: CompletionSource::Lsp,
+                });
+            }
+            Result::Ok(_) => self.set_message("No completions available"),
+            Err(err) => {
+                log!("lsp: completion failed: {err}");
+                self.set_message("No completions available");
+            }
+        }
+    }
+
+    /// Offers the words of the buffer that start with the word before the cursor.
+    fn complete_word(&mut self, backward: bool) {
+        let y = self.buffer_line() as usize;
+        let chars = self.buffer.column_chars(y);
+        let cx = (self.cx as usize).min(chars.len());
+        let start = chars[..cx]
+            .iter()
+            .rposition(|c| !is_word_char(*c))
+            .map_or(0, |i| i + 1);
+        let prefix = self.buffer.get_range(y as u16, start as u16, cx as u16);
+
+        let items: Vec<lsp::CompletionItem> = self
+            .buffer
+            .words_with_prefix(&prefix, cx, y)
+            .into_iter()
+            .filter(|word| *word != prefix)
+            .map(|word| lsp::CompletionItem {
+                label: word.clone(),
+                filter_text: word.clone(),
+                insert_text: word,
+            })
+            .collect();
+        if items.is_empty() {
+            self.set_message("Pattern not found");
+            return;
+        }
+
+        // going round the buffer, the last word found is the nearest before
+        // the cursor
+        let selected = if backward { items.len() - 1 } else { 0 };
+        self.completion = Some(Completion {
+            items,
+            selected,
+            start: start as u16,
+            source: CompletionSource::Words,
+        });
+        self.replace_completed_word();
+    }
+
+    /// Puts the selected word of a buffer word completion in place of the word
+    /// being completed.
+    fn replace_completed_word(&mut self) {
+        let Some(completion) = &self.completion else {
+            return;
+        };
+        let Some(item) = completion.items.get(completion.selected) else {
+            return;
+        };
+        let (start, word) = (completion.start, item.insert_text.clone());
+
+        let y = self.buffer_line();
+        while self.cx > start {
+            self.cx -= 1;
+            self.buffer.remove(self.cx, y);
+        }
+        for c in word.chars() {
+            self.buffer.insert(self.cx, y, c);
+            self.cx += 1;
+        }
+    }
+
+    /// Ctrl-x Ctrl-f: completes the path before the cursor with the names in
+    /// the directory it points into.
+    fn complete_file_name(&mut self) {
+        let y = self.buffer_line() as usize;
+        let chars = self.buffer.column_chars(y);
+        let cx = (self.cx as usize).min(chars.len());
+        let start = chars[..cx]
+            .iter()
+            .rposition(|c| c.is_whitespace())
+            .map_or(0, |i| i + 1);
+        let partial = self.buffer.get_range(y as u16, start as u16, cx as u16);
+
+        let items: Vec<lsp::CompletionItem> = complete::complete_path(&partial)
+            .into_iter()
+            .map(|path| {
+                let name = path.trim_end_matches('/');
+                let name = &path[name.rfind('/').map_or(0, |i| i + 1)..];
+                lsp::CompletionItem {
+                    label: name.to_string(),
+                    filter_text: path.clone(),
+                    insert_text: path,
+                }
+            })
+            .collect();
+        if items.is_empty() {
+            self.set_message("Pattern not found");
+            return;
+        }
+
+        self.completion = Some(Completion {
+            items,
+            selected: 0,
+            start: start as u16,
+            source: CompletionSource::Paths,
+        });
+    }
+
+    fn completion_source(&self) -> Option<&CompletionSource> {
+        self.completion.as_ref().map(|c| &c.source)
+    }
+
+    /// Completion items whose filter text starts with the word typed so far.
+    fn completion_matches(&self) -> Vec<&lsp::CompletionItem> {
+        let Some(completion) = &self.completion else {
+            return vec![];
+        };
+        if completion.source == CompletionSource::Words {
+            return completion.items.iter().collect();
+        }
+
+        let prefix = self
+            .buffer
+            .get_range(self.buffer_line(), completion.start, self.cx)
+            .to_lowercase();
+
+        completion
+            .items
+            .iter()
+            .filter(|item| item.filter_text.to_lowercase().starts_with(&prefix))
+            .collect()
+    }
+
+    fn accept_completion(&mut self) {
+        // the selected word is already in the line
+        if self.completion_source() == Some(&CompletionSource::Words) {
+            self.completion = None;
+            return;
+        }
+        let selected = self.completion.as_ref().map_or(0, |c| c.selected);
+        let Some(item) = self
+            .completion_matches()
+            .get(selected)
+            .map(|item| (*item).clone())
+        else {
+            self.completion = None;
+            return;
+        };
+        let start = self.completion.take().map_or(self.cx, |c| c.start);
+
+        let y = self.buffer_line();
+        while self.cx > start {
+            self.cx -= 1;
+            self.buffer.remove(self.cx, y);
+        }
+
+        // the buffer has no way to split lines yet, so multi-line items are cut
+        // at the first line break
+        let text = item.insert_text.lines().next().unwrap_or_default();
+        let x = self.cx as usize;
+        self.buffer.replace((x, y as usize), (x, y as usize), text);
+        self.cx += text.graphemes(true).count() as u16;
+    }
+
+    /// Keeps the selection inside the filtered list after it shrinks or grows.
+    fn clamp_completion(&mut self) {
+        let count = self.completion_matches().len();
+        if let Some(completion) = self.completion.as_mut() {
+            completion.selected = completion.selected.min(count.saturating_sub(1));
+        }
+    }
+
+    fn move_completion(&mut self, forward: bool) {
+        let count = self.completion_matches().len();
+        if let Some(completion) = self.completion.as_mut() {
+            if count == 0 {
+                completion.selected = 0;
+            } else if forward {
+                completion.selected = (completion.selected + 1) % count;
+            } else {
+                completion.selected = (completion.selected + count - 1) % count;
+            }
+        }
+        if self.completion_source() == Some(&CompletionSource::Words) {
+            self.replace_completed_word();
+        }
+    }
+
+    fn check_bounds(&mut self) {
+        // a closed fold counts as one line, its first
+        let y = self.fold_start(self.buffer_line() as usize) as u16;
+        self.vtop = self.vtop.min(y);
+        self.cy = y - self.vtop;
+
+        let max_cx = max_cursor_col(&self.mode, self.line_length() as usize);
+        self.cx = self.cx.min(max_cx as u16);
+
+        if self.cx >= self.vwidth() {
+            self.cx = self.vwidth();
+        }
+
+        let line_on_buffer = self.cy + self.vtop;
+        if line_on_buffer as usize >= self.buffer.len() {
+            self.cy = self.buffer.len() as u16 - self.vtop;
+        }
+
+        self.scroll_into_view();
+    }
+
+    /// Scrolls so the cursor stays on screen with at least `scrolloff` lines of
+    /// context above and below it, where the buffer has them.
+    fn scroll_into_view(&mut self) {
+        let vheight = self.vheight() as usize;
+        let so = self.scrolloff();
+        let y = self.buffer_line() as usize;
+
+        let mut vtop = self.fold_start(self.vtop as usize).min(self.rows_up(y, so));
+
+        let mut below = 0;
+        let mut line = self.next_visible(y);
+        while below < so && line < self.buffer.len() {
+            below += 1;
+            line = self.next_visible(line);
+        }
+        vtop = vtop.max(self.rows_up(y, vheight.saturating_sub(below + 1)));
+
+        self.vtop = vtop as u16;
+        self.cy = (y - vtop) as u16;
+    }
+
+    /// `scrolloff`, less when the view is too short for it.
+    fn scrolloff(&self) -> usize {
+        let vheight = self.vheight() as usize;
+        (self.config.scrolloff as usize).min(vheight.saturating_sub(1) / 2)
+    }
+
+    /// Puts line `vtop` at the top of the view, moving the cursor only as far
+    /// as it takes to stay `scrolloff` lines inside it.
+    fn scroll_to(&mut self, vtop: usize) {
+        let vheight = self.vheight() as usize;
+        let so = self.scrolloff();
+        let mut y = self.buffer_line() as usize;
+        if vtop > 0 {
+            y = y.max(self.rows_down(vtop, so));
+        }
+        // the bottom margin is not kept once the end of the buffer is in view
+        if self.rows_down(vtop, vheight.saturating_sub(1)) < self.buffer.len().saturating_sub(1) {
+            y = y.min(self.rows_down(vtop, vheight.saturating_sub(so + 1)));
+        }
+
+        self.vtop = vtop as u16;
+        self.cy = (y - vtop) as u16;
+    }
+
+    /// The line shown `n` rows below line `y`, or the last line.
+    fn rows_down(&self, y: usize, n: usize) -> usize {
+        let mut y = self.fold_start(y);
+        for _ in 0..n {
+            let next = self.next_visible(y);
+            if next >= self.buffer.len() {
+                break;
+            }
+            y = next;
+        }
+        y
+    }
+
+    /// The line shown `n` rows above line `y`, or the first line.
+    fn rows_up(&self, y: usize, n: usize) -> usize {
+        let mut y = self.fold_start(y);
+        for _ in 0..n {
+            if y == 0 {
+                break;
+            }
+            y = self.fold_start(y - 1);
+        }
+        y
+    }
+
+    /// The closed fold that hides line `y`, the outermost if closed folds nest.
+    fn closed_fold(&self, y: usize) -> Option<&Fold> {
+        self.folds
+            .iter()
+            .find(|fold| !fold.open && fold.contains(y))
+    }
+
+    /// The line shown for line `y`: the first of the closed fold hiding it, or
+    /// `y` itself.
+    fn fold_start(&self, y: usize) -> usize {
+        self.closed_fold(y)
+            .map_or(y, |fold| fold.start_line as usize)
+    }
+
+    /// The line shown below line `y`.
+    fn next_visible(&self, y: usize) -> usize {
+        self.closed_fold(y).map_or(y, |fold| fold.end_line as usize) + 1
+    }
+
+    /// Works the folds out again from the buffer, keeping closed those that
+    /// were. A fold starting after line `changed_at` has moved by as many
+    /// lines as the buffer grew or shrank from `old_len`.
+    fn update_folds(&mut self, changed_at: usize, old_len: usize) {
+        let shift = self.buffer.len() as isize - old_len as isize;
+        let closed: Vec<usize> = self
+            .folds
+            .iter()
+            .filter(|fold| !fold.open)
+            .map(|fold| match fold.start_line as usize {
+                start if start > changed_at => start.saturating_add_signed(shift),
+                start => start,
+            })
+            .collect();
+        self.folds = (self.buffer.detect_indent_folds().into_iter())
+            .map(|(start_line, end_line)| Fold {
+                start_line,
+                end_line,
+                open: !closed.contains(&(start_line as usize)),
+            })
+            .collect();
+    }
+
+    /// Places `sign` in the gutter on buffer line `y`. It moves with the line
+    /// as lines are added or removed above it.
+    pub fn set_sign(&mut self, y: usize, sign: Sign) {
+        self.signs.place(y, sign);
+    }
+
+    /// Reads the committed version of the file to mark the changes from.
+    fn load_git_gutter(&mut self) {
+        self.gutter = self.buffer.file.as_deref().and_then(GitGutter::load);
+        self.git_signs_at = None;
+        self.update_git_signs();
+    }
+
+    /// Marks again how the buffer differs from `HEAD`, if it changed since the
+    /// last time. That diffs the whole buffer, so it waits for a pause in the
+    /// typing rather than following every key.
+    fn update_git_signs(&mut self) {
+        let changes = self.buffer.changes();
+        if self.git_signs_at == Some(changes) {
+            return;
+        }
+        self.git_signs_at = Some(changes);
+
+        self.signs.remove(Sign::is_git);
+        let Some(gutter) = &self.gutter else {
+            return;
+        };
+        let lines: Vec<Cow<str>> = self.buffer.lines().collect();
+        let lines: Vec<&str> = lines.iter().map(|line| line.as_ref()).collect();
+        for (y, sign) in gutter.signs(&lines) {
+            self.set_sign(y, sign);
+        }
+    }
+
+    /// Adopts a new terminal size and clears the screen, since what was drawn
+    /// for the old size is no longer where the next draw expects it.
+    fn resize(&mut self, width: u16, height: u16) -> anyhow::Result<()> {
+        self.size = (width, height);
+        self.fit_viewport();
+        self.out.queue(terminal::Clear(terminal::ClearType::All))?;
+        self.drawn.clear();
+        Ok(())
+    }
+
+    /// Moves the viewport so that the cursor row lies inside `vheight`, and
+    /// scrolls back up when lines below the end of the buffer would be shown
+    /// while lines above `vtop` are hidden.
+    fn fit_viewport(&mut self) {
+        let vheight = self.vheight().max(1);
+        if self.cy >= vheight {
+            let shift = self.cy + 1 - vheight;
+            self.vtop += shift;
+            self.cy -= shift;
+        }
+
+        let len = self.buffer.len() as u16;
+        let empty_rows = (self.vtop + vheight).saturating_sub(len);
+        let shift = empty_rows.min(self.vtop);
+        self.vtop -= shift;
+        self.cy += shift;
+
+        self.check_bounds();
+    }
+
+    /// Applies an action to the editor state. Returns `true` when the editor
+    /// should quit.
+    fn execute(&mut self, action: Action) -> anyhow::Result<bool> {
+        let vertical = matches!(action, Action::MoveUp | Action::MoveDown);
+        let preferred_cx = self.preferred_cx.take().unwrap_or(self.cx);
+        if vertical {
+            self.cx = preferred_cx;
+            self.preferred_cx = Some(preferred_cx);
+        }
+
+        if action.changes_buffer() && !self.modifiable() {
+            return Ok(false);
+        }
+        // what is changed is not left out of sight
+        if action.changes_buffer() {
+            let y = self.buffer_line() as usize;
+            for fold in self.folds.iter_mut().filter(|fold| fold.contains(y)) {
+                fold.open = true;
+            }
+        }
+        // a change made from insert mode is undone together with the typing,
+        // except what Ctrl-w and Ctrl-u delete, which undo brings back first
+        let deletes_back = matches!(
+            action,
+            Action::DeleteWordBackward | Action::DeleteToLineStart
+        );
+        if (!matches!(self.mode, Mode::Insert) || deletes_back) && action.changes_buffer() {
+            self.save_undo_state();
+        }
+
+        match action {
+            Action::Quit => {
+                self.remove_swap();
+                return Ok(true);
+            }
+            Action::Save => {
+                self.save(Action::ExecuteCommand("w!".to_string()));
+            }
+            Action::ReloadFile => {
+                self.reload();
+            }
+            Action::RecoverSwap => match self.buffer.file.as_deref().and_then(swap::newer) {
+                Some(text) => {
+                    self.buffer.replace((0, 0), (usize::MAX, usize::MAX), &text);
+                    self.set_message("Recovered from the swap file; :w to keep it, u to undo");
+                }
+                None => self.set_message("The swap file is gone"),
+            },
+            Action::Undo => {
+                self.save_undo_state();
+                let Some(change) = self.undo.undo() else {
+                    self.set_message("Already at oldest change");
+                    return Ok(false);
+                };
+                for edit in change.edits.iter().rev() {
+                    self.buffer.unapply_edit(edit);
+                }
+                let cursor = change.cursor;
+                self.restore_cursor(cursor);
+            }
+            Action::Redo => {
+                self.save_undo_state();
+                let Some(change) = self.undo.redo() else {
+                    self.set_message("Already at newest change");
+                    return Ok(false);
+                };
+                for edit in &change.edits {
+                    self.buffer.apply_edit(edit);
+                }
+                let cursor = change.cursor;
+                self.restore_cursor(cursor);
+            }
+            Action::AlternateFile => {
+                let Some((file, x, y)) = self.alternate.clone() else {
+                    self.set_message("No alternate file");
+                    return Ok(false);
+                };
+                if self.buffer.modified {
+                    self.set_message("No write since last change (add ! to override)");
+                    return Ok(false);
+                }
+                if !self.open(&file) {
+                    return Ok(false);
+                }
+                let y = y.min(self.buffer.len().saturating_sub(1));
+                self.move_to(x, y);
+            }
+            Action::GoToFile => {
+                let name = self.path_at(self.cx as usize, self.buffer_line() as usize);
+                if name.is_empty() {
+                    self.set_message("No file name under cursor");
+                    return Ok(false);
+                }
+                let dir = (self.buffer.file.as_deref())
+                    .and_then(|file| Path::new(file).parent())
+                    .unwrap_or(Path::new(""));
+                let path = dir.join(&name);
+                if !path.is_file() {
+                    self.set_message(format!("Can't find file \"{name}\""));
+                    return Ok(false);
+                }
+                if self.buffer.modified {
+                    self.set_message("No write since last change (add ! to override)");
+                    return Ok(false);
+                }
+                self.open(&path.to_string_lossy());
+            }
+            Action::MoveUp => {
+                let y = self.buffer_line() as usize;
+                if y > 0 {
+                    let up = self.fold_start(y - 1) as u16;
+                    self.vtop = self.vtop.min(up);
+                    self.cy = up - self.vtop;
+                }
+            }
+            Action::MoveDown => {
+                let down = self.next_visible(self.buffer_line() as usize);
+                if down < self.buffer.len() {
+                    self.cy = down as u16 - self.vtop;
+                }
+            }
+            Action::MoveLeft => {
+                self.cx = self.cx.saturating_sub(1);
+                if self.cx < self.vleft {
+                    self.cx = self.vleft;
+                }
+            }
+            Action::MoveRight => {
+                self.cx += 1;
+            }
+            Action::MoveBackward => {
+                let y = self.buffer_line() as usize;
+                if self.cx > 0 {
+                    self.cx -= 1;
+                } else if y > 0 {
+                    let up = self.fold_start(y - 1);
+                    let end = self.buffer.chars_on_line(up).saturating_sub(1);
+                    self.move_to(end, up);
+                }
+            }
+            Action::MoveForward => {
+                let down = self.next_visible(self.buffer_line() as usize);
+                if self.cx + 1 < self.line_length() {
+                    self.cx += 1;
+                } else if down < self.buffer.len() {
+                    self.move_to(0, down);
+                }
+            }
+            Action::MoveToLineEnd => {
+                self.cx = max_cursor_col(&self.mode, self.line_length() as usize) as u16;
+            }
+            Action::MoveToLineStart => {
+                self.cx = 0;
+            }
+            Action::SmartHome => {
+                let first = self.buffer.first_non_blank(self.buffer_line() as usize) as u16;
+                self.cx = if self.cx == first { 0 } else { first };
+            }
+            Action::GoToTop => self.go_to_line(0),
+            Action::GoToBottom => self.go_to_line(self.buffer.len().saturating_sub(1)),
+            Action::PageUp => {
+                if self.vtop > 0 {
+                    self.vtop = self.vtop.saturating_sub(self.vheight());
+                }
+            }
+            Action::PageDown => {
+                if self.buffer.len() > (self.vtop + self.vheight()) as usize {
+                    self.vtop += self.vheight();
+                }
+            }
+            Action::HalfPageUp => {
+                let amount = (self.vheight() / 2).max(1);
+                let y = self.buffer_line().saturating_sub(amount);
+                self.vtop = self.vtop.saturating_sub(amount);
+                self.cy = y - self.vtop;
+            }
+            Action::HalfPageDown => {
+                let amount = (self.vheight() / 2).max(1);
+                let last = self.buffer.len().saturating_sub(1) as u16;
+                let max_vtop = (self.buffer.len() as u16).saturating_sub(self.vheight());
+                let y = (self.buffer_line() + amount).min(last);
+                self.vtop = (self.vtop + amount).min(max_vtop.max(self.vtop));
+                self.cy = y.saturating_sub(self.vtop);
+            }
+            Action::ScrollDown => {
+                // as far as the last line with `scrolloff` lines above it
+                let last = self.buffer.len().saturating_sub(1);
+                let vtop = self.next_visible(self.vtop as usize);
+                if vtop <= self.rows_up(last, self.scrolloff()) {
+                    self.scroll_to(vtop);
+                }
+            }
+            Action::ScrollUp => {
+                if self.vtop > 0 {
+                    self.scroll_to(self.rows_up(self.vtop as usize, 1));
+                }
+            }
+            Action::OpenFold => {
+                self.update_folds(usize::MAX, self.buffer.len());
+                let y = self.buffer_line() as usize;
+                match self.folds.iter_mut().find(|f| !f.open && f.contains(y)) {
+                    Some(fold) => fold.open = true,
+                    None => self.set_message("No fold found"),
+                }
+            }
+            Action::CloseFold => {
+                self.update_folds(usize::MAX, self.buffer.len());
+                let y = self.buffer_line() as usize;
+                match self
+                    .folds
+                    .iter_mut()
+                    .rev()
+                    .find(|f| f.open && f.contains(y))
+                {
+                    Some(fold) => fold.open = false,
+                    None => self.set_message("No fold found"),
+                }
+            }
+            Action::OpenAllFolds | Action::CloseAllFolds => {
+                self.update_folds(usize::MAX, self.buffer.len());
+                let open = matches!(action, Action::OpenAllFolds);
+                for fold in &mut self.folds {
+                    fold.open = open;
+                }
+            }
+            Action::EnterMode(new_mode) => {
+                match new_mode {
+                    Mode::Search => {
+                        self.search_origin = (self.vtop, self.cx, self.cy);
+                        self.visual_search = matches!(self.mode, Mode::VisualBlock);
+                    }
+                    Mode::VisualBlock => {
+                        self.visual_anchor = (self.cx as usize, self.buffer_line() as usize);
+                    }
+                    Mode::Insert => self.count_insert(false),
+                    Mode::Normal => {
+                        self.finish_block_insert();
+                        self.finish_insert_repeat();
+                    }
+                    _ => {}
+                }
+                self.command.clear();
+                self.mode = new_mode;
+            }
+            Action::ExecuteCommand(command) => {
+                return self.execute_command(&command);
+            }
+            Action::InsertCharAtCursorPos(c) => {
+                self.buffer.insert(self.cx, self.buffer_line(), c);
+                self.out.queue(cursor::MoveTo(self.cx, self.screen_cy()))?;
+                self.out.queue(style::Print(c))?;
+                self.cx += 1;
+                if !is_word_char(c) {
+                    self.completion = None;
+                }
+                self.clamp_completion();
+            }
+            Action::InsertLiteral(c) => {
+                // a line break typed literally would split the line under
+                // the cursor, so like vim it goes in as NUL
+                let c = if c == '\n' { '\0' } else { c };
+                self.buffer.insert(self.cx, self.buffer_line(), c);
+                self.cx += 1;
+                self.completion = None;
+            }
+            Action::InsertTab => {
+                let y = self.buffer_line();
+                if self.config.expandtab {
+                    let sw = self.config.shiftwidth.max(1) as u16;
+                    for _ in 0..sw - self.cx % sw {
+                        self.buffer.insert(self.cx, y, ' ');
+                        self.cx += 1;
+                    }
+                } else {
+                    self.buffer.insert(self.cx, y, '\t');
+                    self.cx += 1;
+                }
+                self.completion = None;
+            }
+            Action::Outdent => {
+                let removed = self
+                    .buffer
+                    .outdent_line(self.buffer_line(), self.config.shiftwidth);
+                self.cx = self.cx.saturating_sub(removed as u16);
+                self.completion = None;
+            }
+            Action::DeleteCharAtCursorPos => {
+                let soft_tab = self.soft_tab_before_cursor();
+                if soft_tab > 1 {
+                    for _ in 0..soft_tab {
+                        self.cx -= 1;
+                        self.buffer.remove(self.cx, self.buffer_line());
+                    }
+                } else if self.cx > 0 {
+                    self.cx -= 1;
+                    self.buffer.remove(self.cx, self.buffer_line());
+                } else if self.buffer_line() > 0 {
+                    self.cy = self.cy.saturating_sub(1);
+
+                    self.cx = self.line_length();
+
+                    if self.cx > 0 {
+                        self.cx -= 1;
+                        self.buffer.remove(self.cx, self.buffer_line());
+                    }
+                }
+                if self.completion.as_ref().is_some_and(|c| self.cx < c.start) {
+                    self.completion = None;
+                }
+                self.clamp_completion();
+            }
+            Action::DeleteWordBackward | Action::DeleteWordForward | Action::DeleteToLineStart => {
+                let (x, y) = (self.cx as usize, self.buffer_line() as usize);
+                let boundary = match action {
+                    Action::DeleteToLineStart => 0,
+                    _ => {
+                        let forward = matches!(action, Action::DeleteWordForward);
+                        self.buffer.word_boundary(x, y, forward)
+                    }
+                };
+                let (start, end) = (x.min(boundary), x.max(boundary));
+                if start < end {
+                    let text = self.buffer.delete_range((start, y), (end, y));
+                    self.set_register(text, RegisterKind::Charwise);
+                    self.cx = start as u16;
+                } else if deletes_back && x == 0 && y > 0 {
+                    // at the start of a line, join it to the one above
+                    let len = self.buffer.chars_on_line(y - 1);
+                    self.buffer.delete_range((len, y - 1), (0, y));
+                    self.move_to(len, y - 1);
+                }
+                if self.completion.as_ref().is_some_and(|c| self.cx < c.start) {
+                    self.completion = None;
+                }
+                self.clamp_completion();
+            }
+            Action::DeleteRestOfLine => {
+                let (x, y) = (self.cx as usize, self.buffer_line() as usize);
+                let len = self.buffer.chars_on_line(y);
+                if x < len {
+                    let text = self.buffer.delete_range((x, y), (len, y));
+                    self.set_register(text, RegisterKind::Charwise);
+                } else if y + 1 < self.buffer.len() {
+                    self.buffer.replace((len, y), (0, y + 1), "");
+                }
+            }
+            Action::NewLine => {
+                self.cy += 1;
+                self.cx = 0;
+            }
+            Action::OpenPalette => {
+                self.palette = Some(Palette::commands());
+            }
+            Action::ShowFileInfo => {
+                let name = self.buffer.file.clone();
+                self.set_message(self.file_info(name.as_deref()));
+            }
+            Action::FindFile => {
+                self.palette = Some(Palette::files());
+                self.finder = Some(finder::find_files(self.root.clone(), self.config.gitignore));
+            }
+            Action::Hover => {
+                self.hover();
+            }
+            Action::PromptRename => {
+                self.mode = Mode::Command;
+                self.command = format!("rename {}", self.word_under_cursor());
+            }
+            Action::RenameSymbol(new_name) => {
+                self.rename_symbol(&new_name);
+            }
+            Action::TriggerCompletion => {
+                self.trigger_completion();
+            }
+            Action::CompleteWord { backward } => {
+                self.complete_word(backward);
+            }
+            Action::CompleteFileName => {
+                self.complete_file_name();
+            }
+            Action::CompletionNext => {
+                self.move_completion(true);
+            }
+            Action::CompletionPrev => {
+                self.move_completion(false);
+            }
+            Action::AcceptCompletion => {
+                let paths = self.completion_source() == Some(&CompletionSource::Paths);
+                self.accept_completion();
+                // a directory goes on to complete the names inside it
+                let y = self.buffer_line();
+                let before = self.buffer.get_range(y, self.cx.saturating_sub(1), self.cx);
+                if paths && before == "/" {
+                    self.complete_file_name();
+                }
+            }
+            Action::CancelCompletion => {
+                self.completion = None;
+            }
+            Action::Percent => match self.count {
+                Some(percent) => {
+                    let len = self.buffer.len();
+                    let line = (percent.min(100) * len / 100).clamp(1, len.max(1));
+                    let x = self.buffer.first_non_blank(line - 1);
+                    self.center_on(x, line - 1);
+                }
+                None => {
+                    let (x, y) = (self.cx as usize, self.buffer_line() as usize);
+                    if let Some((x, y)) = self.buffer.matching_bracket(x, y) {
+                        self.move_to(x, y);
+                    }
+                }
+            },
+            Action::Move(motion) => {
+                let (x, y) = self.motion_target(motion);
+                self.move_to(x, y);
+            }
+            Action::DeleteMotion(motion) => {
+                self.delete_motion(motion);
+            }
+            Action::SubstituteChar => {
+                let y = self.buffer_line();
+                let available = (self.line_length() as usize).saturating_sub(self.cx as usize);
+                for _ in 0..self.count.unwrap_or(1).min(available) {
+                    self.buffer.remove(self.cx, y);
+                }
+                self.mode = Mode::Insert;
+            }
+            Action::SubstituteLine => {
+                let y = self.buffer_line() as usize;
+                let indent = self.buffer.first_non_blank(y);
+                if y < self.buffer.len() {
+                    self.buffer.replace((indent, y), (usize::MAX, y), "");
+                }
+                self.mode = Mode::Insert;
+                self.move_to(indent, y);
+            }
+            Action::Append => {
+                self.cx = (self.cx + 1).min(self.line_length());
+                self.count_insert(false);
+                self.mode = Mode::Insert;
+            }
+            Action::OpenLine { above } => {
+                let y = self.buffer_line() as usize + usize::from(!above);
+                self.buffer.insert_line(y, String::new());
+                self.move_to(0, y);
+                self.count_insert(true);
+                self.mode = Mode::Insert;
+            }
+            Action::Change(motion) => {
+                self.delete_motion(motion);
+                self.mode = Mode::Insert;
+            }
+            Action::DeleteToLineEnd => {
+                self.delete_motion(Motion::LineEnd);
+            }
+            Action::ChangeToLineEnd => {
+                self.delete_motion(Motion::LineEnd);
+                self.mode = Mode::Insert;
+            }
+            Action::ChangeCase(op, motion) => {
+                self.change_case(op, motion);
+            }
+            Action::ToggleComment(motion) => {
+                self.toggle_comment(motion);
+            }
+            Action::PasteAfterCursor => {
+                self.paste_after_cursor();
+            }
+            Action::DeleteBlock => {
+                let (top, bottom, left, right) = self.block();
+                self.yank_block(top, bottom, left, right + 1);
+                self.buffer.delete_block(top, bottom, left, right + 1);
+                self.mode = Mode::Normal;
+                self.move_to(left, top);
+            }
+            Action::YankBlock => {
+                let (top, bottom, left, right) = self.block();
+                self.yank_block(top, bottom, left, right + 1);
+                self.mode = Mode::Normal;
+                self.move_to(left, top);
+            }
+            Action::IndentBlock => {
+                let (top, bottom, _, _) = self.block();
+                let (shiftwidth, expandtab) = (self.config.shiftwidth, self.config.expandtab);
+                for y in top..=bottom {
+                    for _ in 0..self.count.unwrap_or(1) {
+                        self.buffer.indent_line(y as u16, shiftwidth, expandtab);
+                    }
+                }
+                self.mode = Mode::Normal;
+                self.move_to(self.buffer.first_non_blank(top), top);
+            }
+            Action::OutdentBlock => {
+                let (top, bottom, _, _) = self.block();
+                for y in top..=bottom {
+                    for _ in 0..self.count.unwrap_or(1) {
+                        self.buffer.outdent_line(y as u16, self.config.shiftwidth);
+                    }
+                }
+                self.mode = Mode::Normal;
+                self.move_to(self.buffer.first_non_blank(top), top);
+            }
+            Action::ReselectBlock => {
+                let Some((anchor, (x, y))) = self.last_block else {
+                    return Ok(false);
+                };
+                let last = self.buffer.len().saturating_sub(1);
+                self.visual_anchor = (anchor.0, anchor.1.min(last));
+                self.mode = Mode::VisualBlock;
+                self.move_to(x, y.min(last));
+            }
+            Action::Yank(motion) => {
+                self.yank(motion);
+            }
+            Action::SwapBlockCorners => {
+                let (x, y) = self.visual_anchor;
+                self.visual_anchor = (self.cx as usize, self.buffer_line() as usize);
+                self.move_to(x, y);
+            }
+            Action::BlockInsert { append } => {
+                let (top, bottom, left, right) = self.block();
+                let col = if append { right + 1 } else { left };
+                if append {
+                    // pad the first line so the cursor can sit past its end
+                    self.buffer.insert_block(top, top, col, "", true);
+                }
+                self.block_insert = Some(BlockInsert {
+                    top,
+                    bottom,
+                    col,
+                    pad: append,
+                });
+                self.mode = Mode::Insert;
+                self.move_to(col, top);
+            }
+            Action::DeleteCurrentLine => {
+                let line = self.buffer_line();
+                if (line as usize) < self.buffer.len() {
+                    let text = self.buffer.remove_line(line) + "\n";
+                    self.set_register(text, RegisterKind::Linewise);
+                }
+                // stay on the same line number, which now holds the next line
+                let y = (line as usize).min(self.buffer.len().saturating_sub(1));
+                self.move_to(0, y);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Where `motion` would take the cursor, as `(x, y)` in buffer coordinates.
+    fn motion_target(&self, motion: Motion) -> (usize, usize) {
+        let x = self.cx as usize;
+        let y = self.buffer_line() as usize;
+        let line_len = self.buffer.chars_on_line(y);
+        match motion {
+            Motion::Up => (x, y.saturating_sub(1)),
+            Motion::Down => (x, (y + 1).min(self.buffer.len().saturating_sub(1))),
+            Motion::Left => (x.saturating_sub(1), y),
+            Motion::Right => ((x + 1).min(line_len.saturating_sub(1)), y),
+            Motion::LineStart => (0, y),
+            Motion::FirstNonBlank => (self.buffer.first_non_blank(y), y),
+            Motion::LineEnd => (line_len.saturating_sub(1), y),
+            Motion::NextParagraph => (0, self.buffer.next_blank_line(y)),
+            Motion::PrevParagraph => (0, self.buffer.prev_blank_line(y)),
+            Motion::InnerWord | Motion::AWord => (self.motion_columns(motion).0, y),
+        }
+    }
+
+    /// The lines a linewise `motion` covers, as `start..end`. Paragraph motions
+    /// are exclusive, so the blank line they stop on is left out when moving
+    /// forward; if no blank line follows, everything to the end is included.
+    fn motion_lines(&self, motion: Motion) -> (usize, usize) {
+        let y = self.buffer_line() as usize;
+        let (_, target) = self.motion_target(motion);
+        match motion {
+            Motion::NextParagraph if target > y && self.buffer.is_blank(target) => (y, target),
+            Motion::PrevParagraph => (target, y),
+            _ => (y.min(target), (y.max(target) + 1).min(self.buffer.len())),
+        }
+    }
+
+    /// The chars of the current line a characterwise `motion` covers, as
+    /// `start..end`. `$` includes the last char and `l` the one under the cursor.
+    fn motion_columns(&self, motion: Motion) -> (usize, usize) {
+        let x = self.cx as usize;
+        let line_len = self.buffer.chars_on_line(self.buffer_line() as usize);
+        let x = x.min(line_len);
+        match motion {
+            Motion::Left => (x.saturating_sub(1), x),
+            Motion::Right => (x, (x + 1).min(line_len)),
+            Motion::LineStart => (0, x),
+            Motion::FirstNonBlank => {
+                let first = self.buffer.first_non_blank(self.buffer_line() as usize);
+                (first.min(x), first.max(x))
+            }
+            Motion::InnerWord => self
+                .buffer
+                .word_object(x, self.buffer_line() as usize, false),
+            Motion::AWord => self
+                .buffer
+                .word_object(x, self.buffer_line() as usize, true),
+            _ => (x, line_len),
+        }
+    }
+
+    /// Deletes the text between the cursor and the target of `motion` into the
+    /// register: whole lines for linewise motions, chars of the current line otherwise.
+    fn delete_motion(&mut self, motion: Motion) {
+        if !motion.is_linewise() {
+            let y = self.buffer_line() as usize;
+            let (start, end) = self.motion_columns(motion);
+            if start < end {
+                let text = self.buffer.delete_range((start, y), (end, y));
+                self.set_register(text, RegisterKind::Charwise);
+            }
+            self.move_to(start, y);
+            return;
+        }
+
+        let (start, end) = self.motion_lines(motion);
+        let mut removed = String::new();
+        for _ in start..end {
+            removed.push_str(&self.buffer.remove_line(start as u16));
+            removed.push('\n');
+        }
+        if !removed.is_empty() {
+            self.set_register(removed, RegisterKind::Linewise);
+        }
+
+        let y = start.min(self.buffer.len().saturating_sub(1));
+        self.move_to(0, y);
+    }
+
+    /// Converts the case of the text `motion` covers, or of the current line.
+    fn change_case(&mut self, op: CaseOp, motion: Option<Motion>) {
+        let y = self.buffer_line() as usize;
+        match motion {
+            Some(motion) if !motion.is_linewise() => {
+                let (start, end) = self.motion_columns(motion);
+                self.buffer
+                    .transform_range(y as u16, start as u16, end as u16, op.transform());
+                self.move_to(start, y);
+            }
+            _ => {
+                let (start, end) = match motion {
+                    Some(motion) => self.motion_lines(motion),
+                    None => (y, y + 1),
+                };
+                for line in start..end {
+                    self.buffer
+                        .transform_range(line as u16, 0, u16::MAX, op.transform());
+                }
+                self.move_to(self.cx as usize, start.min(y));
+            }
+        }
+    }
+
+    /// Comments or uncomments the lines `motion` covers, with the comment
+    /// prefix of the file type.
+    fn toggle_comment(&mut self, motion: Option<Motion>) {
+        let y = self.buffer_line() as usize;
+        let filetype = self.buffer.filetype;
+        let prefix = match self.config.comments.get(filetype.name()) {
+            Some(prefix) => prefix.clone(),
+            None => match filetype.comment() {
+                Some(prefix) => prefix.to_string(),
+                None => {
+                    self.set_message(format!("No comment string for {}", filetype.name()));
+                    return;
+                }
+            },
+        };
+
+        let (start, end) = match motion {
+            Some(motion) => self.motion_lines(motion),
+            None => (y, y + 1),
+        };
+        if start < end {
+            self.buffer.toggle_comment(start, end - 1, &prefix);
+        }
+        self.move_to(self.cx as usize, start.min(y));
+    }
+
+    /// Runs `substitute` on lines `start..=end` and remembers it for `&`. An
+    /// empty pattern stands for the last one.
+    fn substitute(&mut self, start: usize, end: usize, mut substitute: Substitute) {
+        if substitute.pattern.is_empty() {
+            let Some(last) = &self.last_substitute else {
+                self.set_message("No previous substitute");
+                return;
+            };
+            substitute.pattern = last.pattern.clone();
+        }
+
+        self.save_undo_state();
+        let global = substitute.flags.contains('g');
+        let ignore_case = self.ignore_case(&substitute.pattern);
+        let (count, last) = self.buffer.substitute(
+            start,
+            end,
+            &substitute.pattern,
+            &substitute.replacement,
+            global,
+            ignore_case,
+        );
+        match last {
+            Some(y) => {
+                self.move_to(self.buffer.first_non_blank(y), y);
+                self.set_message(counted(count, "substitution"));
+            }
+            None => self.set_message(format!("Pattern not found: {}", substitute.pattern)),
+        }
+        self.last_substitute = Some(substitute);
+    }
+
+    /// Closes the change made so far for undo, before starting another.
+    fn save_undo_state(&mut self) {
+        let edits = self.buffer.take_edits();
+        let (x, y) = (self.cx as usize, self.buffer_line() as usize);
+        self.undo.checkpoint(edits, self.buffer.pos_to_offset(y, x));
+    }
+
+    /// Moves to where the cursor was before an undone or redone change, given
+    /// as a char offset: after undoing `dd`, that is on the line put back. The
+    /// edits that reversed or replayed it are not a change of their own.
+    fn restore_cursor(&mut self, offset: usize) {
+        self.buffer.take_edits();
+        let (y, x) = self.buffer.offset_to_pos(offset);
+        self.move_to(x, y);
+    }
+
+    /// Copies the text typed on the first line of a block insert to the other
+    /// lines of the block. Nothing is copied if the cursor left that line.
+    fn finish_block_insert(&mut self) {
+        let Some(insert) = self.block_insert.take() else {
+            return;
+        };
+
+        let (x, y) = (self.cx as usize, self.buffer_line() as usize);
+        if y != insert.top || x <= insert.col {
+            return;
+        }
+        let text = self.buffer.get_range(y as u16, insert.col as u16, x as u16);
+        self.buffer
+            .insert_block(y + 1, insert.bottom, insert.col, &text, insert.pad);
+        self.move_to(insert.col, insert.top);
+    }
+
+    /// Remembers where an insert with a count starts, to type its text again
+    /// when it ends.
+    fn count_insert(&mut self, lines: bool) {
+        let (col, y) = (self.cx as usize, self.buffer_line() as usize);
+        self.insert_repeat = (self.count)
+            .filter(|&count| count > 1)
+            .map(|count| InsertRepeat {
+                count,
+                col,
+                y,
+                lines,
+            });
+    }
+
+    fn finish_insert_repeat(&mut self) {
+        let Some(repeat) = self.insert_repeat.take() else {
+            return;
+        };
+
+        let (x, y) = (self.cx as usize, self.buffer_line() as usize);
+        if y != repeat.y || x <= repeat.col {
+            return;
+        }
+        let text = self.buffer.get_range(y as u16, repeat.col as u16, x as u16);
+        if repeat.lines {
+            for i in 1..repeat.count {
+                self.buffer.insert_line(y + i, text.clone());
+            }
+            self.move_to(x, y + repeat.count - 1);
+        } else {
+            let copies = text.repeat(repeat.count - 1);
+            self.buffer.replace((x, y), (x, y), &copies);
+            self.move_to(x + copies.graphemes(true).count(), y);
+        }
+    }
+
+    fn set_register(&mut self, text: String, kind: RegisterKind) {
+        self.register = Register { text, kind };
+    }
+
+    /// Yanks the text `motion` covers, or the current line, into the register,
+    /// the way [`Editor::delete_motion`] would delete it.
+    fn yank(&mut self, motion: Option<Motion>) {
+        let y = self.buffer_line() as usize;
+        match motion {
+            Some(motion) if !motion.is_linewise() => {
+                let (start, end) = self.motion_columns(motion);
+                if start < end {
+                    let text = self.buffer.get_range(y as u16, start as u16, end as u16);
+                    self.set_register(text, RegisterKind::Charwise);
+                }
+                self.move_to(start, y);
+            }
+            _ => {
+                let (start, end) = match motion {
+                    Some(motion) => self.motion_lines(motion),
+                    None => (y, (y + 1).min(self.buffer.len())),
+                };
+                let mut text = String::new();
+                for line in start..end {
+                    text.push_str(&self.buffer.get(line).unwrap_or_default());
+                    text.push('\n');
+                }
+                if !text.is_empty() {
+                    self.set_register(text, RegisterKind::Linewise);
+                }
+                if start < y {
+                    self.move_to(self.cx as usize, start);
+                }
+            }
+        }
+    }
+
+    /// Yanks columns `left..right` of lines `top..=bottom` into the register
+    /// as a block. Lines too short to reach the block give shorter rows.
+    fn yank_block(&mut self, top: usize, bottom: usize, left: usize, right: usize) {
+        let rows: Vec<String> = (top..=bottom.min(self.buffer.len().saturating_sub(1)))
+            .map(|y| self.buffer.get_range(y as u16, left as u16, right as u16))
+            .collect();
+        self.set_register(rows.join("\n"), RegisterKind::Blockwise);
+    }
+
+    /// Puts the register after the cursor: linewise content goes on new lines
+    /// below the current one, a block goes in as a rectangle after the cursor
+    /// column, and anything else is spliced in after the cursor.
+    fn paste_after_cursor(&mut self) {
+        if self.register.text.is_empty() {
+            return;
+        }
+
+        let y = self.buffer_line() as usize;
+        let line_len = self.buffer.chars_on_line(y);
+        let text = self.register.text.clone();
+        match self.register.kind {
+            RegisterKind::Linewise => {
+                let text = text.strip_suffix('\n').unwrap_or(&text);
+                let below = if self.buffer.len() == 0 { 0 } else { y + 1 };
+                for (i, line) in text.split('\n').enumerate() {
+                    self.buffer.insert_line(below + i, line.to_string());
+                }
+                // the cursor ends on the first non-blank of the first pasted line
+                self.move_to(self.buffer.first_non_blank(below), below);
+            }
+            RegisterKind::Charwise => {
+                let x = (self.cx as usize + 1).min(line_len);
+                self.buffer.replace((x, y), (x, y), &text);
+
+                // the cursor ends on the last pasted char, or on the first when
+                // the text spans lines
+                match text.split('\n').count() {
+                    1 => self.move_to(x + text.graphemes(true).count() - 1, y),
+                    _ => self.move_to(x, y),
+                }
+            }
+            RegisterKind::Blockwise => {
+                let x = (self.cx as usize + 1).min(line_len);
+                let rows: Vec<&str> = text.split('\n').collect();
+                let width = rows
+                    .iter()
+                    .map(|row| row.graphemes(true).count())
+                    .max()
+                    .unwrap_or(0);
+                for (i, row) in rows.iter().enumerate() {
+                    let line = y + i;
+                    if line >= self.buffer.len() {
+                        self.buffer.insert_line(line, String::new());
+                    }
+                    let len = self.buffer.chars_on_line(line);
+                    // short rows are padded to keep the text after the block
+                    // aligned, and short lines to reach the block
+                    let padded = match len > x {
+                        true => format!("{row:<width$}"),
+                        false if row.is_empty() => continue,
+                        false => format!("{}{row}", " ".repeat(x - len)),
+                    };
+                    let at = x.min(len);
+                    self.buffer.replace((at, line), (at, line), &padded);
+                }
+                self.move_to(x, y);
+            }
+        }
+    }
+
+    /// With `smarttab`, the number of spaces Backspace should remove to get back
+    /// to the previous `shiftwidth` stop, if only spaces lie in between.
+    fn soft_tab_before_cursor(&self) -> usize {
+        if !self.config.smarttab || self.cx == 0 {
+            return 0;
+        }
+
+        let sw = self.config.shiftwidth.max(1);
+        let cx = self.cx as usize;
+        let stop = (cx - 1) / sw * sw;
+        let before = self
+            .buffer
+            .get_range(self.buffer_line(), stop as u16, cx as u16);
+        let all_spaces = before.chars().filter(|c| *c == ' ').count() == cx - stop;
+
+        if all_spaces {
+            cx - stop
+        } else {
+            0
+        }
+    }
+
+    /// Writes the buffer, unless the file changed on disk since it was read. In
+    /// that case the user is asked first and `retry` runs if they agree.
+    fn save(&mut self, retry: Action) -> bool {
+        if self.buffer.file.is_none() {
+            self.set_message("No file name");
+            return false;
+        }
+        if self.buffer.readonly {
+            self.set_message("'readonly' is set (add ! to override)");
+            return false;
+        }
+        if self.buffer.changed_on_disk() {
+            self.ask("file changed since reading it — overwrite? (y/n)", retry);
+            return false;
+        }
+
+        self.write()
+    }
+
+    /// `:w {file}`. A buffer without a file takes `file` as its own; otherwise
+    /// a copy is written there and the buffer keeps its file. Either way it is
+    /// saved as `:w` saves, and a file that exists is only overwritten with `!`.
+    fn write_as(&mut self, file: &str, force: bool) {
+        let same = self.buffer.file.as_deref() == Some(file);
+        if !same && !force && Path::new(file).exists() {
+            self.set_message("File exists (add ! to override)");
+            return;
+        }
+
+        let retry = Action::ExecuteCommand(format!("w! {file}"));
+        let written = if same || self.buffer.file.is_none() {
+            self.buffer.file = Some(file.to_string());
+            if force {
+                self.write()
+            } else {
+                self.save(retry)
+            }
+        } else {
+            let swap_written = self.swap_written;
+            let target = self.buffer.retarget(file);
+            let written = if force {
+                self.write()
+            } else {
+                self.save(retry)
+            };
+            self.buffer.restore_target(target);
+            self.swap_written = swap_written;
+            self.load_git_gutter();
+            written
+        };
+        if written {
+            self.set_message(format!("\"{file}\" written"));
+        }
+    }
+
+    /// Runs the pre-save hooks, then writes the buffer to its file. Returns
+    /// whether the file was written.
+    fn write(&mut self) -> bool {
+        // what the hooks change is a change of its own, which `u` takes back
+        // without what was typed before the write
+        self.save_undo_state();
+        for hook in &mut self.pre_save {
+            if let Err(err) = hook(&mut self.buffer) {
+                self.set_message(format!("Not written: {err}"));
+                self.save_undo_state();
+                return false;
+            }
+        }
+        self.save_undo_state();
+        self.write_file()
+    }
+
+    /// Writes the buffer to its file as it is, and refreshes the git signs.
+    /// With `whitespace_warnings`, what it found is shown in the message line.
+    /// Returns whether the file was written.
+    fn write_file(&mut self) -> bool {
+        if let Err(err) = self.buffer.save() {
+            let file = self.buffer.file.clone().unwrap_or_default();
+            self.set_message(format!("Can't write {file}: {err}"));
+            return false;
+        }
+        self.remove_swap();
+        for hook in &mut self.post_save {
+            hook(&self.buffer);
+        }
+        self.load_git_gutter();
+
+        if self.config.whitespace_warnings {
+            let (trailing, mixed) = self.buffer.whitespace_issues();
+            let mut warnings = vec![];
+            if trailing > 0 {
+                warnings.push(format!("{trailing} with trailing whitespace"));
+            }
+            if mixed > 0 {
+                warnings.push(format!("{mixed} with mixed indent"));
+            }
+            if !warnings.is_empty() {
+                self.set_message(format!("Lines: {}", warnings.join(", ")));
+            }
+        }
+        true
+    }
+
+    /// With `autosave_interval`, writes the buffer once it has gone that long
+    /// without a change, or right away when the terminal loses focus. Buffers
+    /// without a file, read-only ones and files changed on disk since they were
+    /// read are left for an explicit `:w`. So are the pre-save hooks, so that a
+    /// formatter does not rewrite the buffer while it is being typed in.
+    fn autosave(&mut self, focus_lost: bool) {
+        let Some(interval) = self.config.autosave_interval else {
+            return;
+        };
+        let changes = self.buffer.changes();
+        if changes != self.last_change.0 && !focus_lost {
+            self.last_change = (changes, Instant::now());
+            return;
+        }
+        let writable =
+            self.buffer.file.is_some() && !self.buffer.readonly && !self.buffer.changed_on_disk();
+        let idle = focus_lost || self.last_change.1.elapsed() >= Duration::from_secs(interval);
+        if self.buffer.modified && writable && idle {
+            self.write_file();
+        }
+    }
+
+    /// Keeps the unsaved changes in the swap file, so that a crash loses little
+    /// of them. They are written again once `SWAP_INTERVAL` has gone by since
+    /// the last time, or at once with `now`.
+    fn write_swap(&mut self, now: bool) {
+        let Some(file) = self.buffer.file.as_deref() else {
+            return;
+        };
+        let changes = self.buffer.changes();
+        let due = now || self.swap_written.1.elapsed() >= SWAP_INTERVAL;
+        if !self.buffer.modified || changes == self.swap_written.0 || !due {
+            return;
+        }
+        if let Err(err) = swap::write(file, &self.buffer.text()) {
+            log!("swap: can't write the swap file of {file}: {err}");
+        }
+        self.swap_written = (changes, Instant::now());
+    }
+
+    /// Removes the swap file, once its changes are saved or given up.
+    fn remove_swap(&mut self) {
+        if let Some(file) = &self.buffer.file {
+            swap::remove(file);
+        }
+        self.swap_written.0 = self.buffer.changes();
+    }
+
+    /// Offers to recover the changes in a swap file written after the file,
+    /// which an editor that crashed or was killed leaves behind.
+    fn check_swap(&mut self) {
+        let Some(file) = self.buffer.file.as_deref() else {
+            return;
+        };
+        if swap::newer(file).is_some() {
+            self.ask(
+                "swap file newer than the file found — recover? (y/n)",
+                Action::RecoverSwap,
+            );
+        }
+    }
+
+    /// Reads the file again, dropping unsaved changes, and keeps the cursor on
+    /// the same line if it still exists.
+    fn reload(&mut self) {
+        if let Some(file) = self.buffer.file.clone() {
+            let buffer = match Buffer::from_file(Some(file.clone())) {
+                Result::Ok(buffer) => buffer,
+                Err(err) => {
+                    self.set_message(format!("Can't open {file}: {err}"));
+                    return;
+                }
+            };
+            self.undo.clear();
+            self.folds.clear();
+            self.remove_swap();
+            self.buffer = buffer;
+            self.buffer.tabstop = self.config.tabstop;
+            self.signs = Signs::default();
+            self.load_git_gutter();
+            let y = (self.buffer_line() as usize).min(self.buffer.len().saturating_sub(1));
+            self.move_to(self.cx as usize, y);
+        }
+    }
+
+    /// Whether the buffer may be changed. A read-only one may not, which is
+    /// shown in the message line.
+    fn modifiable(&mut self) -> bool {
+        if self.buffer.readonly {
+            self.set_message("cannot modify, 'readonly' is set");
+        }
+        !self.buffer.readonly
+    }
+
+    /// The file info Ctrl-g and `:file` show, like vim's:
+    /// `"name" [Modified] line 3 of 40 --7%--`.
+    fn file_info(&self, name: Option<&str>) -> String {
+        let name = name.unwrap_or("[No Name]");
+        let modified = if self.buffer.modified {
+            " [Modified]"
+        } else {
+            ""
+        };
+        let len = self.buffer.len().max(1);
+        let line = (self.buffer_line() as usize + 1).min(len);
+        format!(
+            "\"{name}\"{modified} line {line} of {len} --{}%--",
+            self.percent()
+        )
+    }
+
+    /// How far through the buffer the cursor line is, in percent.
+    fn percent(&self) -> usize {
+        let len = self.buffer.len().max(1);
+        let through = self
+            .buffer
+            .len_lines_in_range(0, self.buffer_line() as usize + 1);
+        through * 100 / len
+    }
+
+    /// Shows `message` in the message line for a while.
+    fn set_message(&mut self, message: impl Into<String>) {
+        self.message = Some(message.into());
+        self.message_time = Instant::now();
+    }
+
+    /// Shows a yes/no question in the message line; `y` runs `action`.
+    fn ask(&mut self, question: &str, action: Action) {
+        self.set_message(question);
+        self.confirm = Some(action);
+    }
+
+    /// Applies `:set option=value`, and `:set name`, `:set noname` or `:set name?`
+    /// for the on/off options.
+    fn set_option(&mut self, args: &str) {
+        let (option, value) = args.split_once('=').unwrap_or((args, ""));
+        match option {
+            "ft" | "filetype" if value.is_empty() => {
+                self.set_message(format!("filetype={}", self.buffer.filetype.name()));
+            }
+            "ft" | "filetype" => match FileType::from_name(value) {
+                Some(filetype) => self.buffer.filetype = filetype,
+                None => self.set_message(format!("Unknown filetype: {value}")),
+            },
+            "cc" | "colorcolumn" | "cc?" | "colorcolumn?" if !args.contains('=') => {
+                let column = self.config.colorcolumn.map(|c| c.to_string());
+                self.set_message(format!("colorcolumn={}", column.unwrap_or_default()));
+            }
+            "cc" | "colorcolumn" => match value {
+                "" => self.config.colorcolumn = None,
+                _ => match value.parse() {
+                    Result::Ok(column) => self.config.colorcolumn = Some(column),
+                    Err(_) => self.set_message(format!("Invalid argument: {args}")),
+                },
+            },
+            _ => {
+                let name = option.trim_end_matches('?');
+                let name = name.strip_prefix("no").unwrap_or(name);
+                let Some(flag) = self.flag_option(name) else {
+                    self.set_message(format!("Unknown option: {option}"));
+                    return;
+                };
+                match option {
+                    _ if option.ends_with('?') => {
+                        let prefix = if *flag { "" } else { "no" };
+                        self.set_message(format!("{prefix}{name}"));
+                    }
+                    _ => *flag = !option.starts_with("no"),
+                }
+            }
+        }
+    }
+
+    /// The on/off options, set with `:set name` and `:set noname`.
+    fn flag_option(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            #[cfg(feature = "regex-search")]
+            "magic" => Some(&mut self.magic),
+            "ignorecase" | "ic" => Some(&mut self.config.ignorecase),
+            "smartcase" | "scs" => Some(&mut self.config.smartcase),
+            "cursorline" | "cul" => Some(&mut self.config.cursorline),
+            _ => None,
+        }
+    }
+
+    /// Runs an ex command typed on the command line (without the leading `:`).
+    fn execute_command(&mut self, command: &str) -> anyhow::Result<bool> {
+        let current = self.buffer_line() as usize;
+        let last = self.buffer.len().saturating_sub(1);
+        let (range, command) = parse_range(command.trim(), current, last);
+        let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+        let args = args.trim();
+
+        let substitute = parse_substitute(command);
+        let changes = substitute.is_some()
+            || matches!(
+                name,
+                "sort" | "sort!" | "retab" | "retab!" | "s" | "substitute" | "&" | "&&"
+            );
+        if changes && !self.modifiable() {
+            return Ok(false);
+        }
+        if let Some(substitute) = substitute {
+            let (start, end) = range.unwrap_or((current, current));
+            self.substitute(start, end, substitute);
+            return Ok(false);
+        }
+
+        match name {
+            "" => Ok(false),
+            "s" | "substitute" | "&" | "&&" => {
+                let Some(last) = self.last_substitute.clone() else {
+                    self.set_message("No previous substitute");
+                    return Ok(false);
+                };
+                // `:&&` keeps the flags of the last substitute, the others drop them
+                let flags = match name {
+                    "&&" => format!("{}{args}", last.flags),
+                    _ => args.to_string(),
+                };
+                let (start, end) = range.unwrap_or((current, current));
+                self.substitute(start, end, Substitute { flags, ..last });
+                Ok(false)
+            }
+            "sort" | "sort!" => {
+                self.save_undo_state();
+                let (start, end) = range.unwrap_or((0, last));
+                let count = self.buffer.sort_lines(
+                    start as u16,
+                    end as u16,
+                    name.ends_with('!'),
+                    args.contains('u'),
+                    args.contains('n'),
+                );
+                self.set_message(format!("{count} lines sorted"));
+                Ok(false)
+            }
+            "retab" | "retab!" => {
+                self.save_undo_state();
+                let (start, end) = range.unwrap_or((0, last));
+                let to_spaces = !name.ends_with('!');
+                let count =
+                    self.buffer
+                        .retab(start as u16, end as u16, to_spaces, self.config.tabstop);
+                self.set_message(format!("{count} lines changed"));
+                Ok(false)
+            }
+            "set" | "se" => {
+                self.set_option(args);
+                Ok(false)
+            }
+            "e" | "edit" | "e!" | "edit!" => {
+                let force = name.ends_with('!');
+                if self.buffer.modified && !force {
+                    self.set_message("No write since last change (add ! to override)");
+                    return Ok(false);
+                }
+                if !args.is_empty() {
+                    self.open(args);
+                } else if self.buffer.file.is_some() {
+                    self.reload();
+                } else {
+                    self.set_message("No file name");
+                }
+                Ok(false)
+            }
+            "view" | "view!" => {
+                if !args.is_empty() {
+                    if self.buffer.modified && !name.ends_with('!') {
+                        self.set_message("No write since last change (add ! to override)");
+                        return Ok(false);
+                    }
+                    if !self.open(args) {
+                        return Ok(false);
+                    }
+                }
+                self.buffer.readonly = true;
+                Ok(false)
+            }
+            "w" | "write" | "w!" | "write!" if !args.is_empty() => {
+                self.write_as(args, name.ends_with('!'));
+                Ok(false)
+            }
+            "vim" | "vimgrep" => {
+                self.vimgrep(args);
+                Ok(false)
+            }
+            "cn" | "cnext" | "cp" | "cprev" | "cN" | "cNext" | "cc" => {
+                let entry = match name {
+                    "cc" => self.quickfix.current(),
+                    "cn" | "cnext" => self.quickfix.next(),
+                    _ => self.quickfix.prev(),
+                };
+                match entry.map(|(i, entry)| (i, entry.clone())) {
+                    Some((i, entry)) => {
+                        if self.jump_to_entry(&entry) {
+                            let count = self.quickfix.len();
+                            self.set_message(format!("({} of {count}): {}", i + 1, entry.text));
+                        }
+                    }
+                    None if self.quickfix.len() == 0 => {
+                        self.set_message("No matches");
+                    }
+                    None => self.set_message("No more items"),
+                }
+                Ok(false)
+            }
+            "f" | "file" => {
+                let path = self.buffer.file.as_deref().map(|file| {
+                    std::fs::canonicalize(file)
+                        .map(|p| p.display().to_string())
+                        .unwrap_or_else(|_| file.to_string())
+                });
+                self.set_message(self.file_info(path.as_deref()));
+                Ok(false)
+            }
+            "rename" | "Rename" => {
+                if args.is_empty() {
+                    self.set_message("Usage: :rename <new_name>");
+                    return Ok(false);
+                }
+                self.execute(Action::RenameSymbol(args.to_string()))
+            }
+            "w" | "write" => self.execute(Action::Save),
+            "w!" | "write!" => {
+                self.write();
+                Ok(false)
+            }
+            "checktime" => {
+                if self.buffer.changed_on_disk() {
+                    self.ask("file changed on disk — reload? (y/n)", Action::ReloadFile);
+                } else {
+                    self.set_message("file unchanged on disk");
+                }
+                Ok(false)
+            }
+            "q" | "quit" => {
+                if self.buffer.modified {
+                    self.set_message("No write since last change (add ! to override)");
+                    return Ok(false);
+                }
+                self.execute(Action::Quit)
+            }
+            "q!" | "quit!" => self.execute(Action::Quit),
+            "wq" => {
+                if !self.save(Action::ExecuteCommand("wq!".to_string())) {
+                    return Ok(false);
+                }
+                self.execute(Action::Quit)
+            }
+            "wq!" => {
+                if !self.write() {
+                    return Ok(false);
+                }
+                self.execute(Action::Quit)
+            }
+            "x" | "exit" => {
+                if self.buffer.modified && !self.save(Action::ExecuteCommand("x!".to_string())) {
+                    return Ok(false);
+                }
+                self.execute(Action::Quit)
+            }
+            "x!" | "exit!" => {
+                if self.buffer.modified && !self.write() {
+                    return Ok(false);
+                }
+                self.execute(Action::Quit)
+            }
+            _ => {
+                self.set_message(format!("Not an editor command: {command}"));
+                Ok(false)
+            }
+        }
+    }
+
+    fn handle_event(&mut self, ev: event::Event) -> anyhow::Result<Option<Action>> {
+        if let event::Event::Resize(width, height) = ev {
+            self.resize(width, height)?;
+            return Ok(None);
+        }
+        if let event::Event::FocusLost = ev {
+            self.autosave(true);
+            return Ok(None);
+        }
+        if let event::Event::Key(event) = ev {
+            self.message = None;
+            if let Some(action) = self.confirm.take() {
+                let yes = matches!(event.code, event::KeyCode::Char('y' | 'Y'));
+                return Ok(yes.then_some(action));
+            }
+            if self.popup.take().is_some() {
+                return Ok(None);
+            }
+            if self.palette.is_some() {
+                return Ok(self.handle_palette_event(event));
+            }
+        }
+        match self.mode {
+            Mode::Normal => self.handle_normal_event(ev),
+            Mode::VisualBlock => Ok(self.handle_visual_block_event(ev)),
+            Mode::Insert => self.handle_insert_event(ev),
+            Mode::Command | Mode::Search => self.handle_command_event(ev),
+        }
+    }
+
+    /// Adds the files the finder has sent since last time to its palette. The
+    /// walk is dropped, and so stops, once the palette is closed.
+    fn receive_files(&mut self) {
+        let Some(receiver) = &self.finder else {
+            return;
+        };
+        let Some(palette) = &mut self.palette else {
+            self.finder = None;
+            return;
+        };
+        let mut files = vec![];
+        let done = loop {
+            match receiver.try_recv() {
+                Result::Ok(batch) => {
+                    files.extend(batch.iter().map(|file| from_root(&self.root, file)))
+                }
+                Err(TryRecvError::Empty) => break false,
+                Err(TryRecvError::Disconnected) => break true,
+            }
+        };
+        if !files.is_empty() {
+            palette.add_files(files);
+        }
+        if done {
+            self.finder = None;
+        }
+    }
+
+    /// Keys in the command palette: typing filters the list, Up and Down (or
+    /// Ctrl-p and Ctrl-n) select, Enter runs the selected command and Esc closes.
+    fn handle_palette_event(&mut self, event: event::KeyEvent) -> Option<Action> {
+        let palette = self.palette.as_mut()?;
+        let ctrl = event.modifiers.contains(event::KeyModifiers::CONTROL);
+        match event.code {
+            event::KeyCode::Esc => self.palette = None,
+            event::KeyCode::Char('c') if ctrl => self.palette = None,
+            event::KeyCode::Enter => return self.palette.take()?.selected_action(),
+            event::KeyCode::Up | event::KeyCode::BackTab => palette.select_prev(),
+            event::KeyCode::Char('p') if ctrl => palette.select_prev(),
+            event::KeyCode::Down | event::KeyCode::Tab => palette.select_next(),
+            event::KeyCode::Char('n') if ctrl => palette.select_next(),
+            event::KeyCode::Backspace => palette.pop(),
+            event::KeyCode::Char(c) if !ctrl => palette.push(c),
+            _ => {}
+        }
+        None
+    }
+
+    fn handle_normal_event(&mut self, ev: event::Event) -> anyhow::Result<Option<Action>> {
+        let event::Event::Key(event) = ev else {
+            return Ok(None);
+        };
+
+        if self.key_node == KEY_ROOT && self.count_digit(&event) {
+            return Ok(None);
+        }
+
+        let pending = self.key_node != KEY_ROOT;
+        if pending && event.code == event::KeyCode::Esc {
+            self.key_node = KEY_ROOT;
+            self.count = None;
+            return Ok(None);
+        }
+
+        match self.keymap.normal_step(self.key_node, &event) {
+            KeyResult::Action(action) => {
+                self.key_node = KEY_ROOT;
+                Ok(Some(action))
+            }
+            KeyResult::Pending(node) => {
+                self.key_node = node;
+                self.key_time = Instant::now();
+                Ok(None)
+            }
+            KeyResult::Unbound => {
+                self.key_node = KEY_ROOT;
+                self.count = None;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Adds a digit typed before a command to its count, and returns whether
+    /// the key was one. `0` on its own is not: it goes to the line start.
+    fn count_digit(&mut self, event: &event::KeyEvent) -> bool {
+        let event::KeyCode::Char(c @ '0'..='9') = event.code else {
+            return false;
+        };
+        let plain = (event.modifiers - event::KeyModifiers::SHIFT).is_empty();
+        if !plain || (c == '0' && self.count.is_none()) {
+            return false;
+        }
+        let digit = c.to_digit(10).unwrap_or_default() as usize;
+        let count = self.count.unwrap_or_default();
+        self.count = Some(count.saturating_mul(10).saturating_add(digit));
+        true
+    }
+
+    /// When `ev` does not go on with the pending key sequence but that sequence
+    /// is bound on its own, as `<Space>` is beside `<leader>f`, ends it there
+    /// and returns its action. `ev` then starts the next sequence.
+    fn end_pending_keys(&mut self, ev: &event::Event) -> Option<Action> {
+        let event::Event::Key(event) = ev else {
+            return None;
+        };
+        if !matches!(self.mode, Mode::Normal)
+            || self.key_node == KEY_ROOT
+            || event.code == event::KeyCode::Esc
+            || !matches!(
+                self.keymap.normal_step(self.key_node, event),
+                KeyResult::Unbound
+            )
+        {
+            return None;
+        }
+        let action = self.keymap.normal_value(self.key_node)?;
+        self.key_node = KEY_ROOT;
+        Some(action)
+    }
+
+    /// How much longer to wait for the next key of a pending sequence.
+    fn key_timeout(&self) -> Option<Duration> {
+        if self.key_node == KEY_ROOT {
+            return None;
+        }
+        Some(KEY_TIMEOUT.saturating_sub(self.key_time.elapsed()))
+    }
+
+    /// Keys in visual block mode: the edits act on the block, and normal-mode
+    /// motions extend it.
+    fn handle_visual_block_event(&mut self, ev: event::Event) -> Option<Action> {
+        let event::Event::Key(event) = ev else {
+            return None;
+        };
+        if self.count_digit(&event) {
+            return None;
+        }
+
+        let ctrl = event.modifiers.contains(event::KeyModifiers::CONTROL);
+        match event.code {
+            event::KeyCode::Esc => Some(Action::EnterMode(Mode::Normal)),
+            event::KeyCode::Char('v') if ctrl => Some(Action::EnterMode(Mode::Normal)),
+            event::KeyCode::Char('d' | 'x') => Some(Action::DeleteBlock),
+            event::KeyCode::Char('y') => Some(Action::YankBlock),
+            event::KeyCode::Char('I') => Some(Action::BlockInsert { append: false }),
+            event::KeyCode::Char('A') => Some(Action::BlockInsert { append: true }),
+            event::KeyCode::Char('o') => Some(Action::SwapBlockCorners),
+            event::KeyCode::Char('>') => Some(Action::IndentBlock),
+            event::KeyCode::Char('<') => Some(Action::OutdentBlock),
+            event::KeyCode::Char('/') => Some(Action::EnterMode(Mode::Search)),
+            _ => self.keymap.normal(&event).filter(|action| {
+                matches!(
+                    action,
+                    Action::MoveUp
+                        | Action::MoveDown
+                        | Action::MoveLeft
+                        | Action::MoveRight
+                        | Action::MoveBackward
+                        | Action::MoveForward
+                        | Action::MoveToLineStart
+                        | Action::MoveToLineEnd
+                        | Action::SmartHome
+                        | Action::Move(_)
+                        | Action::GoToTop
+                        | Action::GoToBottom
+                        | Action::PageUp
+                        | Action::PageDown
+                        | Action::HalfPageUp
+                        | Action::HalfPageDown
+                        | Action::ScrollDown
+                        | Action::ScrollUp
+                )
+            }),
+        }
+    }
+
+    fn handle_insert_event(&mut self, ev: event::Event) -> anyhow::Result<Option<Action>> {
+        if let (event::Event::Key(event), Some(literal)) = (&ev, self.literal.take()) {
+            let (c, done) = self.literal_key(literal, event);
+            let insert = c.map(Action::InsertLiteral);
+            if done {
+                return Ok(insert);
+            }
+            // the key that ended a code goes on to do what it does
+            self.apply(insert)?;
+        }
+
+        if let event::Event::Key(event) = &ev {
+            if let Some(completion) = &self.completion {
+                if let Some(action) = self.completion_key(event) {
+                    return Ok(Some(action));
+                }
+                // typing on keeps the word completed so far
+                if completion.source == CompletionSource::Words {
+                    self.completion = None;
+                }
+            }
+
+            let ctrl = event.modifiers.contains(event::KeyModifiers::CONTROL);
+            if std::mem::take(&mut self.ctrl_x_pending)
+                && ctrl
+                && event.code == event::KeyCode::Char('f')
+            {
+                return Ok(Some(Action::CompleteFileName));
+            }
+            if ctrl && event.code == event::KeyCode::Char('x') {
+                self.ctrl_x_pending = true;
+                return Ok(None);
+            }
+            if ctrl && event.code == event::KeyCode::Char('v') {
+                self.literal = Some(Literal::Key);
+                return Ok(None);
+            }
+        }
+
+        let action = match ev {
+            event::Event::Key(event) => match event.code {
+                // terminals report Ctrl-Space either as Ctrl+' ' or as NUL
+                event::KeyCode::Char(' ')
+                    if event.modifiers.contains(event::KeyModifiers::CONTROL) =>
+                {
+                    Some(Action::TriggerCompletion)
+                }
+                event::KeyCode::Null => Some(Action::TriggerCompletion),
+                event::KeyCode::Char('n')
+                    if event.modifiers.contains(event::KeyModifiers::CONTROL) =>
+                {
+                    Some(Action::CompleteWord { backward: false })
+                }
+                event::KeyCode::Char('p')
+                    if event.modifiers.contains(event::KeyModifiers::CONTROL) =>
+                {
+                    Some(Action::CompleteWord { backward: true })
+                }
+                event::KeyCode::Char('w')
+                    if event.modifiers.contains(event::KeyModifiers::CONTROL) =>
+                {
+                    Some(Action::DeleteWordBackward)
+                }
+                event::KeyCode::Char('u')
+                    if event.modifiers.contains(event::KeyModifiers::CONTROL) =>
+                {
+                    Some(Action::DeleteToLineStart)
+                }
+                event::KeyCode::Char('k')
+                    if event.modifiers.contains(event::KeyModifiers::CONTROL) =>
+                {
+                    Some(Action::DeleteRestOfLine)
+                }
+                event::KeyCode::Delete
+                    if event.modifiers.contains(event::KeyModifiers::CONTROL) =>
+                {
+                    Some(Action::DeleteWordForward)
+                }
+                event::KeyCode::Char('d') if event.modifiers.contains(event::KeyModifiers::ALT) => {
+                    Some(Action::DeleteWordForward)
+                }
+                event::KeyCode::Esc => Some(Action::EnterMode(Mode::Normal)),
+                event::KeyCode::Char(c) => Some(Action::InsertCharAtCursorPos(c)),
+                event::KeyCode::Backspace => Some(Action::DeleteCharAtCursorPos),
+                event::KeyCode::Tab => Some(Action::InsertTab),
+                event::KeyCode::BackTab => Some(Action::Outdent),
+                event::KeyCode::Enter => Some(Action::NewLine),
+                event::KeyCode::Up => Some(Action::MoveUp),
+                event::KeyCode::Down => Some(Action::MoveDown),
+                event::KeyCode::Left => Some(Action::MoveLeft),
+                event::KeyCode::Right => Some(Action::MoveRight),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        Ok(action)
+    }
+
+    /// Keys that drive the completion menu while it is open.
+    fn completion_key(&self, event: &event::KeyEvent) -> Option<Action> {
+        let ctrl = event.modifiers.contains(event::KeyModifiers::CONTROL);
+        match event.code {
+            event::KeyCode::Char('n') if ctrl => Some(Action::CompletionNext),
+            event::KeyCode::Char('p') if ctrl => Some(Action::CompletionPrev),
+            event::KeyCode::Down => Some(Action::CompletionNext),
+            event::KeyCode::Up => Some(Action::CompletionPrev),
+            event::KeyCode::Tab | event::KeyCode::Enter => Some(Action::AcceptCompletion),
+            event::KeyCode::Esc => Some(Action::CancelCompletion),
+            _ => None,
+        }
+    }
+
+    /// Handles keys typed on the `:` command line and the `/` search prompt.
+    fn handle_command_event(&mut self, ev: event::Event) -> anyhow::Result<Option<Action>> {
+        let searching = matches!(self.mode, Mode::Search);
+        if let event::Event::Key(event) = ev {
+            if event.code == event::KeyCode::Tab && !searching {
+                self.complete_command_path();
+                return Ok(None);
+            }
+            self.path_completion = None;
+
+            if std::mem::take(&mut self.register_pending) {
+                self.insert_register(event);
+                if searching {
+                    self.preview_search();
+                }
+                return Ok(None);
+            }
+            if event.code == event::KeyCode::Char('r')
+                && event.modifiers.contains(event::KeyModifiers::CONTROL)
+            {
+                self.register_pending = true;
+                return Ok(None);
+            }
+        }
+
+        let history = if searching {
+            &mut self.search_history
+        } else {
+            &mut self.command_history
+        };
+
+        let action = match ev {
+            event::Event::Key(event) => match event.code {
+                event::KeyCode::Esc => {
+                    history.reset();
+                    if searching {
+                        (self.vtop, self.cx, self.cy) = self.search_origin;
+                    }
+                    // returned before the preview below, which would go back
+                    // to the match
+                    return Ok(match self.mode_after_prompt() {
+                        // entering it again would drop the block
+                        Mode::VisualBlock => {
+                            self.command.clear();
+                            self.mode = Mode::VisualBlock;
+                            None
+                        }
+                        mode => Some(Action::EnterMode(mode)),
+                    });
+                }
+                event::KeyCode::Enter => {
+                    let command = std::mem::take(&mut self.command);
+                    history.push(&command);
+                    self.mode = self.mode_after_prompt();
+                    if searching {
+                        None
+                    } else {
+                        Some(Action::ExecuteCommand(command))
+                    }
+                }
+                event::KeyCode::Up => {
+                    if let Some(entry) = history.prev(&self.command) {
+                        self.command = entry.to_string();
+                    }
+                    None
+                }
+                event::KeyCode::Down => {
+                    if let Some(entry) = history.next() {
+                        self.command = entry.to_string();
+                    }
+                    None
+                }
+                event::KeyCode::Backspace => {
+                    if self.command.pop().is_none() {
+                        history.reset();
+                        if searching {
+                            (self.vtop, self.cx, self.cy) = self.search_origin;
+                        }
+                        self.mode = self.mode_after_prompt();
+                    }
+                    None
+                }
+                event::KeyCode::Char(c) => {
+                    self.command.push(c);
+                    None
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+
+        if matches!(self.mode, Mode::Search) {
+            self.preview_search();
+        }
+
+        Ok(action)
+    }
+
+    /// The mode to go back to when the command line closes: visual block for a
+    /// search started there, normal otherwise.
+    fn mode_after_prompt(&mut self) -> Mode {
+        let visual = matches!(self.mode, Mode::Search) && std::mem::take(&mut self.visual_search);
+        if visual {
+            Mode::VisualBlock
+        } else {
+            Mode::Normal
+        }
+    }
+
+    /// Reads a key typed after Ctrl-v in insert mode: the key itself, or a
+    /// digit of a decimal code, or `u`/`U` and the hex digits of a code point.
+    /// Returns the char to insert once it is known, and whether the key was
+    /// used up, which a key that ends a code early is not.
+    fn literal_key(&mut self, literal: Literal, event: &event::KeyEvent) -> (Option<char>, bool) {
+        let ctrl = event.modifiers.contains(event::KeyModifiers::CONTROL);
+        let typed = match event.code {
+            event::KeyCode::Char(c) if !ctrl => Some(c),
+            _ => None,
+        };
+        match literal {
+            Literal::Key => {
+                let c = match event.code {
+                    event::KeyCode::Char(d) if !ctrl && d.is_ascii_digit() => {
+                        self.literal = Some(Literal::Decimal(d.to_string()));
+                        return (None, true);
+                    }
+                    event::KeyCode::Char(u @ ('u' | 'U')) if !ctrl => {
+                        let max = if u == 'u' { 4 } else { 8 };
+                        let digits = String::new();
+                        self.literal = Some(Literal::Hex { digits, max });
+                        return (None, true);
+                    }
+                    // Ctrl-a is 1, Ctrl-[ is Esc
+                    event::KeyCode::Char(c) if ctrl && c.is_ascii() => {
+                        Some(char::from(c.to_ascii_uppercase() as u8 & 0x1f))
+                    }
+                    event::KeyCode::Char(c) => Some(c),
+                    event::KeyCode::Tab => Some('\t'),
+                    event::KeyCode::Enter => Some('\r'),
+                    event::KeyCode::Esc => Some('\x1b'),
+                    event::KeyCode::Backspace => Some('\x08'),
+                    event::KeyCode::Null => Some('\0'),
+                    _ => None,
+                };
+                (c, true)
+            }
+            Literal::Decimal(digits) => {
+                let code = |digits: &str| digits.parse::<u8>().ok().map(char::from);
+                let longer = typed
+                    .filter(char::is_ascii_digit)
+                    .map(|d| format!("{digits}{d}"));
+                match longer {
+                    Some(longer) if code(&longer).is_some() => {
+                        if longer.len() == 3 {
+                            return (code(&longer), true);
+                        }
+                        self.literal = Some(Literal::Decimal(longer));
+                        (None, true)
+                    }
+                    _ => (code(&digits), false),
+                }
+            }
+            Literal::Hex { mut digits, max } => {
+                let code = |digits: &str| {
+                    u32::from_str_radix(digits, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                };
+                match typed.filter(char::is_ascii_hexdigit) {
+                    Some(d) => {
+                        digits.push(d);
+                        if digits.len() == max {
+                            return (code(&digits), true);
+                        }
+                        self.literal = Some(Literal::Hex { digits, max });
+                        (None, true)
+                    }
+                    // `u` with no digits after it is just a `u`
+                    None if digits.is_empty() => (Some(if max == 4 { 'u' } else { 'U' }), false),
+                    None => (code(&digits), false),
+                }
+            }
+        }
+    }
+
+    /// Inserts the register named by the key typed after Ctrl-r into the command
+    /// line: `"` is the unnamed register, `/` the last search, `:` the last
+    /// command, and Ctrl-w the word under the cursor. Lines are joined with spaces.
+    fn insert_register(&mut self, event: event::KeyEvent) {
+        let ctrl = event.modifiers.contains(event::KeyModifiers::CONTROL);
+        let text = match event.code {
+            event::KeyCode::Char('w') if ctrl => {
+                let (vtop, cx, cy) = match self.mode {
+                    // the search preview moves the cursor, so use where it was
+                    Mode::Search => self.search_origin,
+                    _ => (self.vtop, self.cx, self.cy),
+                };
+                self.word_at(cx as usize, (vtop + cy) as usize)
+            }
+            event::KeyCode::Char('"') => self.register.text.clone(),
+            event::KeyCode::Char('/') => self.search_history.last().unwrap_or_default().to_string(),
+            event::KeyCode::Char(':') => {
+                self.command_history.last().unwrap_or_default().to_string()
+            }
+            _ => return,
+        };
+
+        let text = text.strip_suffix('\n').unwrap_or(&text).replace('\n', " ");
+        self.command.push_str(&text);
+    }
+
+    /// Moves the cursor to the first match of the pattern being typed, searching
+    /// from where the prompt was opened, so the result is visible before Enter.
+    fn preview_search(&mut self) {
+        let (vtop, cx, cy) = self.search_origin;
+        (self.vtop, self.cx, self.cy) = (vtop, cx, cy);
+
+        let origin_line = (vtop + cy) as usize;
+        if let Some((x, y)) = self.find(&self.command, cx as usize, origin_line) {
+            self.move_to(x, y);
+        }
+    }
+
+    /// The next match of `pattern` after column `x` of line `y`, wrapping
+    /// around. With `magic`, a pattern with regex special chars is a regular
+    /// expression.
+    fn find(&self, pattern: &str, x: usize, y: usize) -> Option<(usize, usize)> {
+        self.find_in(&self.buffer, pattern, x, y)
+    }
+
+    /// Like [`Editor::find`], in a buffer other than the one being edited.
+    fn find_in(
+        &self,
+        buffer: &Buffer,
+        pattern: &str,
+        x: usize,
+        y: usize,
+    ) -> Option<(usize, usize)> {
+        let ignore_case = self.ignore_case(pattern);
+        #[cfg(feature = "regex-search")]
+        if self.magic && pattern.contains(REGEX_CHARS) {
+            let (y, x, _) = buffer.search_regex(pattern, y, x, ignore_case)?;
+            return Some((x, y));
+        }
+        buffer.find(pattern, x, y, ignore_case)
+    }
+
+    /// Runs `:vimgrep /pattern/ files...`: searches the files matching any of
+    /// the globs under the current directory, the way the file finder lists
+    /// them, and fills the quickfix list with the first match on each line.
+    /// An empty pattern stands for the last search.
+    fn vimgrep(&mut self, args: &str) {
+        let Some((mut pattern, globs)) = parse_vimgrep(args) else {
+            self.set_message("Usage: :vimgrep /pattern/ files");
+            return;
+        };
+        if pattern.is_empty() {
+            pattern = self.search_history.last().unwrap_or_default().to_string();
+        }
+        if pattern.is_empty() {
+            self.set_message("No previous search");
+            return;
+        }
+
+        let files = finder::find_files(self.root.clone(), self.config.gitignore)
+            .into_iter()
+            .flatten()
+            .filter(|file| globs.iter().any(|glob| finder::path_match(glob, file)))
+            .map(|file| from_root(&self.root, &file))
+            .collect::<Vec<_>>();
+        let (mut entries, mut matched_files) = (vec![], 0);
+        for file in files {
+            // files that cannot be read are not searched
+            let Result::Ok(buffer) = Buffer::from_file(Some(file.clone())) else {
+                continue;
+            };
+            let found = entries.len();
+            // from the end of the last line, so that the first match comes first
+            let mut from = usize::MAX;
+            while let Some((col, line)) = self.find_in(&buffer, &pattern, usize::MAX, from) {
+                if from != usize::MAX && line <= from {
+                    break;
+                }
+                let text = buffer.get(line).unwrap_or_default().trim().to_string();
+                entries.push(quickfix::Entry {
+                    file: file.clone(),
+                    line,
+                    col,
+                    text,
+                });
+                from = line;
+            }
+            matched_files += usize::from(entries.len() > found);
+        }
+
+        if entries.is_empty() {
+            self.set_message(format!("Pattern not found: {pattern}"));
+            return;
+        }
+        let (count, first) = (entries.len(), entries[0].clone());
+        self.quickfix = Quickfix::new(entries);
+        if self.jump_to_entry(&first) {
+            self.set_message(format!(
+                "Found {count} match{} in {matched_files} file{}",
+                if count == 1 { "" } else { "es" },
+                if matched_files == 1 { "" } else { "s" },
+            ));
+        }
+    }
+
+    /// Opens the file of a quickfix entry, unless that would drop unsaved
+    /// changes, and puts the cursor on it. Returns whether it got there.
+    fn jump_to_entry(&mut self, entry: &quickfix::Entry) -> bool {
+        if self.buffer.file.as_deref() != Some(entry.file.as_str()) {
+            if self.buffer.modified {
+                self.set_message("No write since last change (add ! to override)");
+                return false;
+            }
+            if !self.open(&entry.file) {
+                return false;
+            }
+        }
+        let y = entry.line.min(self.buffer.len().saturating_sub(1));
+        self.move_to(entry.col, y);
+        true
+    }
+
+    /// Whether `pattern` matches regardless of case: with `ignorecase`, unless
+    /// `smartcase` is on too and the pattern has an uppercase letter.
+    fn ignore_case(&self, pattern: &str) -> bool {
+        self.config.ignorecase
+            && !(self.config.smartcase && pattern.chars().any(char::is_uppercase))
+    }
+
+    /// Places the cursor at column `x` of buffer line `y`, scrolling only when the
+    /// line is outside the viewport (and then centering it).
+    fn move_to(&mut self, x: usize, y: usize) {
+        let (vtop, vheight) = (self.vtop as usize, self.vheight() as usize);
+        if y < vtop || y >= vtop + vheight {
+            self.vtop = y.saturating_sub(vheight / 2) as u16;
+        }
+        self.cy = (y - self.vtop as usize) as u16;
+        self.cx = x as u16;
+    }
+
+    /// Goes to the first non-blank of the line the count names, counted from 1
+    /// and kept inside the buffer, or else of line `default`.
+    fn go_to_line(&mut self, default: usize) {
+        let last = self.buffer.len().saturating_sub(1);
+        let y = self
+            .count
+            .map_or(default, |n| n.saturating_sub(1))
+            .min(last);
+        self.move_to(self.buffer.first_non_blank(y), y);
+    }
+
+    /// Places the cursor at column `x` of buffer line `y` and scrolls so that
+    /// line is in the middle of the viewport.
+    fn center_on(&mut self, x: usize, y: usize) {
+        self.vtop = y.saturating_sub(self.vheight() as usize / 2) as u16;
+        self.move_to(x, y);
+    }
+
+    /// Moves to where the command line asked to start, on the first non-blank
+    /// of a line or at the first match of a pattern.
+    pub fn start_at(&mut self, position: StartPosition) {
+        let last = self.buffer.len().saturating_sub(1);
+        let y = match position {
+            StartPosition::Line(n) => n.saturating_sub(1).min(last),
+            StartPosition::LastLine => last,
+            StartPosition::Search(pattern) => {
+                self.search_history.push(&pattern);
+                // from the end, so a match at the very start is found too
+                let end = self.buffer.chars_on_line(last);
+                let Some((x, y)) = self.find(&pattern, end, last) else {
+                    self.set_message(format!("Pattern not found: {pattern}"));
+                    return;
+                };
+                self.center_on(x, y);
+                return;
+            }
+        };
+        self.center_on(self.buffer.first_non_blank(y), y);
+    }
+}
+
+impl<W: Write> Drop for Editor<W> {
     fn drop(&mut self) {
-        _ = self.stdout.flush();
-        _ = self.stdout.execute(terminal::LeaveAlternateScreen);
-        _ = terminal::disable_raw_mode();
+        _ = self.out.flush();
+        // only restore a terminal that `new` set up and `cleanup` did not
+        if terminal::is_raw_mode_enabled().unwrap_or(false) {
+            _ = self.out.execute(event::DisableFocusChange);
+            _ = self.out.execute(terminal::LeaveAlternateScreen);
+            _ = terminal::disable_raw_mode();
+        }
+        // a crash keeps the changes not written yet, for the next start to recover
+        if std::thread::panicking() && self.buffer.modified {
+            self.write_swap(true);
+            if let Some(file) = &self.buffer.file {
+                eprintln!("Unsaved changes are in {}", swap::swap_path(file).display());
+            }
+        }
+    }
+}
+
+/// `file`, a path relative to `root`, as one to open.
+fn from_root(root: &Path, file: &str) -> String {
+    match root == Path::new(".") {
+        true => file.to_string(),
+        false => root.join(file).to_string_lossy().to_string(),
+    }
+}
+
+/// `count` and `noun`, made plural unless there is one: `3 files`.
+fn counted(count: usize, noun: &str) -> String {
+    let plural = if count == 1 { "" } else { "s" };
+    format!("{count} {noun}{plural}")
+}
+
+/// Wraps `text` on word boundaries so that no line exceeds `width` characters.
+/// Words longer than `width` are split.
+fn wrap_text(text: &str, width: usize) -> Vec<String> {
+    let mut lines = vec![];
+    for paragraph in text.lines() {
+        let mut line = String::new();
+        for word in paragraph.split_whitespace() {
+            let mut word: Vec<char> = word.chars().collect();
+            while word.len() > width {
+                if !line.is_empty() {
+                    lines.push(std::mem::take(&mut line));
+                }
+                lines.push(word.drain(..width).collect());
+            }
+
+            let len = line.chars().count();
+            if len > 0 && len + 1 + word.len() > width {
+                lines.push(std::mem::take(&mut line));
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.extend(word);
+        }
+        lines.push(line);
+    }
+
+    lines
+}
+
+fn start_lsp(file: &str, filetype: FileType) -> Option<LspClient> {
+    let (command, args) = lsp::server_for(filetype)?;
+    match LspClient::start(command, args, file, filetype) {
+        Result::Ok(client) => Some(client),
+        Err(err) => {
+            log!("lsp: could not start {command}: {err}");
+            None
+        }
+    }
+}
+
+/// Shortens a path for the status line: the home directory becomes `~`, and if
+/// it is still wider than `width` the middle is replaced with an ellipsis.
+fn abbreviate_path(path: &str, width: usize) -> String {
+    let home = std::env::var("HOME").unwrap_or_default();
+    let path = match path.strip_prefix(home.as_str()) {
+        Some(rest) if !home.is_empty() && (rest.is_empty() || rest.starts_with('/')) => {
+            format!("~{rest}")
+        }
+        _ => path.to_string(),
+    };
+
+    let chars: Vec<char> = path.chars().collect();
+    if chars.len() <= width {
+        return path;
+    }
+    if width <= 1 {
+        return "…".repeat(width);
+    }
+
+    // keep more of the end, which holds the file name
+    let tail = width / 2;
+    let head = width - 1 - tail;
+    let mut short: String = chars[..head].iter().collect();
+    short.push('…');
+    short.extend(&chars[chars.len() - tail..]);
+    short
+}
+
+/// Parses a `+N`, `+` or `+/pattern` command-line argument.
+pub fn parse_start_position(arg: &str) -> Option<StartPosition> {
+    let rest = arg.strip_prefix('+')?;
+    if rest.is_empty() {
+        return Some(StartPosition::LastLine);
+    }
+    if let Some(pattern) = rest.strip_prefix('/') {
+        return Some(StartPosition::Search(pattern.to_string()));
+    }
+    rest.parse().ok().map(StartPosition::Line)
+}
+
+/// Parses `s/pattern/replacement/flags`. Any punctuation can stand in for `/`,
+/// `\/` is a literal delimiter, and the trailing delimiter may be left out.
+fn parse_substitute(command: &str) -> Option<Substitute> {
+    let rest = command
+        .strip_prefix("substitute")
+        .or_else(|| command.strip_prefix('s'))?;
+    let delimiter = rest
+        .chars()
+        .next()
+        .filter(|c| c.is_ascii_punctuation() && !matches!(c, '"' | '|' | '&' | '!'))?;
+
+    let (mut fields, mut field) = (vec![], String::new());
+    let mut chars = rest[1..].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(c) if c == delimiter => field.push(c),
+                Some(c) => {
+                    field.push('\\');
+                    field.push(c);
+                }
+                None => field.push('\\'),
+            },
+            c if c == delimiter && fields.len() < 2 => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+
+    let mut fields = fields.into_iter();
+    Some(Substitute {
+        pattern: fields.next().unwrap_or_default(),
+        replacement: fields.next().unwrap_or_default(),
+        flags: fields.next().unwrap_or_default().trim().to_string(),
+    })
+}
+
+/// Parses the arguments of `:vimgrep /pattern/ files...` into the pattern and
+/// the file globs. Any punctuation can stand in for `/`, and `\/` is a literal
+/// delimiter.
+fn parse_vimgrep(args: &str) -> Option<(String, Vec<String>)> {
+    let delimiter = args.chars().next().filter(char::is_ascii_punctuation)?;
+    let mut pattern = String::new();
+    let mut chars = args[1..].chars();
+    loop {
+        match chars.next()? {
+            '\\' => match chars.next()? {
+                c if c == delimiter => pattern.push(c),
+                c => {
+                    pattern.push('\\');
+                    pattern.push(c);
+                }
+            },
+            c if c == delimiter => break,
+            c => pattern.push(c),
+        }
+    }
+    let globs: Vec<String> = chars
+        .as_str()
+        .split_whitespace()
+        .map(String::from)
+        .collect();
+    (!globs.is_empty()).then_some((pattern, globs))
+}
+
+/// Splits a leading line range (`%`, `N`, `N,M`, with `.` and `$` as addresses)
+/// off an ex command. Lines are returned zero-based and inclusive.
+fn parse_range(command: &str, current: usize, last: usize) -> (Option<(usize, usize)>, &str) {
+    if let Some(rest) = command.strip_prefix('%') {
+        return (Some((0, last)), rest.trim_start());
+    }
+
+    let address = |s: &str| -> Option<(usize, usize)> {
+        // returns the line and how many bytes it took
+        match s.chars().next()? {
+            '.' => Some((current, 1)),
+            '$' => Some((last, 1)),
+            c if c.is_ascii_digit() => {
+                let len = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+                let n: usize = s[..len].parse().ok()?;
+                Some((n.saturating_sub(1).min(last), len))
+            }
+            _ => None,
+        }
+    };
+
+    let Some((start, len)) = address(command) else {
+        return (None, command);
+    };
+    let rest = &command[len..];
+
+    let Some(after_comma) = rest.strip_prefix(',') else {
+        return (Some((start, start)), rest.trim_start());
+    };
+    let Some((end, len)) = address(after_comma) else {
+        return (Some((start, start)), rest.trim_start());
+    };
+
+    let range = (start.min(end), start.max(end));
+    (Some(range), after_comma[len..].trim_start())
+}
+
+/// The last column the cursor can rest on in `mode`, on a line of `len`
+/// chars: the last char in normal mode, and past it in insert mode, where
+/// text is appended there.
+fn max_cursor_col(mode: &Mode, len: usize) -> usize {
+    match mode {
+        Mode::Insert => len,
+        _ => len.saturating_sub(1),
+    }
+}
+
+/// Replaces tabs with spaces up to the next multiple of `tabstop`.
+pub(crate) fn expand_tabs(line: &str, tabstop: usize) -> String {
+    if !line.contains('\t') {
+        return line.to_string();
+    }
+
+    let tabstop = tabstop.max(1);
+    let mut out = String::with_capacity(line.len());
+    let mut col = 0;
+    for c in line.chars() {
+        if c == '\t' {
+            let width = tabstop - col % tabstop;
+            out.extend(std::iter::repeat_n(' ', width));
+            col += width;
+        } else {
+            out.push(c);
+            col += 1;
+        }
+    }
+    out
+}
+
+/// Display column of column `x` in `line`, given as its graphemes, once tabs
+/// are expanded.
+fn display_col(line: &[String], x: usize, tabstop: usize) -> usize {
+    let tabstop = tabstop.max(1);
+    let mut col = 0;
+    for g in line.iter().take(x) {
+        col += if g == "\t" {
+            tabstop - col % tabstop
+        } else {
+            1
+        };
+    }
+    col + x.saturating_sub(line.len())
+}
+
+/// `line`, given as its graphemes, as it is drawn, one grapheme per screen
+/// column: tabs are expanded to spaces, and the line is cut or padded to
+/// exactly `width` cells.
+fn screen_cells(line: Vec<String>, width: usize, tabstop: usize) -> Vec<String> {
+    let tabstop = tabstop.max(1);
+    let mut cells = Vec::with_capacity(width);
+    for grapheme in line {
+        if grapheme == "\t" {
+            let spaces = tabstop - cells.len() % tabstop;
+            cells.extend(std::iter::repeat_n(" ".to_string(), spaces));
+        } else if let Some(c) = grapheme.chars().next().filter(|c| c.is_control()) {
+            // printed as they are, control chars would act on the terminal
+            cells.push(control_picture(c).to_string());
+        } else {
+            cells.push(grapheme);
+        }
+        if cells.len() >= width {
+            break;
+        }
+    }
+
+    cells.resize(width, " ".to_string());
+    cells
+}
+
+/// The symbol shown for a control char: `␁` for Ctrl-a, `␛` for Esc.
+fn control_picture(c: char) -> char {
+    match c as u32 {
+        code @ 0..=0x1f => char::from_u32(0x2400 + code).unwrap_or('?'),
+        0x7f => '\u{2421}',
+        _ => '\u{fffd}',
+    }
+}
+
+/// Swaps the case of every char, as `g~` does.
+fn toggle_case(text: &str) -> String {
+    let mut toggled = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_uppercase() {
+            toggled.extend(c.to_lowercase());
+        } else {
+            toggled.extend(c.to_uppercase());
+        }
+    }
+    toggled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{keymap::parse_keys, tempdir::TempDir};
+
+    fn editor(lines: &[&str]) -> Editor<Vec<u8>> {
+        let buffer = Buffer::from_lines(lines);
+        Editor::with_output(buffer, Config::default(), (80, 24), vec![])
+    }
+
+    fn lines(buffer: &Buffer) -> Vec<String> {
+        buffer.lines().map(String::from).collect()
+    }
+
+    /// Types `keys`, written like the keys of a mapping (`dd`, `<Esc>`,
+    /// `<C-r>`), and returns the state after the last one.
+    fn keys(editor: &mut Editor<Vec<u8>>, keys: &str) -> StepState {
+        let mut state = None;
+        for (code, modifiers) in parse_keys(keys).expect("valid key notation") {
+            let ev = event::Event::Key(event::KeyEvent::new(code, modifiers));
+            state = Some(editor.step(ev).unwrap());
+        }
+        state.expect("at least one key")
+    }
+
+    /// Runs `action` the way the main loop does, bounds check included.
+    fn run(editor: &mut Editor<Vec<u8>>, action: Action) {
+        editor.execute(action).unwrap();
+        editor.check_bounds();
+    }
+
+    fn numbered(count: usize) -> Vec<String> {
+        (1..=count).map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn shrinking_keeps_the_cursor_on_screen() {
+        let lines = numbered(100);
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let mut editor = editor(&lines);
+        editor.vtop = 10;
+        editor.cy = 20;
+
+        editor.size = (80, 12);
+        editor.fit_viewport();
+        assert_eq!(editor.vheight(), 10);
+        assert_eq!((editor.vtop, editor.cy), (21, 9));
+        assert_eq!(editor.buffer_line(), 30);
+    }
+
+    #[test]
+    fn growing_reveals_lines_above_at_the_end_of_the_buffer() {
+        let lines = numbered(30);
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let mut editor = editor(&lines);
+        editor.size = (80, 12);
+        editor.vtop = 20;
+        editor.cy = 9;
+
+        editor.size = (80, 24);
+        editor.fit_viewport();
+        assert_eq!((editor.vtop, editor.cy), (8, 21));
+        assert_eq!(editor.buffer_line(), 29);
+    }
+
+    #[test]
+    fn growing_keeps_the_top_line_inside_the_buffer() {
+        let lines = numbered(100);
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let mut editor = editor(&lines);
+        editor.vtop = 40;
+        editor.cy = 5;
+
+        editor.size = (80, 40);
+        editor.fit_viewport();
+        assert_eq!((editor.vtop, editor.cy), (40, 5));
+    }
+
+    #[test]
+    fn scrolling_by_a_line_keeps_the_cursor_line_while_in_view() {
+        let lines = numbered(30);
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let mut editor = editor(&lines);
+        editor.config.scrolloff = 2;
+        editor.cy = 5;
+
+        keys(&mut editor, "<C-e><C-e><C-e>");
+        assert_eq!((editor.vtop, editor.buffer_line()), (3, 5));
+        keys(&mut editor, "<C-e><C-e>");
+        assert_eq!((editor.vtop, editor.buffer_line()), (5, 7));
+
+        keys(&mut editor, "<C-y><C-y><C-y><C-y>");
+        assert_eq!((editor.vtop, editor.buffer_line()), (1, 7));
+        keys(&mut editor, "<C-y><C-y>");
+        assert_eq!((editor.vtop, editor.buffer_line()), (0, 7));
+
+        editor.cy = 20;
+        for _ in 0..40 {
+            keys(&mut editor, "<C-e>");
+        }
+        assert_eq!((editor.vtop, editor.buffer_line()), (27, 29));
+        keys(&mut editor, "<C-y>");
+        assert_eq!((editor.vtop, editor.buffer_line()), (26, 29));
+    }
+
+    #[test]
+    fn vertical_moves_restore_the_preferred_column() {
+        let mut editor = editor(&["a long first line", "ab", "a long third line"]);
+        editor.cx = 10;
+
+        run(&mut editor, Action::MoveDown);
+        assert_eq!((editor.cx, editor.cy), (1, 1));
+        run(&mut editor, Action::MoveDown);
+        assert_eq!((editor.cx, editor.cy), (10, 2));
+        run(&mut editor, Action::MoveUp);
+        run(&mut editor, Action::MoveUp);
+        assert_eq!((editor.cx, editor.cy), (10, 0));
+    }
+
+    #[test]
+    fn horizontal_moves_reset_the_preferred_column() {
+        let mut editor = editor(&["a long first line", "ab", "a long third line"]);
+        editor.cx = 10;
+
+        run(&mut editor, Action::MoveDown);
+        run(&mut editor, Action::MoveLeft);
+        run(&mut editor, Action::MoveDown);
+        assert_eq!((editor.cx, editor.cy), (0, 2));
+    }
+
+    #[test]
+    fn the_cursor_goes_past_the_line_end_only_in_insert_mode() {
+        let mut editor = editor(&["abc", ""]);
+        assert_eq!(keys(&mut editor, "$").cursor, (2, 0));
+        assert_eq!(keys(&mut editor, "l").cursor, (2, 0));
+        assert_eq!(keys(&mut editor, "a").cursor, (3, 0));
+        assert_eq!(keys(&mut editor, "<Right><End>").cursor, (3, 0));
+        assert_eq!(keys(&mut editor, "<Esc>").cursor, (2, 0));
+        assert_eq!(keys(&mut editor, "j$").cursor, (0, 1));
+    }
+
+    #[test]
+    fn steps_through_motions_and_edits() {
+        let mut editor = editor(&["first line", "second line", "third line"]);
+
+        assert_eq!(keys(&mut editor, "jll").cursor, (2, 1));
+        assert_eq!(keys(&mut editor, "0").cursor, (0, 1));
+
+        let state = keys(&mut editor, "dd");
+        assert_eq!(state.cursor, (0, 1));
+        assert_eq!(lines(&editor.buffer), ["first line", "third line"]);
+        assert_eq!(editor.register.text, "second line\n");
+    }
+
+    #[test]
+    fn inserts_typed_text_and_returns_to_normal_mode() {
+        let mut editor = editor(&["world"]);
+
+        let state = keys(&mut editor, "ihello ");
+        assert_eq!(state.mode, Mode::Insert);
+        assert_eq!(state.cursor, (6, 0));
+
+        let state = keys(&mut editor, "<Esc>");
+        assert_eq!(state.mode, Mode::Normal);
+        assert_eq!(lines(&editor.buffer), ["hello world"]);
+
+        assert_eq!(keys(&mut editor, "u").cursor.1, 0);
+        assert_eq!(lines(&editor.buffer), ["world"]);
+    }
+
+    #[test]
+    fn counts_apply_to_the_whole_sequence() {
+        let mut editor = editor(&["abcdef"]);
+        keys(&mut editor, "2s");
+        assert_eq!(lines(&editor.buffer), ["cdef"]);
+    }
+
+    #[test]
+    fn g_and_gg_go_to_the_line_of_their_count() {
+        let mut editor = editor(&["one", "  two", "three", "four", "five"]);
+        assert_eq!(keys(&mut editor, "G").cursor, (0, 4));
+        assert_eq!(keys(&mut editor, "gg").cursor, (0, 0));
+        assert_eq!(keys(&mut editor, "2G").cursor, (2, 1));
+        assert_eq!(keys(&mut editor, "10G").cursor, (0, 4));
+        assert_eq!(keys(&mut editor, "3gg").cursor, (0, 2));
+    }
+
+    #[test]
+    fn zq_quits() {
+        let mut editor = editor(&["text"]);
+        assert!(!keys(&mut editor, "Z").quit);
+        assert!(keys(&mut editor, "Q").quit);
+    }
+
+    #[test]
+    fn undo_and_redo_replay_whole_changes() {
+        let mut editor = editor(&["one", "two", "three"]);
+        keys(&mut editor, "jdd");
+        keys(&mut editor, "iab<Esc>");
+        assert_eq!(lines(&editor.buffer), ["one", "abthree"]);
+
+        keys(&mut editor, "u");
+        assert_eq!(lines(&editor.buffer), ["one", "three"]);
+        let state = keys(&mut editor, "u");
+        assert_eq!(lines(&editor.buffer), ["one", "two", "three"]);
+        assert_eq!(state.cursor, (0, 1));
+
+        keys(&mut editor, "<C-r><C-r>");
+        assert_eq!(lines(&editor.buffer), ["one", "abthree"]);
+        keys(&mut editor, "u");
+        assert_eq!(lines(&editor.buffer), ["one", "three"]);
+    }
+
+    #[test]
+    fn undoing_dd_from_elsewhere_goes_back_to_the_line_put_back() {
+        let mut editor = editor(&["one", "two", "three"]);
+        keys(&mut editor, "jlldd");
+        let state = keys(&mut editor, "ku");
+        assert_eq!(lines(&editor.buffer), ["one", "two", "three"]);
+        assert_eq!(state.cursor, (2, 1));
+    }
+
+    #[test]
+    fn each_append_is_undone_on_its_own() {
+        let mut editor = editor(&[""]);
+        keys(&mut editor, "ione<Esc>atwo<Esc>");
+        assert_eq!(lines(&editor.buffer), ["onetwo"]);
+        keys(&mut editor, "u");
+        assert_eq!(lines(&editor.buffer), ["one"]);
+    }
+
+    #[test]
+    fn counts_in_messages_agree_with_their_noun() {
+        assert_eq!(counted(1, "occurrence"), "1 occurrence");
+        assert_eq!(counted(2, "file"), "2 files");
+        assert_eq!(counted(0, "substitution"), "0 substitutions");
+    }
+
+    #[test]
+    fn ampersand_repeats_the_last_substitute() {
+        let mut editor = editor(&["x-x-x", "x-x-x", "x-x-x"]);
+        keys(&mut editor, "&");
+        assert_eq!(editor.message.as_deref(), Some("No previous substitute"));
+
+        keys(&mut editor, ":s/-/+/g<CR>");
+        assert_eq!(lines(&editor.buffer), ["x+x+x", "x-x-x", "x-x-x"]);
+        keys(&mut editor, "g&");
+        assert_eq!(lines(&editor.buffer), ["x+x+x", "x+x+x", "x+x+x"]);
+        assert_eq!(editor.message.as_deref(), Some("4 substitutions"));
+
+        keys(&mut editor, "u");
+        assert_eq!(lines(&editor.buffer), ["x+x+x", "x-x-x", "x-x-x"]);
+        keys(&mut editor, "j&");
+        assert_eq!(lines(&editor.buffer), ["x+x+x", "x+x-x", "x-x-x"]);
+    }
+
+    #[test]
+    fn daw_takes_every_blank_after_the_word() {
+        let mut editor = editor(&["one two   three"]);
+        keys(&mut editor, "lllldaw");
+        assert_eq!(lines(&editor.buffer), ["one three"]);
+        assert_eq!(editor.register.text, "two   ");
+        assert_eq!(editor.cx, 4);
+    }
+
+    #[test]
+    fn diw_on_blanks_deletes_just_the_blanks() {
+        let mut editor = editor(&["a   b"]);
+        keys(&mut editor, "lldiw");
+        assert_eq!(lines(&editor.buffer), ["ab"]);
+    }
+
+    #[test]
+    fn ciw_on_an_empty_line_starts_inserting() {
+        let mut editor = editor(&["", "x"]);
+        let state = keys(&mut editor, "ciw");
+        assert_eq!(state.mode, Mode::Insert);
+        keys(&mut editor, "hi<Esc>");
+        assert_eq!(lines(&editor.buffer), ["hi", "x"]);
+    }
+
+    #[test]
+    fn readonly_buffers_refuse_changes() {
+        let mut editor = editor(&["one", "two"]);
+        editor.buffer.readonly = true;
+        for keys_typed in ["dd", "i", "a", ":s/o/0/<CR>", "ciw"] {
+            let state = keys(&mut editor, keys_typed);
+            assert_eq!(state.mode, Mode::Normal);
+            assert_eq!(
+                editor.message.as_deref(),
+                Some("cannot modify, 'readonly' is set")
+            );
+        }
+        assert_eq!(lines(&editor.buffer), ["one", "two"]);
+
+        editor.buffer.file = Some("unused".to_string());
+        keys(&mut editor, ":w<CR>");
+        assert_eq!(
+            editor.message.as_deref(),
+            Some("'readonly' is set (add ! to override)")
+        );
+    }
+
+    #[test]
+    fn palette_runs_the_selected_command() {
+        let mut editor = editor(&["b", "a"]);
+        keys(&mut editor, "<C-p>sort<Esc>");
+        assert!(editor.palette.is_none());
+        assert_eq!(lines(&editor.buffer), ["b", "a"]);
+
+        keys(&mut editor, "<C-p>sor");
+        assert_eq!(editor.palette.as_ref().unwrap().labels(1), [":sort"]);
+        keys(&mut editor, "<CR>");
+        assert!(editor.palette.is_none());
+        assert_eq!(lines(&editor.buffer), ["a", "b"]);
+    }
+
+    #[test]
+    fn starts_at_the_line_or_match_asked_for() {
+        assert_eq!(parse_start_position("+"), Some(StartPosition::LastLine));
+        assert_eq!(parse_start_position("+12"), Some(StartPosition::Line(12)));
+        assert_eq!(
+            parse_start_position("+/fn main"),
+            Some(StartPosition::Search("fn main".to_string()))
+        );
+        assert_eq!(parse_start_position("file.txt"), None);
+        assert_eq!(parse_start_position("+x"), None);
+
+        let cursor = |editor: &Editor<Vec<u8>>| (editor.cx, editor.buffer_line());
+        let mut editor = editor(&["one", "  two", "three"]);
+        editor.start_at(StartPosition::Line(2));
+        assert_eq!(cursor(&editor), (2, 1));
+        editor.start_at(StartPosition::Line(99));
+        assert_eq!(cursor(&editor), (0, 2));
+        editor.start_at(StartPosition::Search("one".to_string()));
+        assert_eq!(cursor(&editor), (0, 0));
+        editor.start_at(StartPosition::Search("four".to_string()));
+        assert_eq!(editor.message.as_deref(), Some("Pattern not found: four"));
+    }
+
+    #[test]
+    fn closed_folds_show_and_move_as_one_line() {
+        let mut editor = editor(&["fn a() {", "    x", "    y", "}", "fn b() {", "    z", "}"]);
+        keys(&mut editor, "zM");
+        assert_eq!(
+            screen(&editor)[..5],
+            ["fn a() { ··· 3 lines", "}", "fn b() { ··· 2 lines", "}", ""]
+        );
+
+        keys(&mut editor, "jj");
+        assert_eq!((editor.buffer_line(), editor.screen_cy()), (4, 2));
+        keys(&mut editor, "zoj");
+        assert_eq!(screen(&editor)[3], "    z");
+        assert_eq!(editor.buffer_line(), 5);
+        keys(&mut editor, "zc");
+        assert_eq!(editor.buffer_line(), 4);
+        keys(&mut editor, "kzc");
+        assert_eq!(editor.message.as_deref(), Some("No fold found"));
+
+        // the closed folds stay closed as the lines above them go
+        keys(&mut editor, "dd");
+        assert_eq!(
+            screen(&editor)[..3],
+            ["fn a() { ··· 3 lines", "fn b() { ··· 2 lines", "}"]
+        );
+        keys(&mut editor, "zR");
+        assert_eq!(screen(&editor)[1], "    x");
+    }
+
+    #[test]
+    fn mappings_can_use_the_leader_key() {
+        let mut config = Config::default();
+        config.keys.leader = ",".to_string();
+        config
+            .keys
+            .normal
+            .insert("<leader>u".to_string(), "undo".to_string());
+        let buffer = Buffer::from_lines(&["a", "b"]);
+        let mut editor = Editor::with_output(buffer, config, (80, 24), vec![]);
+
+        keys(&mut editor, "dd");
+        assert_eq!(lines(&editor.buffer), ["b"]);
+        keys(&mut editor, ",u");
+        assert_eq!(lines(&editor.buffer), ["a", "b"]);
+        // the built-in mappings follow the leader too
+        keys(&mut editor, " f");
+        assert!(editor.palette.is_none());
+    }
+
+    #[test]
+    fn esc_cancels_a_search_where_it_started() {
+        let mut editor = editor(&["one", "two foo"]);
+        let state = keys(&mut editor, "l/foo");
+        assert_eq!(state.cursor, (4, 1));
+        let state = keys(&mut editor, "<Esc>");
+        assert_eq!((state.cursor, state.mode), ((1, 0), Mode::Normal));
+    }
+
+    #[test]
+    fn searching_from_a_visual_block_extends_it() {
+        let mut editor = editor(&["abc", "def", "ghi xyz"]);
+        keys(&mut editor, "<C-v>l/gh<Esc>");
+        assert!(matches!(editor.mode, Mode::VisualBlock));
+        assert_eq!(editor.block(), (0, 0, 0, 1));
+
+        let state = keys(&mut editor, "/xy<CR>");
+        assert!(matches!(state.mode, Mode::VisualBlock));
+        assert_eq!(editor.block(), (0, 2, 0, 4));
+        keys(&mut editor, "d");
+        assert_eq!(lines(&editor.buffer), ["", "", "yz"]);
+
+        // a search from normal mode still ends there
+        let state = keys(&mut editor, "/z<CR>");
+        assert!(matches!(state.mode, Mode::Normal));
+    }
+
+    #[test]
+    fn signs_follow_their_lines_and_git_signs_follow_edits() {
+        let mut editor = editor(&["a", "b", "c"]);
+        assert_eq!(screen(&editor)[..3], ["a", "b", "c"]);
+        editor.set_sign(2, Sign::Warning);
+        assert_eq!(screen(&editor)[..3], [" a", " b", "Wc"]);
+        keys(&mut editor, "dd");
+        assert_eq!(screen(&editor)[..3], [" b", "Wc", ""]);
+
+        let dir = TempDir::new("signs");
+        dir.write("file.txt", "one\ntwo\n");
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .args([
+                    "-c",
+                    "user.name=vigil",
+                    "-c",
+                    "user.email=vigil@example.com",
+                ])
+                .arg("-C")
+                .arg(dir.path())
+                .args(args)
+                .output()
+                .unwrap()
+                .status;
+            assert!(status.success(), "git {args:?}");
+        };
+        git(&["init", "-q"]);
+        git(&["add", "file.txt"]);
+        git(&["commit", "-q", "-m", "file"]);
+
+        let buffer = Buffer::from_file(Some(dir.file("file.txt"))).unwrap();
+        let mut editor = Editor::with_output(buffer, Config::default(), (80, 24), vec![]);
+        assert_eq!(screen(&editor)[..2], [" one", " two"]);
+        // the signs are updated once typing pauses, not on every key
+        keys(&mut editor, "jgUU");
+        assert_eq!(screen(&editor)[..2], [" one", " TWO"]);
+        editor.update_git_signs();
+        assert_eq!(screen(&editor)[..2], [" one", "~TWO"]);
+        keys(&mut editor, "u");
+        editor.update_git_signs();
+        assert_eq!(screen(&editor)[..2], [" one", " two"]);
+    }
+
+    #[test]
+    fn autosave_writes_once_the_buffer_is_left_alone() {
+        let dir = TempDir::new("autosave");
+        dir.write("file", "one\n");
+        let path = dir.join("file");
+        let buffer = Buffer::from_file(Some(dir.file("file"))).unwrap();
+        let config = Config {
+            autosave_interval: Some(0),
+            ..Config::default()
+        };
+        let mut editor = Editor::with_output(buffer, config, (80, 24), vec![]);
+
+        keys(&mut editor, "$a!<Esc>");
+        // the first look only notes that the buffer changed
+        editor.autosave(false);
+        assert!(editor.buffer.modified);
+        editor.autosave(false);
+        assert!(!editor.buffer.modified);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one!\n");
+
+        keys(&mut editor, "a?<Esc>");
+        editor.step(event::Event::FocusLost).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one!?\n");
+    }
+
+    #[test]
+    fn editing_another_file_removes_the_swap_file() {
+        let dir = TempDir::new("leftswap");
+        let file = dir.file("file");
+        dir.write("file", "one\n");
+
+        let buffer = Buffer::from_file(Some(file.clone())).unwrap();
+        let mut editor = Editor::with_output(buffer, Config::default(), (80, 24), vec![]);
+        keys(&mut editor, "a!<Esc>");
+        editor.write_swap(true);
+        assert!(swap::swap_path(&file).exists());
+        keys(&mut editor, ":e! other<CR>");
+        assert_eq!(editor.buffer.file.as_deref(), Some("other"));
+        assert!(!swap::swap_path(&file).exists());
+    }
+
+    #[test]
+    fn a_file_that_cannot_be_read_is_not_opened() {
+        let dir = TempDir::new("latin1");
+        let file = dir.file("file");
+        dir.write("file", b"caf\xe9\n");
+
+        let mut editor = editor(&["one"]);
+        keys(&mut editor, &format!(":e {file}<CR>"));
+        assert_eq!(lines(&editor.buffer), ["one"]);
+        assert_eq!(editor.buffer.file, None);
+        assert!(editor.message_line().starts_with("Can't open"));
+        assert_eq!(std::fs::read(dir.join("file")).unwrap(), b"caf\xe9\n");
+    }
+
+    #[test]
+    fn changes_left_in_a_swap_file_can_be_recovered() {
+        let dir = TempDir::new("swap");
+        let (path, file) = (dir.join("file"), dir.file("file"));
+        dir.write("file", "one\n");
+        swap::write(&file, "one\ntwo").unwrap();
+
+        let buffer = Buffer::from_file(Some(file.clone())).unwrap();
+        let mut editor = Editor::with_output(buffer, Config::default(), (80, 24), vec![]);
+        assert!(editor.message_line().contains("recover? (y/n)"));
+        keys(&mut editor, "y");
+        assert_eq!(lines(&editor.buffer), ["one", "two"]);
+        assert!(editor.buffer.modified);
+
+        keys(&mut editor, ":w<CR>");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\ntwo\n");
+        assert!(!swap::swap_path(&file).exists());
+
+        keys(&mut editor, "a!<Esc>");
+        editor.write_swap(true);
+        assert_eq!(swap::newer(&file).as_deref(), Some("o!ne\ntwo"));
+        assert!(keys(&mut editor, ":q!<CR>").quit);
+        assert!(!swap::swap_path(&file).exists());
+    }
+
+    #[test]
+    fn writing_to_another_file_keeps_the_buffer_on_its_own() {
+        let dir = TempDir::new("writeas");
+        dir.write("a.txt", "one\n");
+        dir.write("b.txt", "bee\n");
+        let buffer = Buffer::from_file(Some(dir.file("a.txt"))).unwrap();
+        let mut editor = Editor::with_output(buffer, Config::default(), (80, 24), vec![]);
+
+        keys(&mut editor, "a!<Esc>");
+        keys(&mut editor, &format!(":w {}<CR>", dir.file("b.txt")));
+        assert_eq!(
+            editor.message.as_deref(),
+            Some("File exists (add ! to override)")
+        );
+        assert_eq!(std::fs::read_to_string(dir.join("b.txt")).unwrap(), "bee\n");
+
+        keys(&mut editor, &format!(":w! {}<CR>", dir.file("b.txt")));
+        assert_eq!(
+            std::fs::read_to_string(dir.join("b.txt")).unwrap(),
+            "o!ne\n"
+        );
+        keys(&mut editor, &format!(":w {}<CR>", dir.file("c.txt")));
+        assert_eq!(
+            std::fs::read_to_string(dir.join("c.txt")).unwrap(),
+            "o!ne\n"
+        );
+        assert_eq!(editor.buffer.file, Some(dir.file("a.txt")));
+        assert!(editor.buffer.modified);
+        assert!(!editor.buffer.changed_on_disk());
+        assert_eq!(std::fs::read_to_string(dir.join("a.txt")).unwrap(), "one\n");
+
+        editor.buffer.readonly = true;
+        keys(&mut editor, &format!(":w {}<CR>", dir.file("d.txt")));
+        assert_eq!(
+            editor.message.as_deref(),
+            Some("'readonly' is set (add ! to override)")
+        );
+        assert!(!dir.join("d.txt").exists());
+
+        // a buffer without a file takes the one it is written to
+        let buffer = Buffer::from_lines(&["new"]);
+        let mut unnamed = Editor::with_output(buffer, Config::default(), (80, 24), vec![]);
+        keys(&mut unnamed, &format!(":w {}<CR>", dir.file("e.txt")));
+        assert_eq!(unnamed.buffer.file, Some(dir.file("e.txt")));
+        assert!(!unnamed.buffer.modified);
+        assert_eq!(std::fs::read_to_string(dir.join("e.txt")).unwrap(), "new");
+    }
+
+    #[test]
+    fn a_rename_reads_every_file_before_writing_any() {
+        let dir = TempDir::new("rename");
+        dir.write("a.txt", "let old = 1;\nold\n");
+        dir.write("b.txt", "use old;\n");
+        dir.write("c.txt", "old\n");
+        dir.write("d.txt", b"caf\xe9 old\n");
+        let buffer = Buffer::from_file(Some(dir.file("a.txt"))).unwrap();
+        let mut editor = Editor::with_output(buffer, Config::default(), (80, 24), vec![]);
+        editor.on_pre_save(|buffer| match buffer.file.as_deref() {
+            Some(file) if file.ends_with("c.txt") => anyhow::bail!("no"),
+            _ => Result::Ok(()),
+        });
+
+        let edit = |start, end| lsp::TextEdit {
+            start,
+            end,
+            new_text: "new".to_string(),
+        };
+        let own = vec![edit((0, 4), (0, 7)), edit((1, 0), (1, 3))];
+        let files = |others: &[&str]| {
+            let mut files = vec![(dir.file("a.txt"), own.clone())];
+            files.extend(
+                others
+                    .iter()
+                    .map(|f| (dir.file(f), vec![edit((0, 4), (0, 7))])),
+            );
+            files
+        };
+
+        editor.apply_rename(files(&["b.txt", "d.txt"]));
+        assert!(editor.message_line().starts_with("Not renamed: "));
+        assert_eq!(lines(&editor.buffer), ["let old = 1;", "old"]);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("b.txt")).unwrap(),
+            "use old;\n"
+        );
+
+        editor.apply_rename(files(&["b.txt", "c.txt"]));
+        assert_eq!(
+            editor.message.as_deref(),
+            Some(
+                format!(
+                    "Renamed 4 occurrences in 3 files; not written: {}: no",
+                    dir.file("c.txt")
+                )
+                .as_str()
+            )
+        );
+        assert_eq!(lines(&editor.buffer), ["let new = 1;", "new"]);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("b.txt")).unwrap(),
+            "use new;\n"
+        );
+        assert_eq!(std::fs::read_to_string(dir.join("c.txt")).unwrap(), "old\n");
+        keys(&mut editor, "u");
+        assert_eq!(lines(&editor.buffer), ["let old = 1;", "old"]);
+    }
+
+    #[test]
+    fn gf_opens_the_file_named_under_the_cursor_next_to_the_current_one() {
+        let dir = TempDir::new("gf");
+        dir.write("a.txt", "see sub/b.txt, or c.txt\n");
+        dir.write("sub/b.txt", "bee\n");
+
+        let file = dir.file("a.txt");
+        let buffer = Buffer::from_file(Some(file.clone())).unwrap();
+        let mut editor = Editor::with_output(buffer, Config::default(), (80, 24), vec![]);
+        keys(&mut editor, "$gf");
+        assert_eq!(editor.message.as_deref(), Some("Can't find file \"c.txt\""));
+
+        keys(&mut editor, "0llllgf");
+        assert_eq!(editor.buffer.file, Some(dir.file("sub/b.txt")));
+        assert_eq!(lines(&editor.buffer), ["bee"]);
+        keys(&mut editor, "<C-^>");
+        assert_eq!(editor.buffer.file, Some(file));
+    }
+
+    #[test]
+    fn save_hooks_run_around_a_write_and_can_stop_it() {
+        let dir = TempDir::new("hooks");
+        dir.write("file", "one\n");
+        let path = dir.join("file");
+        let buffer = Buffer::from_file(Some(dir.file("file"))).unwrap();
+        let mut editor = Editor::with_output(buffer, Config::default(), (80, 24), vec![]);
+
+        let written = std::rc::Rc::new(std::cell::Cell::new(0));
+        let count = written.clone();
+        editor.on_pre_save(|buffer| {
+            buffer.insert_line(buffer.len(), "two".to_string());
+            Result::Ok(())
+        });
+        editor.on_pre_save(|buffer| match buffer.len() > 3 {
+            true => anyhow::bail!("too long"),
+            false => Result::Ok(()),
+        });
+        editor.on_post_save(move |_| count.set(count.get() + 1));
+
+        keys(&mut editor, ":w<CR>");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\ntwo\n");
+        keys(&mut editor, ":w<CR>");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\ntwo\ntwo\n");
+        keys(&mut editor, ":w<CR>");
+        assert_eq!(editor.message.as_deref(), Some("Not written: too long"));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\ntwo\ntwo\n");
+        assert_eq!(written.get(), 2);
+    }
+
+    #[test]
+    fn format_on_save_pipes_the_buffer_through_the_formatter() {
+        let dir = TempDir::new("format");
+        dir.write("file.txt", "one\ntwo\nthree\n");
+        let path = dir.join("file.txt");
+        let buffer = Buffer::from_file(Some(dir.file("file.txt"))).unwrap();
+        let config = Config {
+            format_on_save: true,
+            formatprg: [("text".to_string(), "tr a-z A-Z".to_string())].into(),
+            autosave_interval: Some(0),
+            ..Config::default()
+        };
+        let mut editor = Editor::with_output(buffer, config, (80, 24), vec![]);
+
+        keys(&mut editor, "jl:w<CR>");
+        assert_eq!(lines(&editor.buffer), ["ONE", "TWO", "THREE"]);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "ONE\nTWO\nTHREE\n");
+        assert_eq!(editor.buffer_line(), 1);
+
+        // formatting is undone apart from what was typed, and a write with
+        // nothing to format leaves nothing to undo
+        keys(&mut editor, "$ax<Esc>:w<CR>");
+        assert_eq!(lines(&editor.buffer), ["ONE", "TWOX", "THREE"]);
+        keys(&mut editor, ":w<CR>u");
+        assert_eq!(lines(&editor.buffer), ["ONE", "TWOx", "THREE"]);
+        keys(&mut editor, "u");
+        assert_eq!(lines(&editor.buffer), ["ONE", "TWO", "THREE"]);
+
+        // autosave writes what was typed as it is
+        keys(&mut editor, "ay<Esc>");
+        editor.step(event::Event::FocusLost).unwrap();
+        assert_eq!(lines(&editor.buffer), ["ONE", "TWOy", "THREE"]);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "ONE\nTWOy\nTHREE\n"
+        );
+    }
+
+    #[test]
+    fn a_failing_formatter_stops_the_write() {
+        let dir = TempDir::new("badfmt");
+        dir.write("file.txt", "one\n");
+        let buffer = Buffer::from_file(Some(dir.file("file.txt"))).unwrap();
+        let config = Config {
+            format_on_save: true,
+            formatprg: [("text".to_string(), "echo oops >&2; exit 1".to_string())].into(),
+            ..Config::default()
+        };
+        let mut editor = Editor::with_output(buffer, config, (80, 24), vec![]);
+
+        keys(&mut editor, "a!<Esc>:w<CR>");
+        assert_eq!(lines(&editor.buffer), ["o!ne"]);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("file.txt")).unwrap(),
+            "one\n"
+        );
+        assert_eq!(
+            editor.message.as_deref(),
+            Some("Not written: echo oops >&2; exit 1: oops")
+        );
+    }
+
+    #[test]
+    fn ctrl_g_shows_the_file_and_the_cursor_line() {
+        let mut editor = editor(&["one", "two", "three"]);
+        keys(&mut editor, "j<C-g>");
+        assert_eq!(
+            editor.message.as_deref(),
+            Some("\"[No Name]\" line 2 of 3 --66%--")
+        );
+        editor.buffer.file = Some("notes.txt".to_string());
+        keys(&mut editor, "ja!<Esc><C-g>");
+        assert_eq!(
+            editor.message.as_deref(),
+            Some("\"notes.txt\" [Modified] line 3 of 3 --100%--")
+        );
+    }
+
+    #[test]
+    fn ctrl_v_inserts_the_next_key_or_a_code_literally() {
+        let mut editor = editor(&[""]);
+        editor.config.expandtab = true;
+        keys(
+            &mut editor,
+            "i<C-v><Tab><Tab><C-v><C-a><C-v>065<C-v>7x<C-v>u00e9<C-v>u2c<Esc>",
+        );
+        assert_eq!(lines(&editor.buffer), ["\t   \u{1}A\u{7}xé,"]);
+        assert_eq!(screen(&editor)[0], "           ␁A␇xé,");
+    }
+
+    #[test]
+    fn a_literal_line_break_stays_on_its_line() {
+        let mut editor = editor(&["ab"]);
+        keys(&mut editor, "li<C-v>010x<C-v><C-j><C-v><CR><Esc>");
+        assert_eq!(lines(&editor.buffer), ["a\0x\0\rb"]);
+        assert_eq!(editor.buffer_line(), 0);
+        keys(&mut editor, "u");
+        assert_eq!(lines(&editor.buffer), ["ab"]);
+    }
+
+    #[test]
+    fn a_column_is_a_whole_grapheme() {
+        let mut editor = editor(&["e\u{301}a"]);
+        keys(&mut editor, "$sb<Esc>");
+        assert_eq!(lines(&editor.buffer), ["e\u{301}b"]);
+        keys(&mut editor, "0sc<Esc>");
+        assert_eq!(lines(&editor.buffer), ["cb"]);
+        keys(&mut editor, "uu$ix<Esc>");
+        assert_eq!(lines(&editor.buffer), ["e\u{301}xa"]);
+        assert_eq!(editor.buffer.find("a", 0, 0, false), Some((2, 0)));
+    }
+
+    #[test]
+    fn messages_go_after_a_while_and_failed_writes_show_one() {
+        let mut editor = editor(&["one"]);
+        editor.buffer.file = Some("/nonexistent/dir/file.txt".to_string());
+        let state = keys(&mut editor, ":wq!<CR>");
+        assert!(!state.quit);
+        assert!(editor
+            .message_line()
+            .starts_with("Can't write /nonexistent/dir/file.txt: "));
+
+        editor.message_time -= MESSAGE_DURATION;
+        assert_eq!(editor.message_line(), "");
+    }
+
+    #[test]
+    fn paste_puts_lines_below_and_chars_after_the_cursor() {
+        let mut editor = editor(&["one two", "  three", "four"]);
+        keys(&mut editor, "yiw$p");
+        assert_eq!(lines(&editor.buffer)[0], "one twoone");
+        assert_eq!((editor.cx, editor.buffer_line()), (9, 0));
+
+        keys(&mut editor, "jddkp");
+        assert_eq!(lines(&editor.buffer), ["one twoone", "  three", "four"]);
+        assert_eq!((editor.cx, editor.buffer_line()), (2, 1));
+        keys(&mut editor, "yyjp");
+        assert_eq!(editor.register.kind, RegisterKind::Linewise);
+        assert_eq!(lines(&editor.buffer)[3], "  three");
+    }
+
+    #[test]
+    fn a_yanked_block_is_pasted_as_a_block() {
+        let mut editor = editor(&["abcd", "ef", "ghij"]);
+        keys(&mut editor, "l<C-v>jjly");
+        assert_eq!(editor.register.text, "bc\nf\nhi");
+        keys(&mut editor, "0p");
+        assert_eq!(lines(&editor.buffer), ["abcbcd", "ef f", "ghihij"]);
+        assert_eq!((editor.cx, editor.buffer_line()), (1, 0));
+        keys(&mut editor, "jj$p");
+        assert_eq!(
+            lines(&editor.buffer)[2..],
+            ["ghihijbc", "      f", "      hi"]
+        );
+    }
+
+    #[test]
+    fn backspace_and_right_wrap_across_lines() {
+        let mut editor = editor(&["ab", "", "cd"]);
+        keys(&mut editor, "<BS>");
+        assert_eq!((editor.cx, editor.buffer_line()), (0, 0));
+        keys(&mut editor, "<Right><Right>");
+        assert_eq!((editor.cx, editor.buffer_line()), (0, 1));
+        keys(&mut editor, "<Right><Right><Right>");
+        assert_eq!((editor.cx, editor.buffer_line()), (1, 2));
+        keys(&mut editor, "<BS><BS><BS>");
+        assert_eq!((editor.cx, editor.buffer_line()), (1, 0));
+    }
+
+    #[test]
+    fn space_moves_on_before_a_key_that_does_not_follow_the_leader() {
+        let mut editor = editor(&["abcdef"]);
+        keys(&mut editor, "<Space>l");
+        assert_eq!(editor.cx, 2);
+        keys(&mut editor, "<Space><Space>$");
+        assert_eq!(editor.cx, 5);
+        keys(&mut editor, "0<Space>");
+        assert_eq!(editor.cx, 0);
+        assert!(editor.key_timeout().is_some());
+    }
+
+    #[test]
+    fn vimgrep_fills_the_quickfix_list() {
+        assert_eq!(
+            parse_vimgrep(r"#a\#b# **/*.rs  *.md"),
+            Some((
+                "a#b".to_string(),
+                vec!["**/*.rs".to_string(), "*.md".to_string()]
+            ))
+        );
+        assert_eq!(parse_vimgrep("/a/"), None);
+        assert_eq!(parse_vimgrep("a Cargo.toml"), None);
+
+        let dir = TempDir::new("vimgrep");
+        dir.write("one.txt", "one\n");
+        dir.write("two.txt", "two\nname = \"two\"\n");
+        dir.write("sub/three.txt", "name = \"three\"\n");
+        dir.write("sub/four.md", "name = \"four\"\n");
+        let path = |file: &str| dir.file(file);
+
+        let buffer = Buffer::from_file(Some(path("one.txt"))).unwrap();
+        let mut editor = Editor::with_output(buffer, Config::default(), (80, 24), vec![]);
+        editor.root = dir.path().to_path_buf();
+        keys(&mut editor, ":vimgrep /no such thing/ **/*.txt<CR>");
+        assert_eq!(
+            editor.message.as_deref(),
+            Some("Pattern not found: no such thing")
+        );
+        keys(&mut editor, "ix<Esc>");
+        keys(&mut editor, ":vimgrep #name = \"# **/*.txt<CR>");
+        assert_eq!(
+            editor.message.as_deref(),
+            Some("No write since last change (add ! to override)")
+        );
+        keys(&mut editor, ":w<CR>");
+        keys(&mut editor, ":vimgrep #name = \"# **/*.txt<CR>");
+        assert_eq!(
+            editor.message.as_deref(),
+            Some("Found 2 matches in 2 files")
+        );
+        assert_eq!(editor.buffer.file, Some(path("two.txt")));
+        assert_eq!((editor.cx, editor.buffer_line()), (0, 1));
+        keys(&mut editor, ":cnext<CR>");
+        assert_eq!(editor.buffer.file, Some(path("sub/three.txt")));
+        assert_eq!((editor.cx, editor.buffer_line()), (0, 0));
+        keys(&mut editor, ":cnext<CR>");
+        assert_eq!(editor.message.as_deref(), Some("No more items"));
+        keys(&mut editor, ":cc<CR>");
+        assert_eq!(
+            editor.message.as_deref(),
+            Some("(2 of 2): name = \"three\"")
+        );
+    }
+
+    #[test]
+    fn o_moves_the_other_corner_of_a_visual_block() {
+        let mut editor = editor(&["abcd", "efgh", "ijkl"]);
+        keys(&mut editor, "l<C-v>jlo");
+        assert_eq!((editor.cx, editor.buffer_line()), (1, 0));
+        keys(&mut editor, "h");
+        assert_eq!(editor.block(), (0, 1, 0, 2));
+        keys(&mut editor, "oj");
+        assert_eq!(editor.block(), (0, 2, 0, 2));
+        keys(&mut editor, "d");
+        assert_eq!(lines(&editor.buffer), ["d", "h", "l"]);
+    }
+
+    #[cfg(feature = "regex-search")]
+    #[test]
+    fn magic_searches_use_regexes() {
+        let mut editor = editor(&["a.c", "abc"]);
+        keys(&mut editor, "/b.<CR>");
+        assert_eq!(editor.buffer_line(), 1);
+        keys(&mut editor, ":set nomagic<CR>/a.c<CR>");
+        assert_eq!((editor.cx, editor.buffer_line()), (0, 0));
+        keys(&mut editor, ":set magic?<CR>");
+        assert_eq!(editor.message.as_deref(), Some("nomagic"));
+    }
+
+    #[cfg(not(feature = "regex-search"))]
+    #[test]
+    fn magic_is_unknown_without_regexes() {
+        let mut editor = editor(&["a.c"]);
+        keys(&mut editor, ":set nomagic<CR>");
+        assert_eq!(editor.message.as_deref(), Some("Unknown option: nomagic"));
+    }
+
+    #[test]
+    fn smartcase_ignores_case_only_for_lowercase_patterns() {
+        let lines_matching = |options: &str, pattern: &str| {
+            let mut editor = editor(&["Foo", "foo", "FOO"]);
+            keys(&mut editor, options);
+            keys(&mut editor, &format!(":%s/{pattern}/_/<CR>"));
+            lines(&editor.buffer)
+        };
+        let none = ":<Esc>";
+        let ignorecase = ":set ic<CR>";
+        let smartcase = ":set scs<CR>";
+        let both = ":set ic<CR>:set smartcase<CR>";
+        assert_eq!(lines_matching(none, "foo"), ["Foo", "_", "FOO"]);
+        assert_eq!(lines_matching(smartcase, "foo"), ["Foo", "_", "FOO"]);
+        assert_eq!(lines_matching(ignorecase, "foo"), ["_", "_", "_"]);
+        assert_eq!(lines_matching(ignorecase, "Foo"), ["_", "_", "_"]);
+        assert_eq!(lines_matching(both, "foo"), ["_", "_", "_"]);
+        assert_eq!(lines_matching(both, "Foo"), ["_", "foo", "FOO"]);
+
+        let mut editor = editor(&["Foo", "foo", "FOO"]);
+        keys(
+            &mut editor,
+            ":set ignorecase<CR>:set smartcase<CR>/foo<CR>/foo<CR>",
+        );
+        assert_eq!(editor.buffer_line(), 2);
+        keys(&mut editor, "/Foo<CR>");
+        assert_eq!(editor.buffer_line(), 0);
+        keys(&mut editor, ":set noic<CR>:set ic?<CR>");
+        assert_eq!(editor.message.as_deref(), Some("noic"));
+    }
+
+    #[test]
+    fn only_changed_rows_are_drawn_again() {
+        let mut editor = editor(&["first", "second"]);
+        let drawn = |editor: &mut Editor<Vec<u8>>| {
+            editor.out.clear();
+            editor.draw().unwrap();
+            String::from_utf8_lossy(&editor.out).into_owned()
+        };
+        assert!(drawn(&mut editor).contains("second"));
+
+        // the highlight moves from one line to the other
+        keys(&mut editor, "j");
+        let output = drawn(&mut editor);
+        assert!(output.contains("first") && output.contains("second"));
+        assert!(output.contains("\x1b[48;2;50;50;70msecond"));
+
+        keys(&mut editor, ":set nocul<CR>k");
+        drawn(&mut editor);
+        keys(&mut editor, "j");
+        let output = drawn(&mut editor);
+        assert!(!output.contains("first") && !output.contains("second"));
+
+        keys(&mut editor, "gUU");
+        let output = drawn(&mut editor);
+        assert!(output.contains("SECOND") && !output.contains("first"));
+    }
+
+    #[test]
+    fn colorcolumn_highlights_a_column_on_every_line() {
+        let mut editor = editor(&["abcdef", "a"]);
+        keys(&mut editor, ":set nocul<CR>:set cc=3<CR>:set cc?<CR>");
+        assert_eq!(editor.message.as_deref(), Some("colorcolumn=3"));
+        editor.draw().unwrap();
+        let output = String::from_utf8_lossy(&editor.out).into_owned();
+        assert!(output.contains("ab\x1b[49m\x1b[48;2;70;40;40mc\x1b[49m"));
+        assert!(output.contains("a \x1b[49m\x1b[48;2;70;40;40m \x1b[49m"));
+        // not past the end of the buffer
+        assert_eq!(output.matches("\x1b[48;2;70;40;40m").count(), 2);
+
+        keys(&mut editor, ":set cc=<CR>:set cc<CR>");
+        assert_eq!(editor.message.as_deref(), Some("colorcolumn="));
+        keys(&mut editor, ":set cc=x<CR>");
+        assert_eq!(editor.message.as_deref(), Some("Invalid argument: cc=x"));
+    }
+
+    #[test]
+    fn a_row_draws_its_gutter_then_its_cells_after_it() {
+        let mut editor = editor(&[""]);
+        let row = RenderedRow {
+            gutter: Some(Some(Sign::Added)),
+            cells: ["a", "b", "c"].map(String::from).to_vec(),
+            selection: (1, 2),
+            cursor_line: false,
+            color_column: None,
+        };
+        let reset = Some(style::Color::Reset);
+        assert_eq!(
+            row.runs(),
+            [("a".into(), reset), ("b".into(), None), ("c".into(), reset)]
+        );
+
+        // the cells start right after the gutter
+        let width = editor.draw_gutter(&row, 4).unwrap();
+        assert_eq!(width, 1);
+        editor.out.clear();
+        editor.draw_cells(&row, 4, width).unwrap();
+        let mut move_to = vec![];
+        move_to.queue(cursor::MoveTo(1, 4)).unwrap();
+        assert!(editor.out.starts_with(&move_to));
+    }
+
+    #[test]
+    fn a_visual_block_shifts_its_lines_and_gv_selects_it_again() {
+        let mut editor = editor(&["a", "", "  b", "c"]);
+        editor.config.expandtab = true;
+        editor.config.shiftwidth = 2;
+        keys(&mut editor, "<C-v>jj");
+        assert_eq!(editor.last_block, None);
+        let state = keys(&mut editor, "2>");
+        assert_eq!(lines(&editor.buffer), ["    a", "", "      b", "c"]);
+        assert_eq!((state.cursor, state.mode), ((4, 0), Mode::Normal));
+
+        let state = keys(&mut editor, "jgv");
+        assert_eq!((state.cursor, state.mode), ((0, 2), Mode::VisualBlock));
+        assert_eq!(editor.visual_anchor, (0, 0));
+        keys(&mut editor, "<lt>");
+        assert_eq!(lines(&editor.buffer), ["  a", "", "    b", "c"]);
+        keys(&mut editor, "u");
+        assert_eq!(lines(&editor.buffer), ["    a", "", "      b", "c"]);
+    }
+
+    #[test]
+    fn shifting_a_block_shifts_whole_lines_whatever_its_columns() {
+        let mut editor = editor(&["fn main() {", "let x = 1;", "x", "}"]);
+        editor.config.shiftwidth = 4;
+        editor.config.expandtab = true;
+        let state = keys(&mut editor, "jllll<C-v>jl>");
+        assert_eq!(
+            lines(&editor.buffer),
+            ["fn main() {", "    let x = 1;", "    x", "}"]
+        );
+        assert_eq!(state.cursor, (4, 1));
+
+        let state = keys(&mut editor, "gv$<lt>");
+        assert_eq!(
+            lines(&editor.buffer),
+            ["fn main() {", "let x = 1;", "x", "}"]
+        );
+        assert_eq!(state.cursor, (0, 1));
+    }
+
+    #[test]
+    fn ctrl_p_completes_the_nearest_word_above_first() {
+        let mut editor = editor(&["alpha", "alps", "", "alto"]);
+        keys(&mut editor, "jjoal<C-p>");
+        assert_eq!(lines(&editor.buffer)[3], "alps");
+        keys(&mut editor, "<C-p>");
+        assert_eq!(lines(&editor.buffer)[3], "alpha");
+        keys(&mut editor, "<Esc>");
+
+        keys(&mut editor, "oal<C-n>");
+        assert_eq!(lines(&editor.buffer)[4], "alto");
+    }
+
+    #[test]
+    fn insert_mode_deletes_words_and_to_the_line_start() {
+        let mut editor = editor(&["let foo = bar;"]);
+        keys(&mut editor, "lllllllliqux<C-w>");
+        assert_eq!(lines(&editor.buffer), ["let foo = bar;"]);
+        keys(&mut editor, "<C-w>");
+        assert_eq!(lines(&editor.buffer), ["let = bar;"]);
+        assert_eq!(editor.register.text, "foo ");
+        keys(&mut editor, "<A-d><C-Del>");
+        assert_eq!(lines(&editor.buffer), ["let ;"]);
+        assert_eq!(editor.register.text, " bar");
+        keys(&mut editor, "<C-u>");
+        assert_eq!(lines(&editor.buffer), [";"]);
+        assert_eq!((editor.cx, editor.register.text.as_str()), (0, "let "));
+        // at the start of the line they join it to the one above
+        keys(&mut editor, "<Esc>Oab<Down><Left><Left><Left><C-u>");
+        assert_eq!(lines(&editor.buffer), ["ab;"]);
+        assert_eq!((editor.cx, editor.register.text.as_str()), (2, "let "));
+
+        // each of them is undone on its own
+        keys(&mut editor, "<Esc>u");
+        assert_eq!(lines(&editor.buffer), ["ab", ";"]);
+        keys(&mut editor, "u");
+        assert_eq!(lines(&editor.buffer), [";"]);
+        keys(&mut editor, "u");
+        assert_eq!(lines(&editor.buffer), ["let ;"]);
+        keys(&mut editor, "u");
+        assert_eq!(lines(&editor.buffer), ["let foo = bar;"]);
+    }
+
+    #[test]
+    fn ctrl_k_deletes_the_rest_of_the_line_or_joins_the_next() {
+        let mut editor = editor(&["abc def", "ghi"]);
+        keys(&mut editor, "llli<C-k>");
+        assert_eq!(lines(&editor.buffer), ["abc", "ghi"]);
+        assert_eq!(editor.register.text, " def");
+        keys(&mut editor, "<C-k>");
+        assert_eq!(lines(&editor.buffer), ["abcghi"]);
+        assert_eq!((editor.cx, editor.register.text.as_str()), (3, " def"));
+        // nothing to join on the last line
+        keys(&mut editor, "<C-k><C-k>");
+        assert_eq!(lines(&editor.buffer), ["abc"]);
+        assert_eq!(editor.register.text, "ghi");
+    }
+
+    #[test]
+    fn inserts_with_a_count_are_repeated() {
+        let mut editor = editor(&["ab"]);
+        keys(&mut editor, "3ix<Esc>");
+        assert_eq!(lines(&editor.buffer), ["xxxab"]);
+        keys(&mut editor, "u2a-<Esc>");
+        assert_eq!(lines(&editor.buffer), ["a--b"]);
+        keys(&mut editor, "u2ohi<Esc>");
+        assert_eq!(lines(&editor.buffer), ["ab", "hi", "hi"]);
+        assert_eq!(editor.buffer_line(), 2);
+        keys(&mut editor, "Oy<Esc>");
+        assert_eq!(lines(&editor.buffer), ["ab", "hi", "y", "hi"]);
+    }
+
+    #[test]
+    fn finder_opens_the_selected_file() {
+        let dir = TempDir::new("finder");
+        dir.write(".gitignore", "build/\n");
+        dir.write("notes.txt", "notes\n");
+        dir.write("sub/todo.txt", "todo\n");
+        dir.write("build/out.txt", "out\n");
+
+        let mut editor = editor(&[""]);
+        editor.root = dir.path().to_path_buf();
+        keys(&mut editor, "<Space>f");
+        while editor.finder.is_some() {
+            editor.receive_files();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let path = |file: &str| dir.file(file);
+        let mut files = editor.palette.as_ref().unwrap().labels(usize::MAX);
+        files.sort();
+        assert_eq!(files, [path("notes.txt"), path("sub/todo.txt")]);
+
+        keys(&mut editor, "todotxt");
+        assert_eq!(
+            editor.palette.as_ref().unwrap().labels(1),
+            [path("sub/todo.txt")]
+        );
+        keys(&mut editor, "<CR>");
+        assert!(editor.palette.is_none());
+        assert_eq!(editor.buffer.file, Some(path("sub/todo.txt")));
+        assert_eq!(lines(&editor.buffer), ["todo"]);
+    }
+
+    /// The viewport rows, without the padding at their end.
+    fn screen(editor: &Editor<Vec<u8>>) -> Vec<String> {
+        let rows = editor.screen_text();
+        rows.iter().map(|row| row.trim_end().to_string()).collect()
+    }
+
+    #[test]
+    fn renders_tabs_and_cuts_long_lines() {
+        let mut editor = editor(&["\tindented", "a\tb", "a line longer than the screen"]);
+        editor.size = (20, 6);
+
+        assert_eq!(
+            screen(&editor),
+            ["        indented", "a       b", "a line longer than t", ""]
+        );
+    }
+
+    #[test]
+    fn screen_follows_the_cursor_down() {
+        let lines = numbered(10);
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let mut editor = editor(&lines);
+        editor.size = (20, 6);
+
+        keys(&mut editor, "jjjjj");
+        assert_eq!(screen(&editor), ["3", "4", "5", "6"]);
+        keys(&mut editor, "<C-d>");
+        assert_eq!(screen(&editor), ["5", "6", "7", "8"]);
     }
 }
@@ -0,0 +1,169 @@
+use std::path::Path;
+
+/// The kind of file being edited, which selects language-specific behavior.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Rust,
+    Go,
+    Python,
+    C,
+    Cpp,
+    JavaScript,
+    TypeScript,
+    Markdown,
+    Toml,
+    Json,
+    Yaml,
+    Html,
+    Css,
+    Shell,
+    Text,
+}
+
+const ALL: [FileType; 15] = [
+    FileType::Rust,
+    FileType::Go,
+    FileType::Python,
+    FileType::C,
+    FileType::Cpp,
+    FileType::JavaScript,
+    FileType::TypeScript,
+    FileType::Markdown,
+    FileType::Toml,
+    FileType::Json,
+    FileType::Yaml,
+    FileType::Html,
+    FileType::Css,
+    FileType::Shell,
+    FileType::Text,
+];
+
+/// Works out the file type from the extension of `path`, or else from a `#!`
+/// interpreter line at the top of the file. `Text` if neither tells.
+pub fn detect_filetype(path: Option<&str>, first_line: Option<&str>) -> FileType {
+    let ext = path
+        .and_then(|path| Path::new(path).extension())
+        .and_then(|ext| ext.to_str());
+    match ext.map(FileType::from_extension) {
+        Some(filetype) if filetype != FileType::Text => filetype,
+        _ => first_line.map_or(FileType::Text, FileType::from_shebang),
+    }
+}
+
+impl FileType {
+    fn from_extension(ext: &str) -> FileType {
+        match ext {
+            "rs" => FileType::Rust,
+            "go" => FileType::Go,
+            "py" => FileType::Python,
+            "c" | "h" => FileType::C,
+            "cc" | "cpp" | "hpp" => FileType::Cpp,
+            "js" | "jsx" => FileType::JavaScript,
+            "ts" | "tsx" => FileType::TypeScript,
+            "md" | "markdown" => FileType::Markdown,
+            "toml" => FileType::Toml,
+            "json" => FileType::Json,
+            "yml" | "yaml" => FileType::Yaml,
+            "html" | "htm" => FileType::Html,
+            "css" => FileType::Css,
+            "sh" | "bash" | "zsh" => FileType::Shell,
+            _ => FileType::Text,
+        }
+    }
+
+    /// Reads `#!/bin/sh` or `#!/usr/bin/env python3` style lines.
+    fn from_shebang(line: &str) -> FileType {
+        let Some(command) = line.strip_prefix("#!") else {
+            return FileType::Text;
+        };
+        let mut words = command.split_whitespace();
+        let mut program = words.next().unwrap_or_default().rsplit('/').next();
+        if program == Some("env") {
+            program = words.find(|word| !word.starts_with('-'));
+        }
+        let program = program
+            .unwrap_or_default()
+            .trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+
+        match program {
+            "sh" | "bash" | "zsh" | "dash" => FileType::Shell,
+            "python" => FileType::Python,
+            "node" => FileType::JavaScript,
+            _ => FileType::Text,
+        }
+    }
+
+    /// The name shown in the status line and accepted by `:set ft=`.
+    pub fn name(self) -> &'static str {
+        match self {
+            FileType::Rust => "rust",
+            FileType::Go => "go",
+            FileType::Python => "python",
+            FileType::C => "c",
+            FileType::Cpp => "cpp",
+            FileType::JavaScript => "javascript",
+            FileType::TypeScript => "typescript",
+            FileType::Markdown => "markdown",
+            FileType::Toml => "toml",
+            FileType::Json => "json",
+            FileType::Yaml => "yaml",
+            FileType::Html => "html",
+            FileType::Css => "css",
+            FileType::Shell => "sh",
+            FileType::Text => "text",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<FileType> {
+        ALL.into_iter().find(|filetype| filetype.name() == name)
+    }
+
+    /// The prefix of a line comment, if the language has them.
+    pub fn comment(self) -> Option<&'static str> {
+        match self {
+            FileType::Rust
+            | FileType::Go
+            | FileType::C
+            | FileType::Cpp
+            | FileType::JavaScript
+            | FileType::TypeScript => Some("//"),
+            FileType::Python | FileType::Toml | FileType::Yaml | FileType::Shell => Some("#"),
+            FileType::Markdown
+            | FileType::Json
+            | FileType::Html
+            | FileType::Css
+            | FileType::Text => None,
+        }
+    }
+
+    /// The language identifier a language server expects in `didOpen`.
+    pub fn language_id(self) -> &'static str {
+        match self {
+            FileType::Shell => "shellscript",
+            FileType::Text => "plaintext",
+            _ => self.name(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extension_wins_over_shebang() {
+        let filetype = detect_filetype(Some("src/main.rs"), Some("#!/bin/sh"));
+        assert_eq!(filetype, FileType::Rust);
+    }
+
+    #[test]
+    fn falls_back_to_the_interpreter() {
+        let detect = |line| detect_filetype(Some("bin/run"), Some(line));
+        assert_eq!(detect("#!/bin/bash"), FileType::Shell);
+        assert_eq!(detect("#!/usr/bin/env python3"), FileType::Python);
+        assert_eq!(detect("#!/usr/bin/env -S python3.12 -u"), FileType::Python);
+        assert_eq!(detect("#!/usr/bin/perl"), FileType::Text);
+        assert_eq!(detect("plain text"), FileType::Text);
+        assert_eq!(detect_filetype(None, None), FileType::Text);
+    }
+}
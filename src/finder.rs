@@ -0,0 +1,201 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+/// Files are sent in batches of this many, so the list fills in as the walk goes.
+const BATCH: usize = 256;
+
+/// Lists the files under `root` on a separate thread, so a large tree does not
+/// hold up the editor. Paths are relative to `root` and arrive in batches; the
+/// channel closes when the walk is done, and the walk stops once the receiver
+/// is dropped. Hidden entries are skipped, and with `gitignore` so is whatever
+/// the `.gitignore` at `root` ignores.
+pub fn find_files(root: PathBuf, gitignore: bool) -> Receiver<Vec<String>> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let ignore = match gitignore {
+            true => Ignore::load(&root.join(".gitignore")),
+            false => Ignore::default(),
+        };
+
+        let mut batch = vec![];
+        let mut dirs = vec![root.clone()];
+        while let Some(dir) = dirs.pop() {
+            let Ok(entries) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            let mut entries: Vec<_> = entries.filter_map(|entry| entry.ok()).collect();
+            entries.sort_by_key(|entry| entry.file_name());
+
+            let mut subdirs = vec![];
+            for entry in entries {
+                let path = entry.path();
+                let Some(relative) = path.strip_prefix(&root).ok().and_then(Path::to_str) else {
+                    continue;
+                };
+                let Ok(file_type) = entry.file_type() else {
+                    continue;
+                };
+                let is_dir = file_type.is_dir();
+                if entry.file_name().to_string_lossy().starts_with('.')
+                    || ignore.matches(relative, is_dir)
+                {
+                    continue;
+                }
+
+                if is_dir {
+                    subdirs.push(path);
+                } else {
+                    batch.push(relative.to_string());
+                }
+            }
+
+            // reversed so they come off the stack in order
+            dirs.extend(subdirs.into_iter().rev());
+
+            if batch.len() >= BATCH && tx.send(std::mem::take(&mut batch)).is_err() {
+                return;
+            }
+        }
+        if !batch.is_empty() {
+            _ = tx.send(batch);
+        }
+    });
+    rx
+}
+
+/// The patterns of a `.gitignore`: names or globs with `*` and `?`, anchored
+/// to the root when they contain a `/`, and for directories only when they
+/// end with one. Negated patterns (`!`) are not supported and skipped.
+#[derive(Default)]
+struct Ignore {
+    patterns: Vec<IgnorePattern>,
+}
+
+struct IgnorePattern {
+    glob: String,
+    anchored: bool,
+    dir_only: bool,
+}
+
+impl Ignore {
+    fn load(path: &Path) -> Ignore {
+        let content = std::fs::read_to_string(path).unwrap_or_default();
+        Ignore::parse(&content)
+    }
+
+    fn parse(content: &str) -> Ignore {
+        let patterns = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with(['#', '!']))
+            .map(|line| {
+                let dir_only = line.ends_with('/');
+                let line = line.trim_end_matches('/');
+                IgnorePattern {
+                    anchored: line.contains('/'),
+                    glob: line.trim_start_matches('/').to_string(),
+                    dir_only,
+                }
+            })
+            .collect();
+        Ignore { patterns }
+    }
+
+    /// Whether `path`, relative to the root, is ignored.
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        self.patterns.iter().any(|pattern| {
+            let target = if pattern.anchored { path } else { name };
+            (is_dir || !pattern.dir_only) && glob_match(&pattern.glob, target)
+        })
+    }
+}
+
+/// Matches a `path` relative to the root against `glob`, where a `**`
+/// component stands for any number of directories, `**/*.rs` matching
+/// `main.rs` as well as `src/bin/main.rs`.
+pub fn path_match(glob: &str, path: &str) -> bool {
+    let glob: Vec<&str> = glob.trim_start_matches("./").split('/').collect();
+    let path: Vec<&str> = path.split('/').collect();
+    components_match(&glob, &path)
+}
+
+fn components_match(glob: &[&str], path: &[&str]) -> bool {
+    match glob.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|skip| components_match(rest, &path[skip..])),
+        Some((first, rest)) => path
+            .split_first()
+            .is_some_and(|(name, path)| glob_match(first, name) && components_match(rest, path)),
+    }
+}
+
+/// Matches `text` against `glob`, where `*` stands for any run of chars other
+/// than `/` and `?` for any one of them.
+fn glob_match(glob: &str, text: &str) -> bool {
+    let (glob, text): (Vec<char>, Vec<char>) = (glob.chars().collect(), text.chars().collect());
+    let (mut g, mut t) = (0, 0);
+    // where the last `*` was, and how much of the text it has taken so far
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match glob.get(g) {
+            Some('*') => {
+                star = Some((g, t));
+                g += 1;
+            }
+            Some(&c) if c == text[t] || (c == '?' && text[t] != '/') => {
+                g += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_g, star_t)) if text[star_t] != '/' => {
+                    star = Some((star_g, star_t + 1));
+                    (g, t) = (star_g + 1, star_t + 1);
+                }
+                _ => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs_do_not_cross_directories() {
+        assert!(glob_match("*.rs", "main.rs"));
+        assert!(glob_match("a?c", "abc"));
+        assert!(glob_match("src/*.rs", "src/main.rs"));
+        assert!(!glob_match("src/*.rs", "src/bin/main.rs"));
+        assert!(!glob_match("*.rs", "main.rsx"));
+        assert!(glob_match("*", ""));
+    }
+
+    #[test]
+    fn double_stars_match_any_depth() {
+        assert!(path_match("**/*.rs", "main.rs"));
+        assert!(path_match("**/*.rs", "src/bin/main.rs"));
+        assert!(path_match("./src/**/mod.rs", "src/a/b/mod.rs"));
+        assert!(path_match("src/**", "src/a/b.txt"));
+        assert!(!path_match("src/*.rs", "src/bin/main.rs"));
+        assert!(!path_match("**/*.rs", "src/main.rsx"));
+        assert!(path_match("Cargo.toml", "Cargo.toml"));
+    }
+
+    #[test]
+    fn gitignore_patterns_match_names_anywhere_or_anchored_paths() {
+        let ignore = Ignore::parse("# build output\ntarget/\n*.log\n/docs/gen\n!keep.log\n");
+        assert!(ignore.matches("target", true));
+        assert!(ignore.matches("sub/target", true));
+        assert!(!ignore.matches("target", false));
+        assert!(ignore.matches("logs/run.log", false));
+        assert!(ignore.matches("docs/gen", true));
+        assert!(!ignore.matches("sub/docs/gen", true));
+        assert!(!ignore.matches("src/main.rs", false));
+    }
+}
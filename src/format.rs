@@ -0,0 +1,94 @@
+use std::{
+    io::{Read, Write},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+
+/// Pipes `text` through the shell command `command`, a formatter like
+/// `rustfmt --emit stdout`, and returns what it prints. Fails with the first
+/// line of its error output when it exits with an error, and kills it when it
+/// runs longer than `timeout`.
+pub fn run(command: &str, text: &str, timeout: Duration) -> anyhow::Result<String> {
+    let mut child = Command::new("sh")
+        .args(["-c", command])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // written and read from other threads, so that a formatter printing
+    // before it has read everything cannot block on a full pipe
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let text = text.to_string();
+    let writer = thread::spawn(move || stdin.write_all(text.as_bytes()));
+    let stdout = read_all(child.stdout.take().expect("stdout is piped"));
+    let stderr = read_all(child.stderr.take().expect("stderr is piped"));
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            _ = child.kill();
+            _ = child.wait();
+            bail!("{command}: timed out after {timeout:?}");
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    // a formatter may stop reading early and still succeed
+    _ = writer.join();
+    let stdout = stdout.join().expect("reader panicked")?;
+    let stderr = stderr.join().expect("reader panicked")?;
+
+    if !status.success() {
+        let stderr = String::from_utf8_lossy(&stderr);
+        match stderr.lines().find(|line| !line.trim().is_empty()) {
+            Some(line) => bail!("{command}: {}", line.trim()),
+            None => bail!("{command}: {status}"),
+        }
+    }
+    Ok(String::from_utf8(stdout)?)
+}
+
+/// Reads `pipe` to the end on a thread of its own.
+fn read_all(mut pipe: impl Read + Send + 'static) -> thread::JoinHandle<std::io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut bytes = vec![];
+        pipe.read_to_end(&mut bytes).map(|_| bytes)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn text_is_piped_through_the_command() {
+        assert_eq!(
+            run("tr a-z A-Z", "fn main() {}\n", TIMEOUT).unwrap(),
+            "FN MAIN() {}\n"
+        );
+
+        let err = run("echo 'syntax error' >&2; exit 1", "x", TIMEOUT).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "echo 'syntax error' >&2; exit 1: syntax error"
+        );
+        let err = run("exit 3", "x", TIMEOUT).unwrap_err();
+        assert_eq!(err.to_string(), "exit 3: exit status: 3");
+    }
+
+    #[test]
+    fn a_formatter_that_hangs_is_stopped() {
+        let started = Instant::now();
+        let err = run("exec sleep 10", "x", Duration::from_millis(100)).unwrap_err();
+        assert_eq!(err.to_string(), "exec sleep 10: timed out after 100ms");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
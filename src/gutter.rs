@@ -0,0 +1,200 @@
+use std::{collections::HashMap, path::Path, process::Command};
+
+use crossterm::style::Color;
+use similar::{Algorithm, DiffTag};
+
+/// A one-char marker in the gutter. Declared from the lowest priority to the
+/// highest: a line with several signs shows the last of them.
+// the diagnostic signs wait for language servers to send diagnostics
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Sign {
+    /// Lines were removed below this one.
+    Deleted,
+    Added,
+    Modified,
+    Hint,
+    Info,
+    Warning,
+    Error,
+}
+
+impl Sign {
+    pub fn symbol(self) -> char {
+        match self {
+            Sign::Deleted => '_',
+            Sign::Added => '+',
+            Sign::Modified => '~',
+            Sign::Hint => 'H',
+            Sign::Info => 'I',
+            Sign::Warning => 'W',
+            Sign::Error => 'E',
+        }
+    }
+
+    pub fn color(self) -> Color {
+        match self {
+            Sign::Deleted | Sign::Error => Color::Red,
+            Sign::Added => Color::Green,
+            Sign::Modified | Sign::Warning => Color::Yellow,
+            Sign::Hint => Color::Cyan,
+            Sign::Info => Color::Blue,
+        }
+    }
+
+    /// Whether the sign marks a change from the version in git.
+    pub fn is_git(self) -> bool {
+        matches!(self, Sign::Deleted | Sign::Added | Sign::Modified)
+    }
+}
+
+/// The signs placed on each line.
+#[derive(Default)]
+pub struct Signs {
+    lines: HashMap<usize, Vec<Sign>>,
+}
+
+impl Signs {
+    pub fn place(&mut self, y: usize, sign: Sign) {
+        let signs = self.lines.entry(y).or_default();
+        if !signs.contains(&sign) {
+            signs.push(sign);
+        }
+    }
+
+    /// Takes away the signs for which `remove` is true.
+    pub fn remove(&mut self, remove: impl Fn(Sign) -> bool) {
+        for signs in self.lines.values_mut() {
+            signs.retain(|&sign| !remove(sign));
+        }
+        self.lines.retain(|_, signs| !signs.is_empty());
+    }
+
+    /// Moves the signs below line `changed_at` by `shift` lines, to follow
+    /// the lines added or removed there.
+    pub fn shift(&mut self, changed_at: usize, shift: isize) {
+        if shift == 0 {
+            return;
+        }
+        let mut lines = HashMap::new();
+        for (y, signs) in self.lines.drain() {
+            let y = match y {
+                y if y > changed_at => y.saturating_add_signed(shift).max(changed_at),
+                y => y,
+            };
+            lines.entry(y).or_insert_with(Vec::new).extend(signs);
+        }
+        self.lines = lines;
+    }
+
+    /// The sign shown for line `y`: the one with the highest priority.
+    pub fn shown(&self, y: usize) -> Option<Sign> {
+        self.lines.get(&y)?.iter().max().copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+}
+
+/// The lines of a file as they are in `HEAD`, to mark how the buffer differs
+/// from them.
+pub struct GitGutter {
+    head: Vec<String>,
+}
+
+impl GitGutter {
+    /// Reads `file` from `HEAD` with `git show`. Returns `None` when the file
+    /// is not in a git repository or not committed, or git is not available.
+    pub fn load(file: &str) -> Option<GitGutter> {
+        let path = Path::new(file);
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let name = path.file_name()?.to_str()?;
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["show", "--no-color", "--no-textconv"])
+            .arg(format!("HEAD:./{name}"))
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+
+        let text = String::from_utf8_lossy(&output.stdout);
+        Some(GitGutter {
+            head: text.lines().map(String::from).collect(),
+        })
+    }
+
+    /// The signs for the changes from `HEAD` to `lines`. Replaced lines are
+    /// marked modified, and any lines beyond the ones they replace as added.
+    pub fn signs(&self, lines: &[&str]) -> Vec<(usize, Sign)> {
+        let head: Vec<&str> = self.head.iter().map(String::as_str).collect();
+        let ops = similar::capture_diff_slices(Algorithm::Myers, &head, lines);
+
+        let mut signs = vec![];
+        for op in ops {
+            let (tag, old, new) = op.as_tag_tuple();
+            match tag {
+                DiffTag::Equal => {}
+                // marked on the line after which the lines were deleted
+                DiffTag::Delete => signs.push((new.start.saturating_sub(1), Sign::Deleted)),
+                DiffTag::Insert | DiffTag::Replace => {
+                    for y in new.clone() {
+                        let sign = match y - new.start < old.len() {
+                            true => Sign::Modified,
+                            false => Sign::Added,
+                        };
+                        signs.push((y, sign));
+                    }
+                }
+            }
+        }
+        signs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_from_head_are_marked_by_line() {
+        let gutter = GitGutter {
+            head: ["a", "b", "c", "d", "e"].map(String::from).to_vec(),
+        };
+        let signs = gutter.signs(&["a", "B", "x", "c", "e"]);
+        assert_eq!(
+            signs,
+            [(1, Sign::Modified), (2, Sign::Added), (3, Sign::Deleted)]
+        );
+        assert_eq!(gutter.signs(&["a", "b", "c", "d", "e"]), []);
+    }
+
+    #[test]
+    fn the_highest_priority_sign_shows_and_signs_follow_their_lines() {
+        let mut signs = Signs::default();
+        signs.place(2, Sign::Modified);
+        signs.place(2, Sign::Warning);
+        signs.place(5, Sign::Added);
+        assert_eq!(signs.shown(2), Some(Sign::Warning));
+
+        signs.shift(3, 2);
+        assert_eq!((signs.shown(5), signs.shown(7)), (None, Some(Sign::Added)));
+        signs.shift(0, -1);
+        assert_eq!(
+            (signs.shown(1), signs.shown(6)),
+            (Some(Sign::Warning), Some(Sign::Added))
+        );
+
+        signs.remove(Sign::is_git);
+        assert_eq!(
+            (signs.shown(1), signs.shown(6)),
+            (Some(Sign::Warning), None)
+        );
+    }
+}
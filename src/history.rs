@@ -0,0 +1,57 @@
+/// Previously entered lines for a prompt (`:` commands or `/` searches), browsed
+/// with Up/Down like a shell history.
+#[derive(Default)]
+pub struct History {
+    entries: Vec<String>,
+    index: Option<usize>,
+    draft: String,
+}
+
+impl History {
+    /// Records an entry as the most recent one, dropping an older duplicate.
+    pub fn push(&mut self, entry: &str) {
+        self.reset();
+        if entry.is_empty() {
+            return;
+        }
+        self.entries.retain(|e| e != entry);
+        self.entries.push(entry.to_string());
+    }
+
+    /// Steps back to an older entry. `current` is what has been typed so far and
+    /// is restored once the user walks forward past the newest entry.
+    pub fn prev(&mut self, current: &str) -> Option<&str> {
+        let index = match self.index {
+            None if self.entries.is_empty() => return None,
+            None => {
+                self.draft = current.to_string();
+                self.entries.len() - 1
+            }
+            Some(i) => i.saturating_sub(1),
+        };
+        self.index = Some(index);
+        Some(&self.entries[index])
+    }
+
+    /// Steps forward to a newer entry, ending with the text typed before browsing.
+    pub fn next(&mut self) -> Option<&str> {
+        let index = self.index?;
+        if index + 1 < self.entries.len() {
+            self.index = Some(index + 1);
+            Some(&self.entries[index + 1])
+        } else {
+            self.index = None;
+            Some(&self.draft)
+        }
+    }
+
+    /// The most recent entry.
+    pub fn last(&self) -> Option<&str> {
+        self.entries.last().map(String::as_str)
+    }
+
+    pub fn reset(&mut self) {
+        self.index = None;
+        self.draft.clear();
+    }
+}
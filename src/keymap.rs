@@ -0,0 +1,371 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+use crate::{
+    config::KeysConfig,
+    editor::{Action, CaseOp, Mode, Motion},
+    log,
+    trie::{Step, Trie},
+};
+
+pub type KeyBinding = (KeyCode, KeyModifiers);
+
+pub struct Keymap {
+    normal: Trie<KeyBinding, Action>,
+}
+
+/// Where a key sequence stands after one more key.
+pub enum KeyResult {
+    Action(Action),
+    /// More keys are needed; continue from this trie node.
+    Pending(usize),
+    Unbound,
+}
+
+impl Keymap {
+    /// Builds the keymap from the defaults, then applies the overrides from the
+    /// config file, with `<leader>` standing for its leader key. Entries that
+    /// fail to parse are logged and skipped.
+    pub fn new(config: &KeysConfig) -> Keymap {
+        let mut leader = config.leader.clone();
+        if parse_keys(&leader).is_none() {
+            log!("keymap: ignoring unknown leader key {leader:?}");
+            leader = KeysConfig::default().leader;
+        }
+
+        let defaults: Vec<(Vec<KeyBinding>, Action)> = default_normal()
+            .into_iter()
+            .map(|(keys, action)| {
+                let keys = expand_leader(&keys, &leader);
+                let keys = parse_keys(&keys).expect("default key binding must parse");
+                (keys, action)
+            })
+            .collect();
+        let mut normal = Trie::from_slice(&defaults);
+
+        for (key, value) in &config.normal {
+            let Some(keys) = parse_keys(&expand_leader(key, &leader)) else {
+                log!("keymap: ignoring mapping for unknown key {key:?}");
+                continue;
+            };
+
+            match parse_action(value) {
+                Some(Some(action)) => normal.insert(&keys, action),
+                Some(None) => normal.remove(&keys),
+                None => log!("keymap: ignoring unknown action {value:?} for {key:?}"),
+            }
+        }
+
+        Keymap { normal }
+    }
+
+    /// Looks up a single key press, ignoring multi-key sequences.
+    pub fn normal(&self, event: &KeyEvent) -> Option<Action> {
+        match self
+            .normal
+            .step(Trie::<KeyBinding, Action>::ROOT, &binding(event))
+        {
+            Step::Match(action) => Some(action.clone()),
+            Step::Prefix(_) | Step::NoMatch => None,
+        }
+    }
+
+    /// Follows `event` from `node` of the normal-mode key sequences.
+    pub fn normal_step(&self, node: usize, event: &KeyEvent) -> KeyResult {
+        match self.normal.step(node, &binding(event)) {
+            Step::Match(action) => KeyResult::Action(action.clone()),
+            Step::Prefix(node) => KeyResult::Pending(node),
+            Step::NoMatch => KeyResult::Unbound,
+        }
+    }
+
+    /// The action bound to the sequence ending at `node`, used when no further
+    /// key follows it in time.
+    pub fn normal_value(&self, node: usize) -> Option<Action> {
+        self.normal.value(node).cloned()
+    }
+}
+
+/// Keys that can follow an operator, with the motion they stand for.
+fn operator_motions() -> Vec<(&'static str, Motion)> {
+    vec![
+        ("k", Motion::Up),
+        ("<Up>", Motion::Up),
+        ("j", Motion::Down),
+        ("<Down>", Motion::Down),
+        ("h", Motion::Left),
+        ("<Left>", Motion::Left),
+        ("l", Motion::Right),
+        ("<Right>", Motion::Right),
+        ("0", Motion::LineStart),
+        ("<Home>", Motion::LineStart),
+        ("^", Motion::FirstNonBlank),
+        ("$", Motion::LineEnd),
+        ("<End>", Motion::LineEnd),
+        ("}", Motion::NextParagraph),
+        ("{", Motion::PrevParagraph),
+    ]
+}
+
+/// Text objects, which select text around the cursor for an operator (`diw`).
+fn text_objects() -> Vec<(&'static str, Motion)> {
+    vec![("iw", Motion::InnerWord), ("aw", Motion::AWord)]
+}
+
+fn default_normal() -> Vec<(String, Action)> {
+    let mut bindings: Vec<(String, Action)> = vec![
+        ("q", Action::Quit),
+        ("<C-s>", Action::Save),
+        ("k", Action::MoveUp),
+        ("<Up>", Action::MoveUp),
+        ("j", Action::MoveDown),
+        ("<Down>", Action::MoveDown),
+        ("h", Action::MoveLeft),
+        ("<Left>", Action::MoveLeft),
+        ("l", Action::MoveRight),
+        ("<Right>", Action::MoveForward),
+        ("<BS>", Action::MoveBackward),
+        ("<Space>", Action::MoveForward),
+        ("0", Action::MoveToLineStart),
+        ("^", Action::Move(Motion::FirstNonBlank)),
+        ("<Home>", Action::SmartHome),
+        ("$", Action::MoveToLineEnd),
+        ("<End>", Action::MoveToLineEnd),
+        ("}", Action::Move(Motion::NextParagraph)),
+        ("{", Action::Move(Motion::PrevParagraph)),
+        ("%", Action::Percent),
+        ("gg", Action::GoToTop),
+        ("G", Action::GoToBottom),
+        ("<C-b>", Action::PageUp),
+        ("<C-f>", Action::PageDown),
+        ("<C-u>", Action::HalfPageUp),
+        ("<C-d>", Action::HalfPageDown),
+        ("<C-e>", Action::ScrollDown),
+        ("<C-y>", Action::ScrollUp),
+        ("zo", Action::OpenFold),
+        ("zc", Action::CloseFold),
+        ("zR", Action::OpenAllFolds),
+        ("zM", Action::CloseAllFolds),
+        ("i", Action::EnterMode(Mode::Insert)),
+        ("a", Action::Append),
+        ("o", Action::OpenLine { above: false }),
+        ("O", Action::OpenLine { above: true }),
+        (":", Action::EnterMode(Mode::Command)),
+        ("/", Action::EnterMode(Mode::Search)),
+        ("<C-v>", Action::EnterMode(Mode::VisualBlock)),
+        ("gv", Action::ReselectBlock),
+        ("dd", Action::DeleteCurrentLine),
+        ("yy", Action::Yank(None)),
+        ("ZZ", Action::ExecuteCommand("x".to_string())),
+        ("ZQ", Action::ExecuteCommand("q!".to_string())),
+        // lines are never wrapped, so display rows and buffer lines coincide
+        ("gj", Action::MoveDown),
+        ("g<Down>", Action::MoveDown),
+        ("gk", Action::MoveUp),
+        ("g<Up>", Action::MoveUp),
+        ("grn", Action::PromptRename),
+        // doubling a case operator (`gUU`, `guu`, `g~~`) acts on the line
+        ("gUU", Action::ChangeCase(CaseOp::Upper, None)),
+        ("guu", Action::ChangeCase(CaseOp::Lower, None)),
+        ("g~~", Action::ChangeCase(CaseOp::Toggle, None)),
+        ("gcc", Action::ToggleComment(None)),
+        // `&` repeats the last `:s` on the line without its flags, `g&` on
+        // every line with them
+        ("&", Action::ExecuteCommand("s".to_string())),
+        ("g&", Action::ExecuteCommand("%&&".to_string())),
+        ("s", Action::SubstituteChar),
+        ("S", Action::SubstituteLine),
+        ("D", Action::DeleteToLineEnd),
+        ("C", Action::ChangeToLineEnd),
+        ("p", Action::PasteAfterCursor),
+        ("u", Action::Undo),
+        ("<C-r>", Action::Redo),
+        ("K", Action::Hover),
+        ("<C-p>", Action::OpenPalette),
+        ("<C-g>", Action::ShowFileInfo),
+        ("<leader>f", Action::FindFile),
+        // terminals send Ctrl-^ and Ctrl-6 differently
+        ("<C-^>", Action::AlternateFile),
+        ("<C-6>", Action::AlternateFile),
+        ("gf", Action::GoToFile),
+    ]
+    .into_iter()
+    .map(|(keys, action)| (keys.to_string(), action))
+    .collect();
+
+    for (key, motion) in text_objects() {
+        bindings.push((format!("c{key}"), Action::Change(motion)));
+    }
+    for (key, motion) in operator_motions().into_iter().chain(text_objects()) {
+        bindings.push((format!("d{key}"), Action::DeleteMotion(motion)));
+        bindings.push((format!("y{key}"), Action::Yank(Some(motion))));
+        bindings.push((format!("gc{key}"), Action::ToggleComment(Some(motion))));
+        for (op, prefix) in [
+            (CaseOp::Upper, "gU"),
+            (CaseOp::Lower, "gu"),
+            (CaseOp::Toggle, "g~"),
+        ] {
+            bindings.push((
+                format!("{prefix}{key}"),
+                Action::ChangeCase(op, Some(motion)),
+            ));
+        }
+    }
+
+    bindings
+}
+
+/// Normalizes a key event so that shifted characters match their plain binding,
+/// e.g. `K` arrives as `Char('K')` with `SHIFT` but is bound as `"K"`.
+pub fn binding(event: &KeyEvent) -> KeyBinding {
+    let mut modifiers = event.modifiers;
+    if let KeyCode::Char(_) = event.code {
+        modifiers.remove(KeyModifiers::SHIFT);
+    }
+    (event.code, modifiers)
+}
+
+/// Parses vim-style key notation: `q`, `;`, `<C-s>`, `<Up>`, `<Space>`, ...
+pub fn parse_key(key: &str) -> Option<KeyBinding> {
+    let mut chars = key.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some((KeyCode::Char(c), KeyModifiers::NONE));
+    }
+
+    let inner = key.strip_prefix('<')?.strip_suffix('>')?;
+    let (modifiers, name) = match inner.split_once('-') {
+        Some((m, name)) if !name.is_empty() => {
+            let modifiers = match m.to_ascii_uppercase().as_str() {
+                "C" => KeyModifiers::CONTROL,
+                "A" | "M" => KeyModifiers::ALT,
+                "S" => KeyModifiers::SHIFT,
+                _ => return None,
+            };
+            (modifiers, name)
+        }
+        _ => (KeyModifiers::NONE, inner),
+    };
+
+    let code = match name.to_ascii_lowercase().as_str() {
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "pageup" => KeyCode::PageUp,
+        "pagedown" => KeyCode::PageDown,
+        "esc" => KeyCode::Esc,
+        "cr" | "enter" => KeyCode::Enter,
+        "bs" => KeyCode::Backspace,
+        "tab" => KeyCode::Tab,
+        "del" => KeyCode::Delete,
+        "space" => KeyCode::Char(' '),
+        "lt" => KeyCode::Char('<'),
+        _ => {
+            let mut chars = name.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => KeyCode::Char(c.to_ascii_lowercase()),
+                _ => return None,
+            }
+        }
+    };
+
+    Some((code, modifiers))
+}
+
+/// Replaces `<leader>` (or `<Leader>`) in a sequence of keys with `leader`.
+fn expand_leader(keys: &str, leader: &str) -> String {
+    keys.replace("<leader>", leader).replace("<Leader>", leader)
+}
+
+/// Parses a sequence of keys such as `gU`, `<C-w>j` or `d<Up>`.
+pub fn parse_keys(keys: &str) -> Option<Vec<KeyBinding>> {
+    let mut bindings = vec![];
+    let mut rest = keys;
+    while let Some(c) = rest.chars().next() {
+        let len = match rest.find('>') {
+            Some(end) if c == '<' && end > 1 => end + 1,
+            _ => c.len_utf8(),
+        };
+        bindings.push(parse_key(&rest[..len])?);
+        rest = &rest[len..];
+    }
+
+    (!bindings.is_empty()).then_some(bindings)
+}
+
+/// The actions a mapping can name, also listed by the command palette.
+pub fn named_actions() -> Vec<(&'static str, Action)> {
+    vec![
+        ("quit", Action::Quit),
+        ("save", Action::Save),
+        ("move_up", Action::MoveUp),
+        ("move_down", Action::MoveDown),
+        ("move_left", Action::MoveLeft),
+        ("move_right", Action::MoveRight),
+        ("move_backward", Action::MoveBackward),
+        ("move_forward", Action::MoveForward),
+        ("move_to_line_start", Action::MoveToLineStart),
+        ("move_to_line_end", Action::MoveToLineEnd),
+        ("first_non_blank", Action::Move(Motion::FirstNonBlank)),
+        ("smart_home", Action::SmartHome),
+        ("next_paragraph", Action::Move(Motion::NextParagraph)),
+        ("prev_paragraph", Action::Move(Motion::PrevParagraph)),
+        ("percent", Action::Percent),
+        ("go_to_top", Action::GoToTop),
+        ("go_to_bottom", Action::GoToBottom),
+        ("page_up", Action::PageUp),
+        ("page_down", Action::PageDown),
+        ("half_page_up", Action::HalfPageUp),
+        ("half_page_down", Action::HalfPageDown),
+        ("scroll_down", Action::ScrollDown),
+        ("scroll_up", Action::ScrollUp),
+        ("open_fold", Action::OpenFold),
+        ("close_fold", Action::CloseFold),
+        ("open_all_folds", Action::OpenAllFolds),
+        ("close_all_folds", Action::CloseAllFolds),
+        ("insert_mode", Action::EnterMode(Mode::Insert)),
+        ("append", Action::Append),
+        ("open_line_below", Action::OpenLine { above: false }),
+        ("open_line_above", Action::OpenLine { above: true }),
+        ("command_mode", Action::EnterMode(Mode::Command)),
+        ("search", Action::EnterMode(Mode::Search)),
+        ("visual_block", Action::EnterMode(Mode::VisualBlock)),
+        ("substitute_char", Action::SubstituteChar),
+        ("substitute_line", Action::SubstituteLine),
+        ("delete_to_line_end", Action::DeleteToLineEnd),
+        ("change_to_line_end", Action::ChangeToLineEnd),
+        ("yank_line", Action::Yank(None)),
+        ("paste", Action::PasteAfterCursor),
+        ("reselect_block", Action::ReselectBlock),
+        ("toggle_comment", Action::ToggleComment(None)),
+        ("undo", Action::Undo),
+        ("redo", Action::Redo),
+        ("hover", Action::Hover),
+        ("alternate_file", Action::AlternateFile),
+        ("go_to_file", Action::GoToFile),
+        ("file_info", Action::ShowFileInfo),
+        ("rename", Action::PromptRename),
+        ("command_palette", Action::OpenPalette),
+        ("find_file", Action::FindFile),
+    ]
+}
+
+/// Resolves the right-hand side of a mapping. `Some(None)` disables the key,
+/// `None` means the action is unknown.
+fn parse_action(value: &str) -> Option<Option<Action>> {
+    if let Some(command) = value.strip_prefix(':') {
+        if command.is_empty() {
+            return Some(Some(Action::EnterMode(Mode::Command)));
+        }
+        return Some(Some(Action::ExecuteCommand(command.to_string())));
+    }
+
+    if matches!(value, "none" | "") {
+        return Some(None);
+    }
+    let (_, action) = named_actions()
+        .into_iter()
+        .find(|(name, _)| *name == value)?;
+    Some(Some(action))
+}
@@ -1,19 +1,37 @@
 #![allow(dead_code)]
 
 use std::{
+    fmt::Write as _,
     fs::{File, OpenOptions},
     io::Write,
+    path::{Path, PathBuf},
     sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 pub static LOGGER: OnceLock<std::sync::Mutex<Logger>> = OnceLock::new();
 
+/// Where the log goes: `$XDG_STATE_HOME/vigil/vigil.log` (or
+/// `~/.local/state/vigil/vigil.log`), or the temp dir when that cannot be made.
+pub fn log_path() -> PathBuf {
+    let state = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")),
+    };
+    let dir = state
+        .map(|state| state.join("vigil"))
+        .filter(|dir| std::fs::create_dir_all(dir).is_ok())
+        .unwrap_or_else(std::env::temp_dir);
+    dir.join("vigil.log")
+}
+
 pub struct Logger {
     file: File,
 }
 
 impl Logger {
-    pub fn new(file: &str) -> anyhow::Result<Logger> {
+    pub fn new(file: impl AsRef<Path>) -> anyhow::Result<Logger> {
+        #[allow(clippy::ineffective_open_options)]
         let file = OpenOptions::new()
             .create(true)
             .write(true)
@@ -27,17 +45,140 @@ impl Logger {
         writeln!(self.file, "{}", message)?;
         Ok(())
     }
+
+    /// Writes one line of JSON, `{"ts":...,"level":...,"msg":...}` followed by
+    /// `fields`, for tools like `jq` to read.
+    pub fn json_log(
+        &mut self,
+        level: &str,
+        message: &str,
+        fields: &[(&str, &str)],
+    ) -> anyhow::Result<()> {
+        let line = json_line(&timestamp(SystemTime::now()), level, message, fields);
+        writeln!(self.file, "{line}")?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for Logger {
+    fn drop(&mut self) {
+        _ = self.flush();
+    }
+}
+
+fn json_line(ts: &str, level: &str, message: &str, fields: &[(&str, &str)]) -> String {
+    let mut line = String::from("{");
+    let pairs = [("ts", ts), ("level", level), ("msg", message)];
+    for (i, (key, value)) in pairs.iter().chain(fields).enumerate() {
+        if i > 0 {
+            line.push(',');
+        }
+        _ = write!(line, "\"{}\":\"{}\"", escape(key), escape(value));
+    }
+    line.push('}');
+    line
+}
+
+/// Escapes `text` for a JSON string.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c < ' ' => _ = write!(escaped, "\\u{:04x}", c as u32),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// `time` in UTC as RFC 3339, like `2024-03-01T12:00:00.250Z`.
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rest) = (secs / 86400, secs % 86400);
+
+    // days to a civil date, after Howard Hinnant's `civil_from_days`
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60,
+        since_epoch.subsec_millis()
+    )
 }
 
 #[macro_export]
 macro_rules! log {
     ($($arg:tt)*) => {{
         let log_message = format!($($arg)*);
-        let logger = crate::logger::LOGGER.get_or_init(|| {
-            std::sync::Mutex::new(crate::logger::Logger::new("vigil.log").unwrap())
+        let logger = $crate::logger::LOGGER.get_or_init(|| {
+            std::sync::Mutex::new($crate::logger::Logger::new($crate::logger::log_path()).unwrap())
         });
-        if let Ok(mut guard) = logger.lock() {
+        if let ::std::result::Result::Ok(mut guard) = logger.lock() {
             let _ = guard.log(&log_message);
         }
     }};
-}
\ No newline at end of file
+}
+
+/// Like [`log!`], as a line of JSON: `log_json!("warn", "hover failed", "error" => err)`.
+/// Field values are anything with `to_string`.
+#[macro_export]
+macro_rules! log_json {
+    ($level:expr, $message:expr $(, $key:expr => $value:expr)* $(,)?) => {{
+        let fields: Vec<(&str, String)> = vec![$(($key, $value.to_string())),*];
+        let fields: Vec<(&str, &str)> = fields.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        let logger = $crate::logger::LOGGER.get_or_init(|| {
+            std::sync::Mutex::new($crate::logger::Logger::new($crate::logger::log_path()).unwrap())
+        });
+        if let ::std::result::Result::Ok(mut guard) = logger.lock() {
+            let _ = guard.json_log($level, &$message.to_string(), &fields);
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn json_lines_escape_their_strings() {
+        let line = json_line(
+            "t",
+            "warn",
+            "say \"hi\"\n",
+            &[("path", "C:\\x"), ("bell", "\u{7}")],
+        );
+        assert_eq!(
+            line,
+            r#"{"ts":"t","level":"warn","msg":"say \"hi\"\n","path":"C:\\x","bell":"\u0007"}"#
+        );
+    }
+
+    #[test]
+    fn timestamps_are_rfc3339_utc() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let leap_day = UNIX_EPOCH + Duration::from_millis(1_709_208_000_250);
+        assert_eq!(timestamp(leap_day), "2024-02-29T12:00:00.250Z");
+    }
+}
@@ -0,0 +1,503 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+use serde_json::{json, Value};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::{buffer::Buffer, filetype::FileType, log};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+const INITIALIZE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Returns the language server command for a file type.
+pub fn server_for(filetype: FileType) -> Option<(&'static str, &'static [&'static str])> {
+    match filetype {
+        FileType::Rust => Some(("rust-analyzer", &[])),
+        FileType::Go => Some(("gopls", &[])),
+        FileType::Python => Some(("pylsp", &[])),
+        FileType::C | FileType::Cpp => Some(("clangd", &[])),
+        FileType::JavaScript | FileType::TypeScript => {
+            Some(("typescript-language-server", &["--stdio"]))
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompletionItem {
+    pub label: String,
+    pub filter_text: String,
+    pub insert_text: String,
+}
+
+/// A replacement of the text between two positions, as `(line, utf-16 column)`.
+#[derive(Debug, Clone)]
+pub struct TextEdit {
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+    pub new_text: String,
+}
+
+pub struct LspClient {
+    child: Child,
+    stdin: ChildStdin,
+    rx: Receiver<Value>,
+    next_id: i64,
+    uri: String,
+    language_id: &'static str,
+    version: i32,
+    pending_init: Option<i64>,
+    opened: bool,
+}
+
+impl LspClient {
+    /// Spawns the server and sends `initialize`. The handshake is completed lazily on
+    /// the first request so a slow server does not delay startup.
+    pub fn start(
+        command: &str,
+        args: &[&str],
+        file: &str,
+        filetype: FileType,
+    ) -> anyhow::Result<LspClient> {
+        let path = std::fs::canonicalize(file)?;
+        let root = path
+            .parent()
+            .map(|p| p.to_path_buf())
+            .unwrap_or_else(|| path.clone());
+
+        let mut child = Command::new(command)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        let stdin = child
+            .stdin
+            .take()
+            .ok_or(anyhow!("no stdin for {command}"))?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or(anyhow!("no stdout for {command}"))?;
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(stdout);
+            while let Some(message) = read_message(&mut reader) {
+                if tx.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut client = LspClient {
+            child,
+            stdin,
+            rx,
+            next_id: 0,
+            uri: format!("file://{}", path.display()),
+            language_id: filetype.language_id(),
+            version: 0,
+            pending_init: None,
+            opened: false,
+        };
+
+        let id = client.send_request(
+            "initialize",
+            json!({
+                "processId": std::process::id(),
+                "rootUri": format!("file://{}", root.display()),
+                "capabilities": {
+                    "textDocument": {
+                        "hover": { "contentFormat": ["plaintext", "markdown"] },
+                        "completion": { "completionItem": { "snippetSupport": true } },
+                        "rename": {},
+                    },
+                    "workspace": {
+                        "workspaceEdit": { "documentChanges": true },
+                    },
+                },
+            }),
+        )?;
+        client.pending_init = Some(id);
+
+        Ok(client)
+    }
+
+    fn ensure_ready(&mut self, text: &str) -> anyhow::Result<()> {
+        if let Some(id) = self.pending_init.take() {
+            self.wait_for(id, INITIALIZE_TIMEOUT)?;
+            self.notify("initialized", json!({}))?;
+        }
+
+        self.version += 1;
+        if self.opened {
+            self.notify(
+                "textDocument/didChange",
+                json!({
+                    "textDocument": { "uri": self.uri, "version": self.version },
+                    "contentChanges": [{ "text": text }],
+                }),
+            )?;
+        } else {
+            self.notify(
+                "textDocument/didOpen",
+                json!({
+                    "textDocument": {
+                        "uri": self.uri,
+                        "languageId": self.language_id,
+                        "version": self.version,
+                        "text": text,
+                    },
+                }),
+            )?;
+            self.opened = true;
+        }
+
+        Ok(())
+    }
+
+    /// Requests hover documentation at a position. `line` and `character` are the
+    /// zero-based line and UTF-16 column, as the protocol expects.
+    pub fn hover(
+        &mut self,
+        text: &str,
+        line: usize,
+        character: usize,
+    ) -> anyhow::Result<Option<String>> {
+        self.ensure_ready(text)?;
+        let result = self.request(
+            "textDocument/hover",
+            json!({
+                "textDocument": { "uri": self.uri },
+                "position": { "line": line, "character": character },
+            }),
+        )?;
+
+        if result.is_null() {
+            return Ok(None);
+        }
+
+        let contents = markup_to_string(&result["contents"]);
+        let contents = contents.trim();
+        if contents.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(contents.to_string()))
+    }
+
+    /// Requests completion candidates at a position, in the order the server sent them.
+    pub fn completion(
+        &mut self,
+        text: &str,
+        line: usize,
+        character: usize,
+    ) -> anyhow::Result<Vec<CompletionItem>> {
+        self.ensure_ready(text)?;
+        let result = self.request(
+            "textDocument/completion",
+            json!({
+                "textDocument": { "uri": self.uri },
+                "position": { "line": line, "character": character },
+            }),
+        )?;
+
+        // the result is either `CompletionItem[]` or a `CompletionList`
+        let items = match &result {
+            Value::Array(items) => items.as_slice(),
+            Value::Object(_) => result["items"].as_array().map_or(&[][..], |v| v),
+            _ => &[],
+        };
+
+        Ok(items.iter().filter_map(parse_completion_item).collect())
+    }
+
+    /// Asks the server to rename the symbol at a position. Returns the edits to
+    /// make, grouped by file path.
+    pub fn rename(
+        &mut self,
+        text: &str,
+        line: usize,
+        character: usize,
+        new_name: &str,
+    ) -> anyhow::Result<Vec<(String, Vec<TextEdit>)>> {
+        self.ensure_ready(text)?;
+        let result = self.request(
+            "textDocument/rename",
+            json!({
+                "textDocument": { "uri": self.uri },
+                "position": { "line": line, "character": character },
+                "newName": new_name,
+            }),
+        )?;
+
+        let mut files = vec![];
+        if let Some(changes) = result["documentChanges"].as_array() {
+            for change in changes {
+                // resource operations (create/rename/delete file) are not supported
+                let Some(uri) = change["textDocument"]["uri"].as_str() else {
+                    continue;
+                };
+                files.push((uri_to_path(uri), parse_text_edits(&change["edits"])));
+            }
+        } else if let Some(changes) = result["changes"].as_object() {
+            for (uri, edits) in changes {
+                files.push((uri_to_path(uri), parse_text_edits(edits)));
+            }
+        }
+
+        Ok(files)
+    }
+
+    fn request(&mut self, method: &str, params: Value) -> anyhow::Result<Value> {
+        let id = self.send_request(method, params)?;
+        self.wait_for(id, REQUEST_TIMEOUT)
+    }
+
+    fn send_request(&mut self, method: &str, params: Value) -> anyhow::Result<i64> {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.send(&json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))?;
+        Ok(id)
+    }
+
+    fn notify(&mut self, method: &str, params: Value) -> anyhow::Result<()> {
+        self.send(&json!({ "jsonrpc": "2.0", "method": method, "params": params }))
+    }
+
+    fn send(&mut self, message: &Value) -> anyhow::Result<()> {
+        let body = message.to_string();
+        write!(self.stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        self.stdin.flush()?;
+        Ok(())
+    }
+
+    fn wait_for(&mut self, id: i64, timeout: Duration) -> anyhow::Result<Value> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let message = self.rx.recv_timeout(remaining)?;
+
+            // requests from the server (e.g. progress tokens) still expect a reply
+            if let (Some(method), Some(request_id)) = (message.get("method"), message.get("id")) {
+                log!("lsp: answering server request {method}");
+                self.send(&json!({ "jsonrpc": "2.0", "id": request_id, "result": null }))?;
+                continue;
+            }
+
+            if message.get("id").and_then(Value::as_i64) != Some(id) {
+                continue;
+            }
+
+            if let Some(error) = message.get("error") {
+                bail!("lsp error: {}", error["message"]);
+            }
+
+            return Ok(message.get("result").cloned().unwrap_or(Value::Null));
+        }
+    }
+}
+
+impl Drop for LspClient {
+    fn drop(&mut self) {
+        _ = self.notify("exit", Value::Null);
+        _ = self.child.kill();
+        _ = self.child.wait();
+    }
+}
+
+fn read_message(reader: &mut impl BufRead) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let mut body = vec![0; length?];
+    reader.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+/// Flattens `MarkupContent`, `MarkedString` or an array of them into plain text.
+fn markup_to_string(contents: &Value) -> String {
+    match contents {
+        Value::String(s) => s.clone(),
+        Value::Array(items) => items
+            .iter()
+            .map(markup_to_string)
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n\n"),
+        Value::Object(_) => contents["value"].as_str().unwrap_or_default().to_string(),
+        _ => String::new(),
+    }
+}
+
+fn parse_completion_item(item: &Value) -> Option<CompletionItem> {
+    let label = item["label"].as_str()?.to_string();
+    let text = item["textEdit"]["newText"]
+        .as_str()
+        .or(item["insertText"].as_str())
+        .unwrap_or(&label);
+
+    // InsertTextFormat.Snippet
+    let insert_text = if item["insertTextFormat"].as_i64() == Some(2) {
+        strip_snippet(text)
+    } else {
+        text.to_string()
+    };
+
+    let filter_text = item["filterText"].as_str().unwrap_or(&label).to_string();
+
+    Some(CompletionItem {
+        label,
+        filter_text,
+        insert_text,
+    })
+}
+
+/// Reduces snippet syntax to plain text: tab stops (`$1`, `${2}`) are dropped and
+/// placeholders (`${1:name}`) keep their default text.
+fn strip_snippet(snippet: &str) -> String {
+    let mut out = String::new();
+    let mut chars = snippet.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(next) = chars.next() {
+                    out.push(next);
+                }
+            }
+            '$' if chars.peek().is_some_and(|c| c.is_ascii_digit()) => {
+                while chars.peek().is_some_and(|c| c.is_ascii_digit()) {
+                    chars.next();
+                }
+            }
+            '$' if chars.peek() == Some(&'{') => {
+                chars.next();
+                while chars.peek().is_some_and(|c| c.is_ascii_digit()) {
+                    chars.next();
+                }
+                if chars.peek() == Some(&':') {
+                    chars.next();
+                }
+                let mut depth = 1;
+                let mut placeholder = String::new();
+                for c in chars.by_ref() {
+                    match c {
+                        '{' => depth += 1,
+                        '}' => depth -= 1,
+                        _ => {}
+                    }
+                    if depth == 0 {
+                        break;
+                    }
+                    placeholder.push(c);
+                }
+                out.push_str(&strip_snippet(&placeholder));
+            }
+            c => out.push(c),
+        }
+    }
+
+    out
+}
+
+/// Applies edits to a buffer, last position first so that earlier edits do not
+/// shift the positions of the ones still to apply.
+pub fn apply_text_edits(buffer: &mut Buffer, mut edits: Vec<TextEdit>) {
+    edits.sort_by_key(|edit| std::cmp::Reverse(edit.start));
+    for edit in edits {
+        let col = |(line, utf16): (usize, usize)| {
+            let text = buffer.get(line).unwrap_or_default();
+            (column(&text, utf16), line)
+        };
+        let (start, end) = (col(edit.start), col(edit.end));
+        buffer.replace(start, end, &edit.new_text);
+    }
+}
+
+fn parse_text_edits(edits: &Value) -> Vec<TextEdit> {
+    let position = |p: &Value| {
+        (
+            p["line"].as_u64().unwrap_or(0) as usize,
+            p["character"].as_u64().unwrap_or(0) as usize,
+        )
+    };
+
+    edits
+        .as_array()
+        .map(|edits| {
+            edits
+                .iter()
+                .map(|edit| TextEdit {
+                    start: position(&edit["range"]["start"]),
+                    end: position(&edit["range"]["end"]),
+                    new_text: edit["newText"].as_str().unwrap_or_default().to_string(),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Turns a `file://` URI into a path, decoding `%XX` escapes.
+fn uri_to_path(uri: &str) -> String {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    let mut bytes = vec![];
+    let mut iter = path.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex: Vec<u8> = iter.by_ref().take(2).collect();
+            if let Some(decoded) = std::str::from_utf8(&hex)
+                .ok()
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+            {
+                bytes.push(decoded);
+                continue;
+            }
+            bytes.push(b);
+            bytes.extend(hex);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Converts a UTF-16 offset from an LSP position back into a column, the
+/// grapheme it falls in.
+pub fn column(line: &str, utf16: usize) -> usize {
+    let mut units = 0;
+    for (i, g) in line.graphemes(true).enumerate() {
+        units += g.encode_utf16().count();
+        if units > utf16 {
+            return i;
+        }
+    }
+    line.graphemes(true).count()
+}
+
+/// Converts a column into the UTF-16 offset used by LSP positions.
+pub fn utf16_col(line: &str, col: usize) -> usize {
+    line.graphemes(true)
+        .take(col)
+        .map(|g| g.encode_utf16().count())
+        .sum()
+}
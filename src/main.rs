@@ -1,25 +1,109 @@
-use std::{io::stdout, panic};
+use std::{
+    backtrace::Backtrace,
+    io::{stdout, IsTerminal},
+    panic,
+    sync::Mutex,
+};
 
-use crossterm::{terminal, ExecutableCommand};
-use editor::Editor;
+use anyhow::Context;
 use buffer::Buffer;
+use config::Config;
+use crossterm::{event, terminal, ExecutableCommand};
+use diff::DiffView;
+use editor::{parse_start_position, Editor};
+use logger::Logger;
 
-mod logger;
-mod editor;
 mod buffer;
+mod complete;
+mod config;
+mod diff;
+mod edit;
+mod editor;
+mod filetype;
+mod finder;
+mod format;
+mod gutter;
+mod history;
+mod keymap;
+mod logger;
+mod lsp;
+mod palette;
+mod quickfix;
+mod rope;
+mod statusline;
+mod swap;
+#[cfg(test)]
+mod tempdir;
+mod trie;
+mod undo;
+
+/// Reads `file`, refusing to start on one that exists but cannot be read.
+fn open(file: Option<String>) -> anyhow::Result<Buffer> {
+    let name = file.clone().unwrap_or_default();
+    Buffer::from_file(file).with_context(|| format!("can't open {name}"))
+}
 
 fn main() -> anyhow::Result<()> {
-    let file = std::env::args().nth(1);
-    let buffer = Buffer::from_file(file);
-    let mut editor = Editor::new(buffer)?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = Config::load();
 
     panic::set_hook(Box::new(|info| {
+        _ = stdout().execute(event::DisableFocusChange);
         _ = stdout().execute(terminal::LeaveAlternateScreen);
+        // the panic goes in the log, after what was logged just before it and
+        // likely explains it; `try_lock`, as it may have come while the
+        // logger was held
+        if logger::LOGGER.get().is_none() {
+            if let Ok(logger) = Logger::new(logger::log_path()) {
+                _ = logger::LOGGER.set(Mutex::new(logger));
+            }
+        }
+        if let Some(Ok(mut logger)) = logger::LOGGER.get().map(Mutex::try_lock) {
+            let backtrace = Backtrace::force_capture().to_string();
+            _ = logger.json_log(
+                "error",
+                "panic",
+                &[("info", &info.to_string()), ("backtrace", &backtrace)],
+            );
+            _ = logger.flush();
+        }
         _ = terminal::disable_raw_mode();
 
+        // the editor writes what is not saved to the swap file as the panic
+        // unwinds through it, and says where
         eprintln!("Error: {}", info);
     }));
 
+    if args.first().is_some_and(|arg| arg == "--diff") {
+        let [_, left, right] = args.as_slice() else {
+            anyhow::bail!("usage: vigil --diff <file1> <file2>");
+        };
+        let left = open(Some(left.clone()))?;
+        let right = open(Some(right.clone()))?;
+        let mut diff = DiffView::new(left, right, config.tabstop)?;
+        diff.run()?;
+        return diff.cleanup();
+    }
+
+    // `-R` opens the file read-only, like `:view`
+    let readonly = args.iter().any(|arg| arg == "-R");
+    // `+N`, `+` or `+/pattern` says where to start, like vim
+    let start = args.iter().find_map(|arg| parse_start_position(arg));
+    let file = args
+        .into_iter()
+        .find(|arg| arg != "-R" && !arg.starts_with('+'));
+    // piped content has to be read before raw mode takes over the terminal
+    let mut buffer = if file.is_none() && !std::io::stdin().is_terminal() {
+        Buffer::from_stdin()?
+    } else {
+        open(file)?
+    };
+    buffer.readonly |= readonly;
+    let mut editor = Editor::new(buffer, config)?;
+    if let Some(start) = start {
+        editor.start_at(start);
+    }
+
     editor.run()?;
     editor.cleanup()
 }
@@ -0,0 +1,199 @@
+use std::cmp::Reverse;
+
+use crate::{editor::Action, keymap::named_actions};
+
+/// The ex commands the palette offers, those that need no argument.
+const EX_COMMANDS: [&str; 16] = [
+    "w",
+    "w!",
+    "wq",
+    "x",
+    "q",
+    "q!",
+    "e!",
+    "sort",
+    "sort!",
+    "retab",
+    "retab!",
+    "checktime",
+    "file",
+    "view",
+    "cnext",
+    "cprev",
+];
+
+/// A fuzzy finder over a list of commands: the named actions and ex commands,
+/// or the files to open. Typing filters the list, best matches first, and the
+/// selected command runs on Enter.
+pub struct Palette {
+    pub query: String,
+    commands: Vec<(String, Action)>,
+    /// Indices into `commands` of those matching the query, best first.
+    matches: Vec<usize>,
+    /// Index into `matches`.
+    pub selected: usize,
+}
+
+impl Palette {
+    pub fn commands() -> Palette {
+        let mut commands: Vec<(String, Action)> = named_actions()
+            .into_iter()
+            .map(|(name, action)| (name.to_string(), action))
+            .collect();
+        commands.extend(EX_COMMANDS.iter().map(|command| {
+            let action = Action::ExecuteCommand(command.to_string());
+            (format!(":{command}"), action)
+        }));
+
+        Palette::new(commands)
+    }
+
+    /// A palette for opening files, which are added with [`Palette::add_files`].
+    pub fn files() -> Palette {
+        Palette::new(vec![])
+    }
+
+    fn new(commands: Vec<(String, Action)>) -> Palette {
+        let mut palette = Palette {
+            query: String::new(),
+            commands,
+            matches: vec![],
+            selected: 0,
+        };
+        palette.filter();
+        palette
+    }
+
+    pub fn add_files(&mut self, files: Vec<String>) {
+        self.commands.extend(files.into_iter().map(|file| {
+            let action = Action::ExecuteCommand(format!("e {file}"));
+            (file, action)
+        }));
+        self.filter();
+    }
+
+    pub fn push(&mut self, c: char) {
+        self.query.push(c);
+        self.selected = 0;
+        self.filter();
+    }
+
+    pub fn pop(&mut self) {
+        self.query.pop();
+        self.selected = 0;
+        self.filter();
+    }
+
+    pub fn select_next(&mut self) {
+        if !self.matches.is_empty() {
+            self.selected = (self.selected + 1) % self.matches.len();
+        }
+    }
+
+    pub fn select_prev(&mut self) {
+        if !self.matches.is_empty() {
+            self.selected = (self.selected + self.matches.len() - 1) % self.matches.len();
+        }
+    }
+
+    /// The names of the first `limit` matching commands, in the order they are listed.
+    pub fn labels(&self, limit: usize) -> Vec<String> {
+        self.matches
+            .iter()
+            .take(limit)
+            .map(|&i| self.commands[i].0.clone())
+            .collect()
+    }
+
+    pub fn selected_action(&self) -> Option<Action> {
+        let &i = self.matches.get(self.selected)?;
+        Some(self.commands[i].1.clone())
+    }
+
+    fn filter(&mut self) {
+        let mut scored: Vec<(usize, usize)> = self
+            .commands
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (name, _))| Some((fuzzy_score(&self.query, name)?, i)))
+            .collect();
+        scored.sort_by_key(|&(score, i)| (Reverse(score), i));
+        self.matches = scored.into_iter().map(|(_, i)| i).collect();
+        self.selected = self.selected.min(self.matches.len().saturating_sub(1));
+    }
+}
+
+/// Scores how well `query` matches `candidate`: its chars have to appear in
+/// order, ignoring case. Matching at the start of a word and matching
+/// consecutive chars score higher. `None` if it does not match.
+pub fn fuzzy_score(query: &str, candidate: &str) -> Option<usize> {
+    let candidate: Vec<char> = candidate.chars().collect();
+    let (mut score, mut next) = (0, 0);
+    let mut last: Option<usize> = None;
+    for q in query.chars() {
+        let found =
+            (next..candidate.len()).find(|&i| candidate[i].to_lowercase().eq(q.to_lowercase()))?;
+        score += 1;
+        if found == 0 || !candidate[found - 1].is_alphanumeric() {
+            score += 8;
+        }
+        if last.is_some_and(|last| last + 1 == found) {
+            score += 4;
+        }
+        last = Some(found);
+        next = found + 1;
+    }
+    Some(score)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fuzzy_matches_prefer_word_starts_and_runs() {
+        assert_eq!(fuzzy_score("xyz", "toggle_comment"), None);
+        assert_eq!(fuzzy_score("", "undo"), Some(0));
+        assert!(fuzzy_score("tc", "toggle_comment") > fuzzy_score("tc", "checktime"));
+        assert!(fuzzy_score("und", "undo") > fuzzy_score("und", "unbound_d"));
+        assert_eq!(fuzzy_score("UNDO", "undo"), fuzzy_score("undo", "undo"));
+    }
+
+    #[test]
+    fn typing_filters_and_selection_wraps() {
+        let mut palette = Palette::commands();
+        for c in "tcom".chars() {
+            palette.push(c);
+        }
+        assert_eq!(palette.labels(1), ["toggle_comment"]);
+        assert!(matches!(
+            palette.selected_action(),
+            Some(Action::ToggleComment(None))
+        ));
+
+        palette.select_prev();
+        assert_eq!(palette.selected, palette.labels(usize::MAX).len() - 1);
+        palette.select_next();
+        assert_eq!(palette.selected, 0);
+
+        palette.push('!');
+        assert!(palette.labels(usize::MAX).is_empty());
+        assert!(palette.selected_action().is_none());
+        palette.pop();
+        assert_eq!(palette.labels(1), ["toggle_comment"]);
+    }
+
+    #[test]
+    fn files_open_with_edit() {
+        let mut palette = Palette::files();
+        palette.push('m');
+        palette.add_files(vec!["src/main.rs".to_string(), "README.md".to_string()]);
+        palette.select_next();
+        palette.add_files(vec!["docs/a.txt".to_string()]);
+        assert_eq!(palette.labels(5), ["src/main.rs", "README.md"]);
+        assert!(matches!(
+            palette.selected_action(),
+            Some(Action::ExecuteCommand(command)) if command == "e README.md"
+        ));
+    }
+}
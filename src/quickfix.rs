@@ -0,0 +1,49 @@
+/// A place in a file, as found by `:vimgrep`, with the text of its line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub file: String,
+    pub line: usize,
+    pub col: usize,
+    pub text: String,
+}
+
+/// The places found by the last `:vimgrep`, stepped through with `:cnext` and
+/// `:cprev`.
+#[derive(Default)]
+pub struct Quickfix {
+    entries: Vec<Entry>,
+    current: usize,
+}
+
+impl Quickfix {
+    pub fn new(entries: Vec<Entry>) -> Quickfix {
+        Quickfix {
+            entries,
+            current: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The entry last jumped to, with its index.
+    pub fn current(&self) -> Option<(usize, &Entry)> {
+        Some((self.current, self.entries.get(self.current)?))
+    }
+
+    /// Moves to the next entry; `None` at the end of the list.
+    pub fn next(&mut self) -> Option<(usize, &Entry)> {
+        if self.current + 1 >= self.entries.len() {
+            return None;
+        }
+        self.current += 1;
+        self.current()
+    }
+
+    /// Moves to the previous entry; `None` at the start of the list.
+    pub fn prev(&mut self) -> Option<(usize, &Entry)> {
+        self.current = self.current.checked_sub(1)?;
+        self.current()
+    }
+}
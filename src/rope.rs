@@ -0,0 +1,525 @@
+use std::borrow::Cow;
+
+/// Leaves are split once they grow past this many bytes.
+const MAX_LEAF: usize = 1024;
+
+/// Text stored as a binary tree of string chunks, so inserting or deleting in
+/// a large file only touches the chunks on one path instead of moving
+/// everything after the change. Offsets are in chars and lines are separated
+/// by `\n`.
+pub struct Rope {
+    root: Node,
+    len: usize,
+    newlines: usize,
+}
+
+enum Node {
+    Leaf(String),
+    /// `weight` and `breaks` are the chars and newlines in `left`, which is all
+    /// a lookup needs to pick a side.
+    Branch {
+        left: Box<Node>,
+        right: Box<Node>,
+        weight: usize,
+        breaks: usize,
+        height: usize,
+    },
+}
+
+impl Rope {
+    pub fn len_chars(&self) -> usize {
+        self.len
+    }
+
+    /// Number of lines, which is one more than the number of newlines.
+    pub fn len_lines(&self) -> usize {
+        self.newlines + 1
+    }
+
+    /// Inserts `s` at char `offset`, or at the end if the rope is shorter.
+    pub fn insert(&mut self, offset: usize, s: &str) {
+        if s.is_empty() {
+            return;
+        }
+        let (chars, newlines) = counts(s);
+        self.root.insert(offset.min(self.len), s, chars, newlines);
+        self.len += chars;
+        self.newlines += newlines;
+        self.rebalance();
+    }
+
+    /// Removes `len` chars starting at `start`, clamped to the end of the rope.
+    pub fn delete(&mut self, start: usize, len: usize) {
+        let end = start.saturating_add(len).min(self.len);
+        if start >= end {
+            return;
+        }
+        let (chars, newlines) = self.root.delete(start, end);
+        self.len -= chars;
+        self.newlines -= newlines;
+        self.rebalance();
+    }
+
+    /// The chars from `start` up to, not including, `end`.
+    pub fn slice(&self, start: usize, end: usize) -> String {
+        let mut text = String::new();
+        let end = end.min(self.len);
+        if start < end {
+            self.root.collect(start, end, &mut text);
+        }
+        text
+    }
+
+    /// The char offset where line `y` starts, or the length past the last line.
+    pub fn line_to_char(&self, y: usize) -> usize {
+        match y {
+            0 => 0,
+            _ if y > self.newlines => self.len,
+            _ => self.root.after_newline(y),
+        }
+    }
+
+    /// The line that char `offset` is on.
+    pub fn char_to_line(&self, offset: usize) -> usize {
+        self.root.newlines_before(offset.min(self.len))
+    }
+
+    /// Line `y` without its newline, empty if there is no such line. Like
+    /// [`Rope::lines`], it is borrowed unless it spans chunks.
+    pub fn line(&self, y: usize) -> Cow<'_, str> {
+        if let Some(line) = self.root.borrow_line(y, true) {
+            return Cow::Borrowed(line);
+        }
+        let mut line = String::new();
+        self.root.collect_line(y, &mut line);
+        Cow::Owned(line)
+    }
+
+    /// The lines of the text. A line that lies within one chunk is borrowed;
+    /// one that spans chunks has to be copied.
+    pub fn lines(&self) -> impl Iterator<Item = Cow<'_, str>> {
+        Lines {
+            chunks: vec![&self.root],
+            current: "",
+            pending: None,
+            done: false,
+        }
+    }
+
+    /// Rebuilds the tree when edits in one place have made it lopsided, which
+    /// also merges the small leaves that deletions leave behind.
+    fn rebalance(&mut self) {
+        let limit = 2 * (usize::BITS - self.len.leading_zeros()) as usize + 2;
+        if self.root.height() > limit {
+            self.root = Node::build(&self.slice(0, self.len)).0;
+        }
+    }
+}
+
+impl From<&str> for Rope {
+    fn from(text: &str) -> Rope {
+        let (root, len, newlines) = Node::build(text);
+        Rope {
+            root,
+            len,
+            newlines,
+        }
+    }
+}
+
+impl std::fmt::Display for Rope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut chunks = vec![&self.root];
+        while let Some(chunk) = next_chunk(&mut chunks) {
+            f.write_str(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+impl Node {
+    /// A balanced tree of chunks of `text`, with its char and newline counts.
+    fn build(text: &str) -> (Node, usize, usize) {
+        if text.len() <= MAX_LEAF {
+            let (chars, newlines) = counts(text);
+            return (Node::Leaf(text.to_string()), chars, newlines);
+        }
+
+        let mut mid = text.len() / 2;
+        while !text.is_char_boundary(mid) {
+            mid += 1;
+        }
+        let (left, weight, breaks) = Node::build(&text[..mid]);
+        let (right, chars, newlines) = Node::build(&text[mid..]);
+        let height = left.height().max(right.height()) + 1;
+        let node = Node::Branch {
+            left: Box::new(left),
+            right: Box::new(right),
+            weight,
+            breaks,
+            height,
+        };
+        (node, weight + chars, breaks + newlines)
+    }
+
+    fn height(&self) -> usize {
+        match self {
+            Node::Leaf(_) => 0,
+            Node::Branch { height, .. } => *height,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        matches!(self, Node::Leaf(text) if text.is_empty())
+    }
+
+    fn insert(&mut self, offset: usize, s: &str, chars: usize, newlines: usize) {
+        match self {
+            Node::Leaf(text) => {
+                text.insert_str(byte_index(text, offset), s);
+                if text.len() > MAX_LEAF {
+                    *self = Node::build(text).0;
+                }
+            }
+            Node::Branch {
+                left,
+                right,
+                weight,
+                breaks,
+                height,
+            } => {
+                if offset <= *weight {
+                    left.insert(offset, s, chars, newlines);
+                    *weight += chars;
+                    *breaks += newlines;
+                } else {
+                    right.insert(offset - *weight, s, chars, newlines);
+                }
+                *height = left.height().max(right.height()) + 1;
+            }
+        }
+    }
+
+    /// Removes chars `start..end` and returns the chars and newlines removed.
+    /// A branch left with one empty side is replaced by the other side.
+    fn delete(&mut self, start: usize, end: usize) -> (usize, usize) {
+        match self {
+            Node::Leaf(text) => {
+                let (start, end) = (byte_index(text, start), byte_index(text, end));
+                let removed = counts(&text[start..end]);
+                text.replace_range(start..end, "");
+                removed
+            }
+            Node::Branch {
+                left,
+                right,
+                weight,
+                breaks,
+                height,
+            } => {
+                let (mut chars, mut newlines) = (0, 0);
+                if start < *weight {
+                    (chars, newlines) = left.delete(start, end.min(*weight));
+                    *weight -= chars;
+                    *breaks -= newlines;
+                }
+                if end > *weight + chars {
+                    let offset = *weight + chars;
+                    let removed = right.delete(start.saturating_sub(offset), end - offset);
+                    chars += removed.0;
+                    newlines += removed.1;
+                }
+                *height = left.height().max(right.height()) + 1;
+
+                let rest = if left.is_empty() {
+                    Some(std::mem::replace(right.as_mut(), Node::Leaf(String::new())))
+                } else if right.is_empty() {
+                    Some(std::mem::replace(left.as_mut(), Node::Leaf(String::new())))
+                } else {
+                    None
+                };
+                if let Some(rest) = rest {
+                    *self = rest;
+                }
+                (chars, newlines)
+            }
+        }
+    }
+
+    fn collect(&self, start: usize, end: usize, out: &mut String) {
+        match self {
+            Node::Leaf(text) => out.push_str(&text[byte_index(text, start)..byte_index(text, end)]),
+            Node::Branch {
+                left,
+                right,
+                weight,
+                ..
+            } => {
+                if start < *weight {
+                    left.collect(start, end.min(*weight), out);
+                }
+                if end > *weight {
+                    right.collect(start.saturating_sub(*weight), end - *weight, out);
+                }
+            }
+        }
+    }
+
+    /// Line `y` of this node when it lies within one leaf. Found by newlines
+    /// alone, so unlike char offsets it needs no counting of chars. `last` is
+    /// whether the node ends the text, so that its last line ends there too.
+    fn borrow_line(&self, y: usize, last: bool) -> Option<&str> {
+        match self {
+            Node::Leaf(text) => {
+                let mut lines = text.split('\n');
+                let line = lines.nth(y)?;
+                (last || lines.next().is_some()).then_some(line)
+            }
+            Node::Branch {
+                left,
+                right,
+                breaks,
+                ..
+            } => {
+                if y < *breaks {
+                    left.borrow_line(y, false)
+                } else if y > *breaks {
+                    right.borrow_line(y - breaks, last)
+                } else {
+                    // starts in `left` and ends in `right`
+                    None
+                }
+            }
+        }
+    }
+
+    /// Appends the part of line `y` that lies in this node, which is all of it
+    /// unless the line goes on past either end.
+    fn collect_line(&self, y: usize, out: &mut String) {
+        match self {
+            Node::Leaf(text) => out.push_str(text.split('\n').nth(y).unwrap_or_default()),
+            Node::Branch {
+                left,
+                right,
+                breaks,
+                ..
+            } => {
+                if y <= *breaks {
+                    left.collect_line(y, out);
+                }
+                if y >= *breaks {
+                    right.collect_line(y - breaks, out);
+                }
+            }
+        }
+    }
+
+    /// The char offset just after newline number `n`, counting from 1.
+    fn after_newline(&self, n: usize) -> usize {
+        match self {
+            Node::Leaf(text) => text
+                .chars()
+                .enumerate()
+                .filter(|(_, c)| *c == '\n')
+                .nth(n - 1)
+                .map_or(0, |(i, _)| i + 1),
+            Node::Branch {
+                left,
+                right,
+                weight,
+                breaks,
+                ..
+            } => {
+                if n <= *breaks {
+                    left.after_newline(n)
+                } else {
+                    weight + right.after_newline(n - breaks)
+                }
+            }
+        }
+    }
+
+    fn newlines_before(&self, offset: usize) -> usize {
+        match self {
+            Node::Leaf(text) => text.chars().take(offset).filter(|c| *c == '\n').count(),
+            Node::Branch {
+                left,
+                right,
+                weight,
+                breaks,
+                ..
+            } => {
+                if offset <= *weight {
+                    left.newlines_before(offset)
+                } else {
+                    breaks + right.newlines_before(offset - weight)
+                }
+            }
+        }
+    }
+}
+
+/// Pops leaves off `stack` in order, pushing the children of branches.
+fn next_chunk<'a>(stack: &mut Vec<&'a Node>) -> Option<&'a str> {
+    loop {
+        match stack.pop()? {
+            Node::Leaf(text) => return Some(text),
+            Node::Branch { left, right, .. } => {
+                stack.push(right);
+                stack.push(left);
+            }
+        }
+    }
+}
+
+struct Lines<'a> {
+    chunks: Vec<&'a Node>,
+    /// What is left of the chunk being read.
+    current: &'a str,
+    /// The start of a line that began in an earlier chunk.
+    pending: Option<String>,
+    done: bool,
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = Cow<'a, str>;
+
+    fn next(&mut self) -> Option<Cow<'a, str>> {
+        loop {
+            if let Some(i) = self.current.find('\n') {
+                let line = &self.current[..i];
+                self.current = &self.current[i + 1..];
+                return Some(match self.pending.take() {
+                    Some(mut start) => {
+                        start.push_str(line);
+                        Cow::Owned(start)
+                    }
+                    None => Cow::Borrowed(line),
+                });
+            }
+
+            if !self.current.is_empty() {
+                self.pending
+                    .get_or_insert_with(String::new)
+                    .push_str(self.current);
+            }
+            match next_chunk(&mut self.chunks) {
+                Some(chunk) => self.current = chunk,
+                None if self.done => return None,
+                None => {
+                    self.done = true;
+                    self.current = "";
+                    return Some(self.pending.take().map_or(Cow::Borrowed(""), Cow::Owned));
+                }
+            }
+        }
+    }
+}
+
+fn counts(text: &str) -> (usize, usize) {
+    (text.chars().count(), text.matches('\n').count())
+}
+
+/// Byte offset of char `x` in `text`, or its length if `x` is past the end.
+fn byte_index(text: &str, x: usize) -> usize {
+    text.char_indices().nth(x).map_or(text.len(), |(i, _)| i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Text long enough to need several levels of branches.
+    fn long_text() -> String {
+        (0..2000).map(|i| format!("line {i} é\n")).collect()
+    }
+
+    #[test]
+    fn edits_across_chunks_match_a_string() {
+        let mut rope = Rope::from(long_text().as_str());
+        let mut chars: Vec<char> = long_text().chars().collect();
+        for i in 0..300 {
+            let offset = i * 7919 % chars.len();
+            if i % 3 == 0 {
+                rope.delete(offset, 50);
+                chars.drain(offset..(offset + 50).min(chars.len()));
+            } else {
+                rope.insert(offset, "ab\ncé");
+                chars.splice(offset..offset, "ab\ncé".chars());
+            }
+        }
+        let expected: String = chars.into_iter().collect();
+
+        assert_eq!(rope.to_string(), expected);
+        assert_eq!(rope.len_chars(), expected.chars().count());
+        assert_eq!(rope.len_lines(), expected.split('\n').count());
+        assert_eq!(
+            rope.lines().collect::<Vec<_>>(),
+            expected.split('\n').collect::<Vec<_>>()
+        );
+        for (y, line) in expected.split('\n').enumerate() {
+            assert_eq!(rope.line(y), line);
+        }
+        assert!(rope.root.height() <= 2 * usize::BITS as usize);
+    }
+
+    #[test]
+    fn converts_between_lines_and_offsets() {
+        let rope = Rope::from(long_text().as_str());
+        assert_eq!(rope.line(0), "line 0 é");
+        assert!(matches!(rope.line(0), Cow::Borrowed(_)));
+        assert_eq!(rope.line(1500), "line 1500 é");
+        assert_eq!(rope.line(2000), "");
+        assert_eq!(rope.line(5000), "");
+
+        let start = rope.line_to_char(1500);
+        assert_eq!(rope.char_to_line(start), 1500);
+        assert_eq!(rope.char_to_line(start - 1), 1499);
+        assert_eq!(rope.slice(start, start + 9), "line 1500");
+        assert_eq!(rope.line_to_char(5000), rope.len_chars());
+    }
+
+    #[test]
+    fn deleting_everything_leaves_one_empty_line() {
+        let mut rope = Rope::from(long_text().as_str());
+        rope.delete(0, usize::MAX);
+        assert_eq!(rope.to_string(), "");
+        assert_eq!(rope.len_lines(), 1);
+        assert_eq!(rope.lines().collect::<Vec<_>>(), [""]);
+
+        rope.insert(10, "x");
+        assert_eq!(rope.to_string(), "x");
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn benchmark_against_a_vec_of_lines() {
+        use std::time::Instant;
+
+        let text: String = (0..100_000).map(|i| format!("line number {i}\n")).collect();
+        let edits = 10_000;
+
+        let start = Instant::now();
+        let mut rope = Rope::from(text.as_str());
+        for i in 0..edits {
+            let y = i * 7919 % 100_000;
+            let offset = rope.line_to_char(y);
+            rope.insert(offset, "new line\n");
+            rope.insert(rope.line_to_char(y / 2), "x");
+        }
+        let rope_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut lines: Vec<String> = text.lines().map(String::from).collect();
+        for i in 0..edits {
+            let y = i * 7919 % 100_000;
+            lines.insert(y, "new line".to_string());
+            lines[y / 2].insert(0, 'x');
+        }
+        let vec_time = start.elapsed();
+
+        println!("{edits} edits on 100K lines: rope {rope_time:?}, Vec<String> {vec_time:?}");
+        assert_eq!(rope.len_lines(), lines.len() + 1);
+    }
+}
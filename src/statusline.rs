@@ -0,0 +1,189 @@
+use crossterm::style::Color;
+
+use crate::{config::StatusLineConfig, log};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Segment {
+    Mode,
+    File,
+    Position,
+    Percent,
+    Filetype,
+}
+
+impl Segment {
+    fn parse(name: &str) -> Option<Segment> {
+        let segment = match name {
+            "mode" => Segment::Mode,
+            "file" => Segment::File,
+            "position" => Segment::Position,
+            "percent" => Segment::Percent,
+            "filetype" => Segment::Filetype,
+            _ => return None,
+        };
+        Some(segment)
+    }
+}
+
+/// Foreground and background of one section of the status line.
+#[derive(Debug, Clone, Copy)]
+pub struct SectionColors {
+    pub fg: Color,
+    pub bg: Color,
+}
+
+/// The status line layout and colors, resolved from [`StatusLineConfig`].
+pub struct StatusLine {
+    pub segments: Vec<Segment>,
+    pub format: Option<String>,
+    pub mode: SectionColors,
+    pub file: SectionColors,
+    pub position: SectionColors,
+}
+
+impl StatusLine {
+    /// Resolves the config. Unknown segments are skipped and invalid colors
+    /// replaced by their defaults, with a warning in the log for each.
+    pub fn new(config: &StatusLineConfig) -> StatusLine {
+        let segments = config
+            .segments
+            .iter()
+            .filter_map(|name| {
+                let segment = Segment::parse(name);
+                if segment.is_none() {
+                    log!("statusline: ignoring unknown segment {name:?}");
+                }
+                segment
+            })
+            .collect();
+
+        let defaults = StatusLineConfig::default();
+        let color = |value: &str, default: &str| {
+            parse_color(value).unwrap_or_else(|| {
+                log!("statusline: invalid color {value:?}, using {default}");
+                parse_color(default).expect("default color must parse")
+            })
+        };
+
+        StatusLine {
+            segments,
+            format: config.format.clone(),
+            mode: SectionColors {
+                fg: color(&config.mode_fg, &defaults.mode_fg),
+                bg: color(&config.mode_bg, &defaults.mode_bg),
+            },
+            file: SectionColors {
+                fg: color(&config.file_fg, &defaults.file_fg),
+                bg: color(&config.file_bg, &defaults.file_bg),
+            },
+            position: SectionColors {
+                fg: color(&config.position_fg, &defaults.position_fg),
+                bg: color(&config.position_bg, &defaults.position_bg),
+            },
+        }
+    }
+
+    pub fn colors(&self, segment: Segment) -> SectionColors {
+        match segment {
+            Segment::Mode => self.mode,
+            Segment::File | Segment::Filetype => self.file,
+            Segment::Position | Segment::Percent => self.position,
+        }
+    }
+}
+
+/// What the placeholders of a status line format stand for.
+pub struct StatusInfo<'a> {
+    pub file: &'a str,
+    pub modified: bool,
+    pub readonly: bool,
+    /// 1-based line and column of the cursor.
+    pub line: usize,
+    pub column: usize,
+    pub percent: usize,
+    pub filetype: &'a str,
+}
+
+/// Fills in a status line format. `%f` is the file, `%m` shows `[+]` when
+/// modified, `%r` shows `[readonly]` for a read-only buffer, `%l` and `%c`
+/// are the line and column, `%p` the percentage through the file, `%y` the
+/// file type and `%%` a literal `%`. `%=` splits the result into a left- and
+/// a right-aligned part. Unknown placeholders are kept as typed.
+pub fn expand(format: &str, info: &StatusInfo) -> (String, String) {
+    let (mut left, mut right) = (String::new(), String::new());
+    let mut out = &mut left;
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('f') => out.push_str(info.file),
+            Some('m') if info.modified => out.push_str("[+]"),
+            Some('m') => {}
+            Some('r') if info.readonly => out.push_str("[readonly]"),
+            Some('r') => {}
+            Some('l') => out.push_str(&info.line.to_string()),
+            Some('c') => out.push_str(&info.column.to_string()),
+            Some('p') => out.push_str(&info.percent.to_string()),
+            Some('y') => out.push_str(info.filetype),
+            Some('%') => out.push('%'),
+            Some('=') => out = &mut right,
+            Some(other) => {
+                out.push('%');
+                out.push(other);
+            }
+            None => out.push('%'),
+        }
+    }
+    (left, right)
+}
+
+/// Parses `#rrggbb` or one of crossterm's color names (`red`, `dark_grey`, ...).
+fn parse_color(value: &str) -> Option<Color> {
+    if let Some(hex) = value.strip_prefix('#') {
+        if hex.len() != 6 {
+            return None;
+        }
+        let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+        return Some(Color::Rgb {
+            r: channel(0)?,
+            g: channel(2)?,
+            b: channel(4)?,
+        });
+    }
+
+    Color::try_from(value).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> StatusInfo<'static> {
+        StatusInfo {
+            file: "src/main.rs",
+            modified: true,
+            readonly: true,
+            line: 12,
+            column: 5,
+            percent: 40,
+            filetype: "rust",
+        }
+    }
+
+    #[test]
+    fn expands_placeholders_on_both_sides_of_the_split() {
+        let (left, right) = expand(" %f%m%r%= %y %l:%c %p%% ", &info());
+        assert_eq!(left, " src/main.rs[+][readonly]");
+        assert_eq!(right, " rust 12:5 40% ");
+    }
+
+    #[test]
+    fn keeps_unknown_placeholders_literally() {
+        let (left, right) = expand("%f %q %", &info());
+        assert_eq!(left, "src/main.rs %q %");
+        assert_eq!(right, "");
+    }
+}
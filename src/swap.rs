@@ -0,0 +1,48 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Where the unsaved changes to `file` are kept: `.name.vigil.swp` in the
+/// same directory.
+pub fn swap_path(file: &str) -> PathBuf {
+    let path = Path::new(file);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.vigil.swp"))
+}
+
+/// Writes the text of the buffer editing `file` to its swap file.
+pub fn write(file: &str, text: &str) -> io::Result<()> {
+    std::fs::write(swap_path(file), text)
+}
+
+/// Removes the swap file of `file`, if there is one.
+pub fn remove(file: &str) {
+    _ = std::fs::remove_file(swap_path(file));
+}
+
+/// The text in the swap file of `file`, when it was written after the file
+/// was: changes left behind by an editor that did not quit cleanly.
+pub fn newer(file: &str) -> Option<String> {
+    let swap = swap_path(file);
+    let swap_time = std::fs::metadata(&swap).and_then(|m| m.modified()).ok()?;
+    let file_time = std::fs::metadata(file).and_then(|m| m.modified()).ok();
+    if file_time.is_some_and(|file_time| file_time > swap_time) {
+        return None;
+    }
+    std::fs::read_to_string(swap).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_swap_file_is_hidden_next_to_the_file() {
+        assert_eq!(swap_path("notes.txt"), Path::new(".notes.txt.vigil.swp"));
+        assert_eq!(
+            swap_path("src/main.rs"),
+            Path::new("src/.main.rs.vigil.swp")
+        );
+    }
+}
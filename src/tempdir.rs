@@ -0,0 +1,45 @@
+use std::path::{Path, PathBuf};
+
+/// A directory for the files a test works on, removed with everything in it
+/// when the test ends, whether it passed or not.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates an empty `vigil-{name}-{pid}` in the system temp dir, clearing
+    /// what an earlier run may have left there.
+    pub fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("vigil-{name}-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// The path of `file` inside the directory.
+    pub fn join(&self, file: &str) -> PathBuf {
+        self.0.join(file)
+    }
+
+    /// The path of `file` as buffers name their files.
+    pub fn file(&self, file: &str) -> String {
+        self.join(file).to_string_lossy().to_string()
+    }
+
+    /// Writes `content` to `file`, creating the directories it goes in.
+    pub fn write(&self, file: &str, content: impl AsRef<[u8]>) {
+        let path = self.join(file);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).unwrap();
+        }
+        std::fs::write(path, content).unwrap();
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        _ = std::fs::remove_dir_all(&self.0);
+    }
+}
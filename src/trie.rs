@@ -0,0 +1,97 @@
+use std::{collections::HashMap, hash::Hash};
+
+/// A prefix tree mapping key sequences to values. Nodes live in one `Vec` and
+/// are referred to by index, so a walk through the trie is just a `usize`.
+pub struct Trie<K, V> {
+    nodes: Vec<TrieNode<K, V>>,
+}
+
+struct TrieNode<K, V> {
+    children: HashMap<K, usize>,
+    value: Option<V>,
+}
+
+/// The outcome of following one key from a node.
+pub enum Step<'a, V> {
+    /// The sequence is complete and bound to this value.
+    Match(&'a V),
+    /// The sequence continues; keep walking from this node.
+    Prefix(usize),
+    /// No binding starts with this sequence.
+    NoMatch,
+}
+
+impl<K: Eq + Hash + Clone, V> Trie<K, V> {
+    pub const ROOT: usize = 0;
+
+    pub fn new() -> Self {
+        Trie {
+            nodes: vec![TrieNode {
+                children: HashMap::new(),
+                value: None,
+            }],
+        }
+    }
+
+    pub fn from_slice(entries: &[(Vec<K>, V)]) -> Self
+    where
+        V: Clone,
+    {
+        let mut trie = Trie::new();
+        for (keys, value) in entries {
+            trie.insert(keys, value.clone());
+        }
+        trie
+    }
+
+    /// Binds `keys` to `value`, replacing any previous value for that sequence.
+    pub fn insert(&mut self, keys: &[K], value: V) {
+        let mut node = Self::ROOT;
+        for key in keys {
+            node = match self.nodes[node].children.get(key) {
+                Some(&child) => child,
+                None => {
+                    self.nodes.push(TrieNode {
+                        children: HashMap::new(),
+                        value: None,
+                    });
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children.insert(key.clone(), child);
+                    child
+                }
+            };
+        }
+        self.nodes[node].value = Some(value);
+    }
+
+    /// Unbinds `keys`. Longer sequences starting with `keys` are kept.
+    pub fn remove(&mut self, keys: &[K]) {
+        let mut node = Self::ROOT;
+        for key in keys {
+            match self.nodes[node].children.get(key) {
+                Some(&child) => node = child,
+                None => return,
+            }
+        }
+        self.nodes[node].value = None;
+    }
+
+    /// Follows `key` from `node`. A node with children is a prefix even if it
+    /// has a value of its own; that value is only reached through [`Trie::value`].
+    pub fn step(&self, node: usize, key: &K) -> Step<'_, V> {
+        let Some(&child) = self.nodes[node].children.get(key) else {
+            return Step::NoMatch;
+        };
+
+        let child_node = &self.nodes[child];
+        match &child_node.value {
+            _ if !child_node.children.is_empty() => Step::Prefix(child),
+            Some(value) => Step::Match(value),
+            None => Step::NoMatch,
+        }
+    }
+
+    pub fn value(&self, node: usize) -> Option<&V> {
+        self.nodes[node].value.as_ref()
+    }
+}
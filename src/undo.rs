@@ -0,0 +1,76 @@
+use std::collections::VecDeque;
+
+use crate::edit::Edit;
+
+/// One undoable change: the edits it made, oldest first, and where the cursor
+/// was before it, as a char offset into the text.
+pub struct Change {
+    pub edits: Vec<Edit>,
+    pub cursor: usize,
+}
+
+/// Undo and redo stacks of changes. At most `levels` changes are kept for undo;
+/// the oldest are dropped as new ones are recorded.
+pub struct UndoHistory {
+    undo: VecDeque<Change>,
+    redo: Vec<Change>,
+    levels: usize,
+    /// Char offset of the cursor where the change being made started.
+    cursor: usize,
+}
+
+impl UndoHistory {
+    pub fn new(levels: usize) -> UndoHistory {
+        UndoHistory {
+            undo: VecDeque::new(),
+            redo: vec![],
+            levels,
+            cursor: 0,
+        }
+    }
+
+    /// Ends the change being made with `edits`, and starts the next one at
+    /// `cursor`. A change that edited nothing (like entering and leaving insert
+    /// mode) is not kept; any other makes redo impossible. With `levels` at 0
+    /// nothing is kept, which disables undo.
+    pub fn checkpoint(&mut self, edits: Vec<Edit>, cursor: usize) {
+        if !edits.is_empty() {
+            self.redo.clear();
+            let change = Change {
+                edits,
+                cursor: self.cursor,
+            };
+            self.record(change);
+        }
+        self.cursor = cursor;
+    }
+
+    fn record(&mut self, change: Change) {
+        if self.levels == 0 {
+            return;
+        }
+        while self.undo.len() >= self.levels {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(change);
+    }
+
+    /// The last change, moved to the redo stack. Its edits are to be reversed.
+    pub fn undo(&mut self) -> Option<&Change> {
+        let change = self.undo.pop_back()?;
+        self.redo.push(change);
+        self.redo.last()
+    }
+
+    /// The last undone change, moved back for undo. Its edits are to be reapplied.
+    pub fn redo(&mut self) -> Option<&Change> {
+        let change = self.redo.pop()?;
+        self.record(change);
+        self.undo.back()
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+}